@@ -0,0 +1,57 @@
+//! Decode throughput of the single-state rANS coder against the interleaved one,
+//! with and without SIMD. Run with `cargo bench --bench rans`.
+
+use neuralink_compressor::coder::{RansCoder, RansTable};
+use std::time::{Duration, Instant};
+
+const LEN: usize = 4 << 20;
+const ROUNDS: usize = 5;
+
+type Decode = fn(&[u8], usize, &RansTable) -> anyhow::Result<Vec<u8>>;
+
+/// Skewed bytes, like serialised residuals: mostly small values
+fn residual_bytes(len: usize) -> Vec<u8> {
+    let mut state = 1u32;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let x = (state >> 16) % 64;
+            (x * x / 16) as u8
+        })
+        .collect()
+}
+
+/// Best of `ROUNDS` runs, which checks its output
+fn best_time(mut decode: impl FnMut() -> Vec<u8>, expected: &[u8]) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            let decoded = decode();
+            let elapsed = start.elapsed();
+            assert!(decoded == expected, "decoder output differs");
+            elapsed
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn main() {
+    let data = residual_bytes(LEN);
+    let table = RansTable::from_data(&data);
+    let single = RansCoder::encode_with(&data, &table).expect("encode");
+    let interleaved = RansCoder::encode_interleaved(&data, &table).expect("encode");
+    println!("{} MiB of input: single state {} bytes, interleaved {} bytes", LEN >> 20, single.len(), interleaved.len());
+
+    let runs: [(&str, &[u8], Decode); 3] = [
+        ("single state", &single, RansCoder::decode_with),
+        ("interleaved, scalar", &interleaved, RansCoder::decode_interleaved_scalar),
+        ("interleaved", &interleaved, RansCoder::decode_interleaved),
+    ];
+    let mut baseline = None;
+    for (name, payload, decode) in runs {
+        let time = best_time(|| decode(payload, LEN, &table).expect("decode"), &data);
+        let rate = LEN as f64 / time.as_secs_f64() / (1 << 20) as f64;
+        let speedup = rate / *baseline.get_or_insert(rate);
+        println!("{:<20} {:>8.1} MiB/s  {:>5.2}x", name, rate, speedup);
+    }
+}
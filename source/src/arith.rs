@@ -0,0 +1,264 @@
+//! Adaptive binary arithmetic coding of residuals (`ResidualCoder::Adaptive`).
+//!
+//! Each residual is binarised as a zero flag, a sign, its bit length in unary and
+//! the bits below the leading one. The zero flag and the unary bits are coded
+//! against adaptive probabilities selected by a context: the bit length of a
+//! running average of recent residual magnitudes, as in Monkey's Audio. Bursts
+//! around spikes thus move to "loud" contexts without disturbing the statistics
+//! of the quiet background. The first mantissa bit is modelled per bit length;
+//! the rest are sent with probability one half.
+//!
+//! The coder is an LZMA-style range coder with 11-bit probabilities. A
+//! `ContextModel` can outlive one call, so a channel's blocks keep learning.
+
+use anyhow::Result;
+
+const PROB_BITS: u32 = 11;
+const PROB_ONE: u16 = 1 << PROB_BITS;
+/// Adaptation rate: each coded bit moves its probability 1/32 of the way
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+/// Contexts: bit lengths 0..=23 of the running magnitude (scaled by 16)
+const CONTEXTS: usize = 24;
+/// Residual bit lengths 1..=32
+const LENGTHS: usize = 32;
+/// Zero bytes ending every flush, left for the decoder to read past the end
+const FLUSH_ZEROS: usize = 3;
+
+/// Adaptive probabilities, all starting at one half
+#[derive(Clone, Debug)]
+pub struct ContextModel {
+    zero: [u16; CONTEXTS],
+    sign: u16,
+    /// Unary bit length: `length[context][i]` codes "longer than i + 1 bits"
+    length: [[u16; LENGTHS]; CONTEXTS],
+    /// The bit after the leading one, per bit length
+    mantissa: [u16; LENGTHS],
+    /// Running mean of |residual| in 1/16ths
+    average: u32,
+}
+
+impl Default for ContextModel {
+    fn default() -> Self {
+        let half = PROB_ONE / 2;
+        Self {
+            zero: [half; CONTEXTS],
+            sign: half,
+            length: [[half; LENGTHS]; CONTEXTS],
+            mantissa: [half; LENGTHS],
+            average: 16,
+        }
+    }
+}
+
+impl ContextModel {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn context(&self) -> usize {
+        ((32 - self.average.leading_zeros()) as usize).min(CONTEXTS - 1)
+    }
+
+    fn observe(&mut self, magnitude: u32) {
+        // Mean over roughly the last 16 residuals
+        let sample = (magnitude as u64 * 16).min(u32::MAX as u64) as u32;
+        self.average = self.average - (self.average >> 4) + (sample >> 4);
+    }
+}
+
+fn adapt(prob: &mut u16, bit: bool) {
+    if bit {
+        *prob -= *prob >> MOVE_BITS;
+    } else {
+        *prob += (PROB_ONE - *prob) >> MOVE_BITS;
+    }
+}
+
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn new() -> Self {
+        Self { low: 0, range: u32::MAX, cache: 0, cache_size: 1, out: Vec::new() }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.cache_size > 0 {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    fn bit(&mut self, prob: &mut u16, bit: bool) {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        adapt(prob, bit);
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn direct_bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 != 0 {
+                self.low += self.range as u64;
+            }
+            while self.range < TOP {
+                self.range <<= 8;
+                self.shift_low();
+            }
+        }
+    }
+
+    /// The first byte out of an LZMA range coder is always zero and is dropped.
+    /// `low` is rounded up to a multiple of 2^24, which the range always spans,
+    /// so the last three bytes flushed are zero and are dropped too: the decoder
+    /// reads them past the end, and stops exactly three bytes beyond it.
+    fn finish(mut self) -> Vec<u8> {
+        const MASK: u64 = (1 << 24) - 1;
+        self.low = (self.low + MASK) & !MASK;
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out.truncate(self.out.len() - FLUSH_ZEROS);
+        self.out.split_off(1)
+    }
+}
+
+struct RangeDecoder<'a> {
+    code: u32,
+    range: u32,
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let mut decoder = Self { code: 0, range: u32::MAX, data, pos: 0 };
+        for _ in 0..4 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte() as u32;
+        }
+        decoder
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.data.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    fn normalize(&mut self) {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+    }
+
+    fn bit(&mut self, prob: &mut u16) -> bool {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        adapt(prob, bit);
+        self.normalize();
+        bit
+    }
+
+    fn direct_bits(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = self.code >= self.range;
+            if bit {
+                self.code -= self.range;
+            }
+            value = (value << 1) | bit as u32;
+            self.normalize();
+        }
+        value
+    }
+}
+
+/// Code `residuals`, adapting `model` as it goes
+pub fn encode(residuals: &[i32], model: &mut ContextModel) -> Vec<u8> {
+    let mut rc = RangeEncoder::new();
+    for &r in residuals {
+        let context = model.context();
+        let magnitude = r.unsigned_abs();
+        rc.bit(&mut model.zero[context], magnitude != 0);
+        if magnitude != 0 {
+            rc.bit(&mut model.sign, r < 0);
+            let length = 32 - magnitude.leading_zeros();
+            for i in 1..length {
+                rc.bit(&mut model.length[context][i as usize - 1], true);
+            }
+            if length < LENGTHS as u32 {
+                rc.bit(&mut model.length[context][length as usize - 1], false);
+            }
+            if length >= 2 {
+                rc.bit(&mut model.mantissa[length as usize - 1], (magnitude >> (length - 2)) & 1 != 0);
+                rc.direct_bits(magnitude, length - 2);
+            }
+        }
+        model.observe(magnitude);
+    }
+    rc.finish()
+}
+
+/// Decode `count` residuals written by `encode` from a model in the same state.
+/// Fails unless decoding ends exactly where `encode`'s output did, which catches
+/// truncated payloads; other corruption shows up as wrong samples, caught by
+/// block CRCs where enabled.
+pub fn decode(data: &[u8], count: usize, model: &mut ContextModel) -> Result<Vec<i32>> {
+    let mut rc = RangeDecoder::new(data);
+    let mut residuals = Vec::with_capacity(count);
+    for _ in 0..count {
+        let context = model.context();
+        let mut magnitude = 0u32;
+        let mut negative = false;
+        if rc.bit(&mut model.zero[context]) {
+            negative = rc.bit(&mut model.sign);
+            let mut length = 1;
+            while length < LENGTHS as u32 && rc.bit(&mut model.length[context][length as usize - 1]) {
+                length += 1;
+            }
+            magnitude = 1;
+            if length >= 2 {
+                let half = rc.bit(&mut model.mantissa[length as usize - 1]) as u32;
+                magnitude = (2 | half) << (length - 2) | rc.direct_bits(length - 2);
+            }
+        }
+        model.observe(magnitude);
+        residuals.push(if negative { (magnitude as i32).wrapping_neg() } else { magnitude as i32 });
+    }
+    let end = rc.pos - FLUSH_ZEROS;
+    if end != data.len() {
+        anyhow::bail!("Adaptive residual payload is {} bytes but codes {}", data.len(), end);
+    }
+    Ok(residuals)
+}
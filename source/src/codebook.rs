@@ -0,0 +1,189 @@
+use anyhow::Result;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+const MAGIC: [u8; 4] = *b"NCBK";
+/// Event indices are stored as `u8`, so a codebook holds at most 256 templates.
+pub const MAX_TEMPLATES: usize = 256;
+
+/// Spike template dictionary for Vector Quantization
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Codebook {
+    pub snippet_len: usize,
+    pub templates: Vec<Vec<i16>>,
+}
+
+impl Codebook {
+    /// Pseudo-random but deterministic sine-based templates, used when no trained
+    /// codebook is supplied.
+    pub fn synthetic(snippet_len: usize) -> Self {
+        let mut templates = Vec::with_capacity(MAX_TEMPLATES);
+        for i in 0..MAX_TEMPLATES {
+            let mut template = vec![0i16; snippet_len];
+            for (j, t) in template.iter_mut().enumerate() {
+                // Generate various "spike-like" shapes
+                let phase = (j as f32 / snippet_len as f32) * 2.0 * std::f32::consts::PI;
+                let val = (i as f32 / 128.0 - 1.0) * (phase.sin() * 1000.0);
+                *t = val as i16;
+            }
+            templates.push(template);
+        }
+        Self { snippet_len, templates }
+    }
+
+    /// Train up to `size` templates with the LBG algorithm: start from the global
+    /// centroid, split every centroid in two, refine with k-means, repeat.
+    pub fn train(snippets: &[Vec<i16>], size: usize, iterations: usize) -> Result<Self> {
+        let Some(first) = snippets.first() else {
+            anyhow::bail!("No spike snippets to train on");
+        };
+        let snippet_len = first.len();
+        if snippets.iter().any(|s| s.len() != snippet_len) {
+            anyhow::bail!("Training snippets must all have the same length");
+        }
+        let size = size.clamp(1, MAX_TEMPLATES);
+        let data: Vec<Vec<f64>> = snippets
+            .iter()
+            .map(|s| s.iter().map(|&v| v as f64).collect())
+            .collect();
+
+        let mut centroids = vec![vec![0.0; snippet_len]];
+        kmeans(&data, &mut centroids, iterations);
+        while centroids.len() < size {
+            let mut split = Vec::with_capacity(centroids.len() * 2);
+            for c in &centroids {
+                // Additive perturbation so zero-valued dimensions still separate
+                split.push(c.iter().map(|&v| v + 0.01 * v.abs() + 1.0).collect());
+                split.push(c.iter().map(|&v| v - 0.01 * v.abs() - 1.0).collect());
+            }
+            split.truncate(size);
+            centroids = split;
+            kmeans(&data, &mut centroids, iterations);
+        }
+
+        let templates = centroids
+            .iter()
+            .map(|c| c.iter().map(|&v| v.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16).collect())
+            .collect();
+        Ok(Self { snippet_len, templates })
+    }
+
+    /// Closest template by squared Euclidean distance
+    pub fn nearest(&self, snippet: &[i16]) -> u8 {
+        let mut best_idx = 0;
+        let mut min_dist = f64::MAX;
+        for (idx, template) in self.templates.iter().enumerate() {
+            let dist: f64 = snippet
+                .iter()
+                .zip(template.iter())
+                .map(|(&s, &t)| (s as f64 - t as f64).powi(2))
+                .sum();
+            if dist < min_dist {
+                min_dist = dist;
+                best_idx = idx as u8;
+            }
+        }
+        best_idx
+    }
+
+    /// Gain-shape VQ: the template and non-negative gain `g` minimising
+    /// `|snippet - g * template|^2`
+    pub fn nearest_with_gain(&self, snippet: &[i16]) -> (u8, f64) {
+        let energy: f64 = snippet.iter().map(|&s| (s as f64).powi(2)).sum();
+        let mut best = (0u8, 0.0);
+        let mut min_dist = f64::MAX;
+        for (idx, template) in self.templates.iter().enumerate() {
+            let mut dot = 0.0;
+            let mut norm = 0.0;
+            for (&s, &t) in snippet.iter().zip(template.iter()) {
+                dot += s as f64 * t as f64;
+                norm += (t as f64).powi(2);
+            }
+            let gain = if norm > 0.0 { (dot / norm).max(0.0) } else { 0.0 };
+            let dist = energy - 2.0 * gain * dot + gain * gain * norm;
+            if dist < min_dist {
+                min_dist = dist;
+                best = (idx as u8, gain);
+            }
+        }
+        best
+    }
+
+    /// Identity stored in `.neur` streams: CRC-32 of the serialized codebook
+    pub fn id(&self) -> u32 {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes).expect("writing to a Vec cannot fail");
+        crc32fast::hash(&bytes)
+    }
+
+    /// `NCBK`, snippet length u16, template count u16, then the templates as i16
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_all(&MAGIC)?;
+        out.write_u16::<BigEndian>(self.snippet_len as u16)?;
+        out.write_u16::<BigEndian>(self.templates.len() as u16)?;
+        for template in &self.templates {
+            for &v in template {
+                out.write_i16::<BigEndian>(v)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            anyhow::bail!("Not a codebook file");
+        }
+        let snippet_len = input.read_u16::<BigEndian>()? as usize;
+        let count = input.read_u16::<BigEndian>()? as usize;
+        if snippet_len == 0 || count == 0 || count > MAX_TEMPLATES {
+            anyhow::bail!("Invalid codebook shape {}x{}", count, snippet_len);
+        }
+        let mut templates = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut template = vec![0i16; snippet_len];
+            input.read_i16_into::<BigEndian>(&mut template)?;
+            templates.push(template);
+        }
+        Ok(Self { snippet_len, templates })
+    }
+}
+
+/// Lloyd iterations; centroids of empty cells are left where they are.
+fn kmeans(data: &[Vec<f64>], centroids: &mut [Vec<f64>], iterations: usize) {
+    let dim = centroids[0].len();
+    for _ in 0..iterations.max(1) {
+        let mut sums = vec![vec![0.0; dim]; centroids.len()];
+        let mut counts = vec![0usize; centroids.len()];
+        for point in data {
+            let mut best = 0;
+            let mut best_dist = f64::MAX;
+            for (idx, c) in centroids.iter().enumerate() {
+                let dist: f64 = point.iter().zip(c.iter()).map(|(a, b)| (a - b).powi(2)).sum();
+                if dist < best_dist {
+                    best_dist = dist;
+                    best = idx;
+                }
+            }
+            counts[best] += 1;
+            for (s, &p) in sums[best].iter_mut().zip(point.iter()) {
+                *s += p;
+            }
+        }
+        let mut moved = false;
+        for ((c, sum), &count) in centroids.iter_mut().zip(sums.iter()).zip(counts.iter()) {
+            if count == 0 {
+                continue;
+            }
+            for (v, &s) in c.iter_mut().zip(sum.iter()) {
+                let next = s / count as f64;
+                moved |= (next - *v).abs() > 1e-6;
+                *v = next;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+}
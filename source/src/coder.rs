@@ -1,14 +1,17 @@
 use bitstream_io::{BigEndian, BitRead, BitReader, BitWrite, BitWriter};
-use std::io::Cursor;
+use std::io::{Cursor, Read, Write};
 use anyhow::Result;
+use clap::ValueEnum;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::simd_ops;
+
 /// Encode residuals using Rice Coding (Lossless)
 pub fn encode_rice(residuals: &[i32], k: u32) -> Result<Vec<u8>> {
     let mut writer = BitWriter::endian(Vec::new(), BigEndian);
     let m = 1 << k;
     for &val in residuals {
-        let u_val = if val >= 0 { (val as u32) << 1 } else { ((val.abs() as u32) << 1) - 1 };
+        let u_val = if val >= 0 { (val as u32) << 1 } else { (val.unsigned_abs() << 1) - 1 };
         let q = u_val >> k;
         let r = u_val & (m - 1);
         for _ in 0..q { writer.write_bit(true)?; }
@@ -28,25 +31,210 @@ pub fn decode_rice(data: &[u8], count: usize, k: u32) -> Result<Vec<i32>> {
         while reader.read_bit()? { q += 1; }
         let r = reader.read::<u32>(k)?;
         let u_val = (q << k) | r;
-        let val = if u_val % 2 == 0 { (u_val >> 1) as i32 } else { -(((u_val + 1) >> 1) as i32) };
+        let val = if u_val.is_multiple_of(2) { (u_val >> 1) as i32 } else { -(((u_val + 1) >> 1) as i32) };
         residuals.push(val);
     }
     Ok(residuals)
 }
 
-const L_BITS: u32 = 16;
+/// Width of the per-partition Rice parameter; the all-ones value is the escape code
+const RICE_PARAM_BITS: u32 = 5;
+const RICE_ESCAPE: u32 = (1 << RICE_PARAM_BITS) - 1;
+/// Width of the raw sample size that follows an escape code
+const RAW_BITS_FIELD: u32 = 5;
+const PARTITION_ORDER_BITS: u32 = 4;
+/// Blocks are split into at most 2^MAX_PARTITION_ORDER partitions
+pub const MAX_PARTITION_ORDER: u32 = 8;
+
+/// Residual coder used by Lossless blocks, recorded in the container header
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ResidualCoder {
+    /// One Rice parameter per block (`encode_rice`)
+    Rice,
+    /// FLAC-style partitioned Rice with raw escape (`encode_rice_partitioned`)
+    Partitioned,
+    /// Context-modelled binary arithmetic coding that adapts across a channel's
+    /// blocks (`arith`)
+    Adaptive,
+}
+
+fn zigzag(v: i32) -> u32 {
+    (v.wrapping_shl(1) ^ (v >> 31)) as u32
+}
+
+fn unzigzag(u: u32) -> i32 {
+    ((u >> 1) as i32) ^ -((u & 1) as i32)
+}
+
+/// `[start, end)` of partition `i` when `count` samples are split 2^order ways
+fn partition_bounds(count: usize, order: u32, i: usize) -> (usize, usize) {
+    let start = ((i as u64 * count as u64) >> order) as usize;
+    let end = (((i as u64 + 1) * count as u64) >> order) as usize;
+    (start, end)
+}
+
+/// Two's complement width needed to store every value raw
+fn raw_width(values: &[i32]) -> u32 {
+    let max = values.iter().map(|&v| (v ^ (v >> 31)) as u32).max().unwrap_or(0);
+    if max == 0 && values.iter().all(|&v| v == 0) { 0 } else { 33 - max.leading_zeros() }
+}
+
+/// Cheapest encoding of one partition as `(parameter, bits)`; `RICE_ESCAPE` means raw.
+fn best_partition_param(values: &[i32]) -> (u32, u64) {
+    let unsigned: Vec<u32> = values.iter().map(|&v| zigzag(v)).collect();
+    let mut best = (0, u64::MAX);
+    for k in 0..RICE_ESCAPE {
+        let bits = unsigned.iter().map(|&u| (u >> k) as u64).sum::<u64>() + values.len() as u64 * (k as u64 + 1);
+        if bits < best.1 {
+            best = (k, bits);
+        }
+    }
+    let width = raw_width(values);
+    if width < (1 << RAW_BITS_FIELD) {
+        let bits = RAW_BITS_FIELD as u64 + width as u64 * values.len() as u64;
+        if bits < best.1 {
+            best = (RICE_ESCAPE, bits);
+        }
+    }
+    best
+}
+
+/// Partitioned Rice coding: the block is split into 2^p partitions, each with the
+/// Rice parameter of lowest exact bit cost or an escape to raw two's complement.
+/// The partition order minimising total size is searched up to `max_partition_order`.
+///
+/// Bitstream: `p` (4 bits), then per partition a 5-bit parameter followed by either
+/// Rice codes or, for the escape value 31, a 5-bit width and raw samples.
+pub fn encode_rice_partitioned(residuals: &[i32], max_partition_order: u32) -> Result<Vec<u8>> {
+    let max_order = max_partition_order.min(MAX_PARTITION_ORDER);
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for order in 0..=max_order {
+        if order > 0 && (residuals.len() >> order) == 0 {
+            break;
+        }
+        let mut params = Vec::with_capacity(1 << order);
+        let mut bits = PARTITION_ORDER_BITS as u64;
+        for i in 0..(1usize << order) {
+            let (start, end) = partition_bounds(residuals.len(), order, i);
+            let (param, cost) = best_partition_param(&residuals[start..end]);
+            params.push(param);
+            bits += RICE_PARAM_BITS as u64 + cost;
+        }
+        if best.as_ref().is_none_or(|b| bits < b.2) {
+            best = Some((order, params, bits));
+        }
+    }
+    let (order, params, _) = best.expect("partition order 0 is always evaluated");
+
+    let mut writer = BitWriter::endian(Vec::new(), BigEndian);
+    writer.write(PARTITION_ORDER_BITS, order)?;
+    for (i, &param) in params.iter().enumerate() {
+        let (start, end) = partition_bounds(residuals.len(), order, i);
+        let values = &residuals[start..end];
+        writer.write(RICE_PARAM_BITS, param)?;
+        if param == RICE_ESCAPE {
+            let width = raw_width(values);
+            writer.write(RAW_BITS_FIELD, width)?;
+            if width > 0 {
+                for &v in values { writer.write_signed(width, v)?; }
+            }
+        } else {
+            for &v in values {
+                let u_val = zigzag(v);
+                for _ in 0..(u_val >> param) { writer.write_bit(true)?; }
+                writer.write_bit(false)?;
+                writer.write(param, u_val & ((1u32 << param) - 1))?;
+            }
+        }
+    }
+    writer.byte_align()?;
+    Ok(writer.into_writer())
+}
+
+/// Decode residuals written by `encode_rice_partitioned`
+pub fn decode_rice_partitioned(data: &[u8], count: usize) -> Result<Vec<i32>> {
+    let mut reader = BitReader::endian(Cursor::new(data), BigEndian);
+    let order = reader.read::<u32>(PARTITION_ORDER_BITS)?;
+    if order > MAX_PARTITION_ORDER {
+        anyhow::bail!("Invalid Rice partition order {}", order);
+    }
+    let mut residuals = Vec::with_capacity(count);
+    for i in 0..(1usize << order) {
+        let (start, end) = partition_bounds(count, order, i);
+        let param = reader.read::<u32>(RICE_PARAM_BITS)?;
+        if param == RICE_ESCAPE {
+            let width = reader.read::<u32>(RAW_BITS_FIELD)?;
+            for _ in start..end {
+                residuals.push(if width == 0 { 0 } else { reader.read_signed::<i32>(width)? });
+            }
+        } else {
+            for _ in start..end {
+                let mut q = 0u32;
+                while reader.read_bit()? { q += 1; }
+                let r = reader.read::<u32>(param)?;
+                residuals.push(unzigzag((q << param) | r));
+            }
+        }
+    }
+    Ok(residuals)
+}
+
+/// Byte rANS states stay in `[L, 2^32)`, matching the encoder's renormalisation
+/// bound, and the encoder emits its bytes in decoding order. The first version of
+/// this coder used 2^16 and emission order, so its payloads never decoded; every
+/// decodable byte rANS payload uses this layout.
+const L_BITS: u32 = 24;
 const L: u32 = 1 << L_BITS;
-const M_BITS: u32 = 12;
-const M: u32 = 1 << M_BITS;
+pub(crate) const M_BITS: u32 = 12;
+pub(crate) const M: u32 = 1 << M_BITS;
 
-pub struct RansCoder;
+/// States of an interleaved rANS payload; one AVX2 register of u32 lanes
+pub const RANS_LANES: usize = 8;
+/// Lower bound of interleaved states, which renormalise 16 bits at a time
+pub(crate) const RANS_WORD_L: u32 = 1 << 16;
 
-impl RansCoder {
-    pub fn encode(data: &[u8]) -> Result<Vec<u8>> {
-        if data.is_empty() { return Ok(Vec::new()); }
+/// Normalised byte frequencies (summing to 2^12) for `RansCoder`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RansTable {
+    freq: [u32; 256],
+    cum: [u32; 257],
+}
+
+/// LEB128: seven bits per byte, high bit set on all but the last
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint<R: Read>(input: &mut R) -> Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = input.read_u8()?;
+        value |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("Varint longer than 32 bits")
+}
+
+impl RansTable {
+    fn from_freq(freq: [u32; 256]) -> Self {
+        let mut cum = [0u32; 257];
+        for i in 0..256 {
+            cum[i + 1] = cum[i] + freq[i];
+        }
+        Self { freq, cum }
+    }
+
+    /// Frequencies of the bytes in `data`, every byte present keeping at least one slot
+    pub fn from_data(data: &[u8]) -> Self {
         let mut freq = [0u32; 256];
         for &b in data { freq[b as usize] += 1; }
-        let total = data.len() as u64;
+        let total = data.len().max(1) as u64;
         let mut normalized_freq = [0u32; 256];
         let mut sum = 0;
         for i in 0..256 {
@@ -55,49 +243,547 @@ impl RansCoder {
                 sum += normalized_freq[i];
             }
         }
-        while sum > M { for i in 0..256 { if normalized_freq[i] > 1 { normalized_freq[i] -= 1; sum -= 1; if sum == M { break; } } } }
+        if sum == 0 {
+            // Empty input: any valid table will do
+            normalized_freq[0] = M;
+            sum = M;
+        }
+        while sum > M { for nf in normalized_freq.iter_mut() { if *nf > 1 { *nf -= 1; sum -= 1; if sum == M { break; } } } }
         while sum < M { for i in 0..256 { if freq[i] > 0 { normalized_freq[i] += 1; sum += 1; if sum == M { break; } } } }
-        let mut cum_freq = [0u32; 257];
-        for i in 0..256 { cum_freq[i + 1] = cum_freq[i] + normalized_freq[i]; }
+        Self::from_freq(normalized_freq)
+    }
+
+    /// Frequencies of the bytes in `data` with a slot kept for every byte value,
+    /// so the table can code any later data: the kind worth sharing
+    pub fn covering(data: &[u8]) -> Self {
+        let mut counts = [0u64; 256];
+        for &b in data { counts[b as usize] += 1; }
+        let total = data.len() as u64;
+        let spare = (M - 256) as u64;
+        let mut freq = [1u32; 256];
+        let mut assigned = 0;
+        for (f, &c) in freq.iter_mut().zip(&counts) {
+            let share = (c * spare).checked_div(total).unwrap_or(0) as u32;
+            *f += share;
+            assigned += share;
+        }
+        let top = (0..256).rev().max_by_key(|&s| counts[s]).unwrap_or(0);
+        freq[top] += spare as u32 - assigned;
+        Self::from_freq(freq)
+    }
+
+    /// True if every byte of `data` has a nonzero frequency
+    pub fn covers(&self, data: &[u8]) -> bool {
+        data.iter().all(|&b| self.freq[b as usize] > 0)
+    }
+
+    /// Estimated coded size of `data` in bytes, final state included; `None` if
+    /// the table cannot code it
+    pub fn cost(&self, data: &[u8]) -> Option<usize> {
+        if !self.covers(data) {
+            return None;
+        }
+        let bits: f64 = data.iter().map(|&b| M_BITS as f64 - (self.freq[b as usize] as f64).log2()).sum();
+        Some((bits / 8.0).ceil() as usize + 4)
+    }
+
+    /// Compact form: `symbols - 1` (u8), then per nonzero symbol the count of
+    /// zero symbols skipped since the previous one (u8) and its frequency as a
+    /// varint. Typically a few dozen bytes instead of 512.
+    pub fn write_compact(&self, out: &mut Vec<u8>) {
+        let present: Vec<usize> = (0..256).filter(|&s| self.freq[s] > 0).collect();
+        out.push((present.len() - 1) as u8);
+        let mut next = 0;
+        for s in present {
+            out.push((s - next) as u8);
+            write_varint(out, self.freq[s]);
+            next = s + 1;
+        }
+    }
+
+    pub fn read_compact<R: Read>(input: &mut R) -> Result<Self> {
+        let symbols = input.read_u8()? as usize + 1;
+        let mut freq = [0u32; 256];
+        let mut next = 0usize;
+        for _ in 0..symbols {
+            let s = next + input.read_u8()? as usize;
+            let f = read_varint(input)?;
+            if s >= 256 || f == 0 || f > M {
+                anyhow::bail!("Invalid rANS table entry {} / {}", s, f);
+            }
+            freq[s] = f;
+            next = s + 1;
+        }
+        if freq.iter().sum::<u32>() != M {
+            anyhow::bail!("rANS table frequencies do not sum to {}", M);
+        }
+        Ok(Self::from_freq(freq))
+    }
+
+    /// Per slot of the 2^12 range: `freq - 1` (bits 0..12), `slot - cum` (bits
+    /// 12..24) and the symbol (bits 24..32), so decoding a symbol takes one lookup
+    fn slot_entries(&self) -> Vec<u32> {
+        let mut entries = vec![0u32; M as usize];
+        for s in 0..256 {
+            for slot in self.cum[s]..self.cum[s + 1] {
+                entries[slot as usize] = (self.freq[s] - 1) | (slot - self.cum[s]) << 12 | (s as u32) << 24;
+            }
+        }
+        entries
+    }
+
+    /// The legacy layout: 256 little-endian u16 frequencies
+    fn write_full(&self, out: &mut Vec<u8>) -> Result<()> {
+        for &nf in &self.freq { out.write_u16::<LittleEndian>(nf as u16)?; }
+        Ok(())
+    }
+
+    fn read_full(data: &[u8]) -> Result<Self> {
+        let mut freq = [0u32; 256];
+        let mut f_cursor = Cursor::new(data);
+        for nf in freq.iter_mut() { *nf = f_cursor.read_u16::<LittleEndian>()? as u32; }
+        if freq.iter().sum::<u32>() != M {
+            anyhow::bail!("rANS table is corrupt");
+        }
+        Ok(Self::from_freq(freq))
+    }
+}
+
+/// Most tables a `RansTables` holds, so that its count fits the `u16` it is written as
+/// and table IDs stay below `RAW_TABLE_ID`
+pub const MAX_RANS_TABLES: usize = u16::MAX as usize;
+
+/// Table ID of an `encode_shared` payload stored uncoded, no table making it smaller
+pub const RAW_TABLE_ID: u16 = u16::MAX;
+
+/// Frequency tables shared by many `RansCoder` payloads, for instance every block
+/// of a file. Payloads name their table by its index (the table ID).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RansTables {
+    tables: Vec<RansTable>,
+}
+
+impl RansTables {
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    pub fn get(&self, id: u16) -> Result<&RansTable> {
+        match self.tables.get(id as usize) {
+            Some(table) => Ok(table),
+            None => anyhow::bail!("Unknown rANS table {} (of {})", id, self.tables.len()),
+        }
+    }
+
+    /// ID of `table`, adding it unless an identical table is already shared
+    pub fn insert(&mut self, table: RansTable) -> Result<u16> {
+        if let Some(id) = self.tables.iter().position(|t| *t == table) {
+            return Ok(id as u16);
+        }
+        if self.tables.len() >= MAX_RANS_TABLES {
+            anyhow::bail!("At most {} shared rANS tables", MAX_RANS_TABLES);
+        }
+        self.tables.push(table);
+        Ok((self.tables.len() - 1) as u16)
+    }
+
+    /// Table count (LE u16), then each table in compact form
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        let mut buf = Vec::new();
+        buf.write_u16::<LittleEndian>(self.tables.len() as u16)?;
+        for table in &self.tables {
+            table.write_compact(&mut buf);
+        }
+        out.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let count = input.read_u16::<LittleEndian>()?;
+        let tables = (0..count).map(|_| RansTable::read_compact(input)).collect::<Result<_>>()?;
+        Ok(Self { tables })
+    }
+}
+
+pub struct RansCoder;
+
+impl RansCoder {
+    /// Payload with its own table appended in the legacy 512-byte layout
+    pub fn encode(data: &[u8]) -> Result<Vec<u8>> {
+        if data.is_empty() { return Ok(Vec::new()); }
+        let table = RansTable::from_data(data);
+        let mut final_out = Self::encode_with(data, &table)?;
+        table.write_full(&mut final_out)?;
+        Ok(final_out)
+    }
+
+    pub fn decode(data: &[u8], count: usize) -> Result<Vec<u8>> {
+        if data.is_empty() { return Ok(Vec::new()); }
+        let Some(freq_start) = data.len().checked_sub(512) else {
+            anyhow::bail!("rANS payload shorter than its frequency table");
+        };
+        let table = RansTable::read_full(&data[freq_start..])?;
+        Self::decode_with(&data[..freq_start], count, &table)
+    }
+
+    /// Final state (LE u32) and renormalisation bytes, without any table
+    pub fn encode_with(data: &[u8], table: &RansTable) -> Result<Vec<u8>> {
+        if !table.covers(data) {
+            anyhow::bail!("rANS table has no slot for a byte of the data");
+        }
         let mut state = L;
         let mut out = Vec::new();
         for &s in data.iter().rev() {
             let s = s as usize;
-            let f = normalized_freq[s];
-            let b = cum_freq[s];
+            let f = table.freq[s];
+            let b = table.cum[s];
             while state >= (f << (32 - M_BITS)) { out.push((state & 0xFF) as u8); state >>= 8; }
             state = ((state / f) << M_BITS) + (state % f) + b;
         }
+        // rANS is LIFO: the decoder consumes renormalisation bytes in reverse emission order
+        out.reverse();
         let mut final_out = Vec::new();
         final_out.write_u32::<LittleEndian>(state)?;
         final_out.extend_from_slice(&out);
-        for i in 0..256 { final_out.write_u16::<LittleEndian>(normalized_freq[i] as u16)?; }
         Ok(final_out)
     }
 
-    pub fn decode(data: &[u8], count: usize) -> Result<Vec<u8>> {
-        if data.is_empty() { return Ok(Vec::new()); }
+    /// Inverse of `encode_with`. A `count` of 0 decodes until every byte is
+    /// consumed and the state has unwound back to the encoder's initial value.
+    pub fn decode_with(data: &[u8], count: usize, table: &RansTable) -> Result<Vec<u8>> {
         let mut cursor = Cursor::new(data);
         let mut state = cursor.read_u32::<LittleEndian>()?;
-        let freq_start = data.len() - 512;
-        let mut normalized_freq = [0u32; 256];
-        let mut f_cursor = Cursor::new(&data[freq_start..]);
-        for i in 0..256 { normalized_freq[i] = f_cursor.read_u16::<LittleEndian>()? as u32; }
-        let mut cum_freq = [0u32; 257];
-        for i in 0..256 { cum_freq[i+1] = cum_freq[i] + normalized_freq[i]; }
         let mut symbol_map = [0u8; M as usize];
-        for s in 0..256 { for j in cum_freq[s]..cum_freq[s+1] { symbol_map[j as usize] = s as u8; } }
+        for s in 0..256 { for j in table.cum[s]..table.cum[s+1] { symbol_map[j as usize] = s as u8; } }
         let mut out = Vec::with_capacity(count);
         let mut pos = 4;
-        while (count == 0 && pos < freq_start) || (count > 0 && out.len() < count) {
+        while (count == 0 && (pos < data.len() || state != L)) || (count > 0 && out.len() < count) {
+            if count == 0 && state < L {
+                anyhow::bail!("rANS payload is corrupt");
+            }
             let slot = state & (M - 1);
             let s = symbol_map[slot as usize];
             out.push(s);
-            let f = normalized_freq[s as usize];
-            let b = cum_freq[s as usize];
+            let f = table.freq[s as usize];
+            let b = table.cum[s as usize];
             state = f * (state >> M_BITS) + slot - b;
-            while state < L && pos < freq_start { state = (state << 8) | data[pos] as u32; pos += 1; }
+            while state < L && pos < data.len() { state = (state << 8) | data[pos] as u32; pos += 1; }
+        }
+        Ok(out)
+    }
+
+    /// Code `data` with whichever shared table makes it smallest, adding a
+    /// `covering` table built from `data` when that is cheaper even counting its
+    /// compact size. Data that even its own table would not shrink is stored as
+    /// is under `RAW_TABLE_ID`. The payload starts with the table ID (LE u16).
+    pub fn encode_shared(data: &[u8], tables: &mut RansTables) -> Result<Vec<u8>> {
+        let own = RansTable::covering(data);
+        let mut own_size = Vec::new();
+        own.write_compact(&mut own_size);
+        let own_cost = own.cost(data);
+        let fresh = own_cost.map(|cost| cost + own_size.len());
+        let shared = (0..tables.len())
+            .filter_map(|id| tables.tables[id].cost(data).map(|cost| (cost, id as u16)))
+            .min();
+        let id = match (shared, fresh) {
+            _ if own_cost.is_none_or(|cost| cost >= data.len()) => RAW_TABLE_ID,
+            (Some((cost, id)), Some(fresh)) if cost <= fresh => id,
+            _ => tables.insert(own)?,
+        };
+        let mut out = Vec::new();
+        out.write_u16::<LittleEndian>(id)?;
+        if id == RAW_TABLE_ID {
+            out.extend_from_slice(data);
+        } else {
+            out.extend_from_slice(&Self::encode_with(data, tables.get(id)?)?);
+        }
+        Ok(out)
+    }
+
+    /// Inverse of `encode_shared`
+    pub fn decode_shared(data: &[u8], count: usize, tables: &RansTables) -> Result<Vec<u8>> {
+        let mut cursor = Cursor::new(data);
+        let id = cursor.read_u16::<LittleEndian>()?;
+        let payload = &data[2..];
+        if id == RAW_TABLE_ID {
+            if count > 0 && payload.len() != count {
+                anyhow::bail!("Uncoded rANS payload holds {} bytes, expected {}", payload.len(), count);
+            }
+            return Ok(payload.to_vec());
+        }
+        Self::decode_with(payload, count, tables.get(id)?)
+    }
+
+    /// Interleaved variant of `encode_with`: symbol `i` goes through state
+    /// `i % RANS_LANES` and states renormalise 16 bits at a time, so a decoder can
+    /// advance all of them in lockstep. The payload holds the final states (LE
+    /// u32 each), then the renormalisation words (LE u16) in decoding order.
+    pub fn encode_interleaved(data: &[u8], table: &RansTable) -> Result<Vec<u8>> {
+        if !table.covers(data) {
+            anyhow::bail!("rANS table has no slot for a byte of the data");
+        }
+        let mut states = [RANS_WORD_L; RANS_LANES];
+        let mut words = Vec::new();
+        for (i, &s) in data.iter().enumerate().rev() {
+            let state = &mut states[i % RANS_LANES];
+            let f = table.freq[s as usize];
+            if *state >= f << (32 - M_BITS) {
+                words.push(*state as u16);
+                *state >>= 16;
+            }
+            *state = ((*state / f) << M_BITS) + (*state % f) + table.cum[s as usize];
+        }
+        words.reverse();
+        let mut out = Vec::with_capacity(RANS_LANES * 4 + words.len() * 2);
+        for state in states {
+            out.write_u32::<LittleEndian>(state)?;
+        }
+        for word in words {
+            out.write_u16::<LittleEndian>(word)?;
+        }
+        Ok(out)
+    }
+
+    /// Inverse of `encode_interleaved`, decoding `RANS_LANES` symbols per step
+    /// with AVX2 where the CPU has it
+    pub fn decode_interleaved(data: &[u8], count: usize, table: &RansTable) -> Result<Vec<u8>> {
+        Self::decode_lanes(data, count, table, simd_ops::rans_decode)
+    }
+
+    /// `decode_interleaved` one symbol at a time, as on CPUs without SIMD
+    pub fn decode_interleaved_scalar(data: &[u8], count: usize, table: &RansTable) -> Result<Vec<u8>> {
+        Self::decode_lanes(data, count, table, simd_ops::rans_decode_scalar)
+    }
+
+    fn decode_lanes(data: &[u8], count: usize, table: &RansTable, kernel: simd_ops::RansKernel) -> Result<Vec<u8>> {
+        let Some(payload) = data.get(RANS_LANES * 4..).filter(|words| words.len().is_multiple_of(2)) else {
+            anyhow::bail!("Interleaved rANS payload is truncated");
+        };
+        let mut cursor = Cursor::new(data);
+        let mut states = [0u32; RANS_LANES];
+        for state in states.iter_mut() {
+            *state = cursor.read_u32::<LittleEndian>()?;
+        }
+        let words: Vec<u16> = payload.chunks_exact(2).map(|w| u16::from_le_bytes([w[0], w[1]])).collect();
+        let mut lanes = simd_ops::RansLanes { states, words: &words, pos: 0 };
+        let mut out = vec![0u8; count];
+        kernel(&mut lanes, &table.slot_entries(), &mut out)?;
+        // Every state unwinds to the encoder's initial value exactly as the words run out
+        if lanes.pos != words.len() || lanes.states.iter().any(|&state| state != RANS_WORD_L) {
+            anyhow::bail!("rANS payload is corrupt");
         }
         Ok(out)
     }
 }
+
+/// Zigzag residuals below this are their own symbol
+const DIRECT_SYMBOLS: u32 = 16;
+/// Symbols of `ResidualModel`: 16 direct values, then two per bit length 5..=32
+pub const RESIDUAL_SYMBOLS: usize = DIRECT_SYMBOLS as usize + 2 * 28;
+const RESIDUAL_M_BITS: u32 = 14;
+const RESIDUAL_M: u32 = 1 << RESIDUAL_M_BITS;
+/// Counts are halved once their sum passes this, so old blocks fade out
+const MAX_MODEL_TOTAL: u32 = 1 << 16;
+
+/// `(symbol, escape bit count, escape bits)` of a zigzag residual. Values of
+/// `DIRECT_SYMBOLS` and up are coded as their bit length and the bit after the
+/// leading one; the bits below that follow raw.
+fn residual_symbol(u: u32) -> (usize, u32, u32) {
+    if u < DIRECT_SYMBOLS {
+        return (u as usize, 0, 0);
+    }
+    let length = 32 - u.leading_zeros();
+    let escape = length - 2;
+    let half = (u >> escape) & 1;
+    let symbol = DIRECT_SYMBOLS + (length - 5) * 2 + half;
+    (symbol as usize, escape, u & ((1u32 << escape) - 1))
+}
+
+/// Escape bit count of `symbol` and the zigzag value's bits above them
+fn residual_prefix(symbol: usize) -> (u32, u32) {
+    let symbol = symbol as u32;
+    if symbol < DIRECT_SYMBOLS {
+        return (0, symbol);
+    }
+    let length = (symbol - DIRECT_SYMBOLS) / 2 + 5;
+    let half = (symbol - DIRECT_SYMBOLS) & 1;
+    (length - 2, 2 | half)
+}
+
+/// Normalised symbol frequencies (summing to 2^14) for Elite's residual rANS.
+/// Every symbol keeps a frequency of at least one, so any residual can be coded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrequencyTable {
+    freq: [u32; RESIDUAL_SYMBOLS],
+    cum: [u32; RESIDUAL_SYMBOLS + 1],
+}
+
+impl FrequencyTable {
+    /// One slot per symbol, the rest shared in proportion to `counts`; the
+    /// rounding remainder goes to the most frequent symbol.
+    pub fn from_counts(counts: &[u32; RESIDUAL_SYMBOLS]) -> Self {
+        let total: u64 = counts.iter().map(|&c| c as u64).sum();
+        let spare = RESIDUAL_M - RESIDUAL_SYMBOLS as u32;
+        let mut freq = [1u32; RESIDUAL_SYMBOLS];
+        let mut assigned = 0;
+        for (f, &c) in freq.iter_mut().zip(counts) {
+            let share = (c as u64 * spare as u64).checked_div(total).unwrap_or(0) as u32;
+            *f += share;
+            assigned += share;
+        }
+        let top = (0..RESIDUAL_SYMBOLS).rev().max_by_key(|&s| counts[s]).unwrap_or(0);
+        freq[top] += spare - assigned;
+        let mut cum = [0u32; RESIDUAL_SYMBOLS + 1];
+        for s in 0..RESIDUAL_SYMBOLS {
+            cum[s + 1] = cum[s] + freq[s];
+        }
+        Self { freq, cum }
+    }
+
+    fn symbol_at(&self, slot: u32) -> usize {
+        self.cum.partition_point(|&c| c <= slot) - 1
+    }
+}
+
+/// Residual statistics shared by the Elite blocks of one channel: each block is
+/// coded with the table of the blocks before it, then added to the counts.
+/// Encoder and decoder reset it at independent blocks, so those stand alone.
+#[derive(Clone, Debug)]
+pub struct ResidualModel {
+    counts: [u32; RESIDUAL_SYMBOLS],
+}
+
+impl Default for ResidualModel {
+    fn default() -> Self {
+        Self { counts: [1; RESIDUAL_SYMBOLS] }
+    }
+}
+
+impl ResidualModel {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn table(&self) -> FrequencyTable {
+        FrequencyTable::from_counts(&self.counts)
+    }
+
+    pub fn update(&mut self, residuals: &[i32]) {
+        for &r in residuals {
+            self.counts[residual_symbol(zigzag(r)).0] += 1;
+        }
+        while self.counts.iter().sum::<u32>() > MAX_MODEL_TOTAL {
+            for c in &mut self.counts {
+                *c = c.div_ceil(2);
+            }
+        }
+    }
+}
+
+/// Byte-wise rANS over `RESIDUAL_M`-scaled frequencies. Symbols are pushed in
+/// reverse; `finish` returns the final state (LE u32) and the bytes in reading order.
+struct ResidualRansEncoder {
+    state: u32,
+    out: Vec<u8>,
+}
+
+impl ResidualRansEncoder {
+    fn put(&mut self, start: u32, freq: u32) {
+        while self.state >= (freq << (32 - RESIDUAL_M_BITS)) {
+            self.out.push(self.state as u8);
+            self.state >>= 8;
+        }
+        self.state = ((self.state / freq) << RESIDUAL_M_BITS) + (self.state % freq) + start;
+    }
+
+    /// `bits` raw bits, as uniform symbols of up to `RESIDUAL_M_BITS` bits each,
+    /// most significant chunk decoded first
+    fn put_bits(&mut self, value: u32, bits: u32) {
+        let chunks = bits.div_ceil(RESIDUAL_M_BITS);
+        for i in 0..chunks {
+            let shift = i * RESIDUAL_M_BITS;
+            let width = (bits - shift).min(RESIDUAL_M_BITS);
+            let chunk = (value >> shift) & ((1u32 << width) - 1);
+            let freq = 1 << (RESIDUAL_M_BITS - width);
+            self.put(chunk * freq, freq);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let mut out = self.state.to_le_bytes().to_vec();
+        self.out.reverse();
+        out.append(&mut self.out);
+        out
+    }
+}
+
+/// Reads exactly the bytes `ResidualRansEncoder` wrote: renormalisation stops
+/// once the state is back in range, so no length needs to be stored.
+struct ResidualRansDecoder<'a, R> {
+    state: u32,
+    input: &'a mut R,
+}
+
+impl<R: Read> ResidualRansDecoder<'_, R> {
+    fn slot(&self) -> u32 {
+        self.state & (RESIDUAL_M - 1)
+    }
+
+    fn advance(&mut self, start: u32, freq: u32) -> Result<()> {
+        self.state = freq * (self.state >> RESIDUAL_M_BITS) + self.slot() - start;
+        while self.state < L {
+            self.state = (self.state << 8) | self.input.read_u8()? as u32;
+        }
+        Ok(())
+    }
+
+    fn get_bits(&mut self, bits: u32) -> Result<u32> {
+        let mut value = 0;
+        for i in (0..bits.div_ceil(RESIDUAL_M_BITS)).rev() {
+            let shift = i * RESIDUAL_M_BITS;
+            let width = (bits - shift).min(RESIDUAL_M_BITS);
+            let freq = 1 << (RESIDUAL_M_BITS - width);
+            let chunk = self.slot() / freq;
+            self.advance(chunk * freq, freq)?;
+            value |= chunk << shift;
+        }
+        Ok(value)
+    }
+}
+
+/// rANS-code residuals as `ResidualModel` symbols plus escape bits, against `table`
+pub fn encode_residuals_rans(residuals: &[i32], table: &FrequencyTable) -> Vec<u8> {
+    let mut encoder = ResidualRansEncoder { state: L, out: Vec::new() };
+    for &r in residuals.iter().rev() {
+        let (symbol, escape, bits) = residual_symbol(zigzag(r));
+        encoder.put_bits(bits, escape);
+        encoder.put(table.cum[symbol], table.freq[symbol]);
+    }
+    encoder.finish()
+}
+
+/// Decode `count` residuals written by `encode_residuals_rans` with the same
+/// table, reading no further than the end of its output.
+pub fn decode_residuals_rans<R: Read>(input: &mut R, count: usize, table: &FrequencyTable) -> Result<Vec<i32>> {
+    let mut decoder = ResidualRansDecoder { state: input.read_u32::<LittleEndian>()?, input };
+    if decoder.state < L {
+        anyhow::bail!("Residual rANS stream is corrupt");
+    }
+    let mut residuals = Vec::with_capacity(count);
+    for _ in 0..count {
+        let symbol = table.symbol_at(decoder.slot());
+        decoder.advance(table.cum[symbol], table.freq[symbol])?;
+        let (escape, prefix) = residual_prefix(symbol);
+        let low = decoder.get_bits(escape)?;
+        residuals.push(unzigzag(((prefix as u64) << escape | low as u64) as u32));
+    }
+    // A well-formed stream unwinds exactly to the encoder's initial state
+    if decoder.state != L {
+        anyhow::bail!("Residual rANS stream is corrupt");
+    }
+    Ok(residuals)
+}
@@ -0,0 +1,405 @@
+//! `.neur` container header.
+//!
+//! Every stream starts with the magic `NEUR` and a big-endian `u32` format version.
+//! Versions 1 (Lossless) and 4 (Events) are the legacy per-mode layouts, followed
+//! directly by `sample_rate u32, channels u16, bits_per_sample u16, total_samples u64`
+//! and then blocks (or one events payload) coding the interleaved samples as a
+//! single sequence, whatever the channel count.
+//! Version 5, the legacy Elite layout, is refused with `FormatError::LegacyByteRans`:
+//! its byte rANS kept states below the coder's renormalisation bound and wrote bytes
+//! in emission order, so none of its blocks decode. Version 7 holds the corrected
+//! byte rANS (see `coder::RansCoder`) and is self-describing:
+//!
+//! | Offset | Size | Field                                                   |
+//! |--------|------|---------------------------------------------------------|
+//! | 0      | 4    | magic `NEUR`                                            |
+//! | 4      | 4    | format version (7)                                      |
+//! | 8      | 2    | header length: bytes from offset 10 up to the CRC       |
+//! | 10     | 1    | mode (0 Lossless, 1 Events, 2 Elite, 3 Hybrid,          |
+//! |        |      | 4 NearLossless)                                         |
+//! | 11     | 2    | flags (must be a subset of `KNOWN_FLAGS`)               |
+//! | 13     | 4    | sample rate                                             |
+//! | 17     | 2    | channels                                                |
+//! | 19     | 2    | bits per sample                                         |
+//! | 21     | 8    | total samples (interleaved)                             |
+//! | 29     | 4    | block size (frames per block)                           |
+//! | 33     | 1    | LPC order (the maximum when searched per block)         |
+//! | 34     | 1    | residual coder (0 Rice, 1 partitioned Rice, 2 adaptive) |
+//! | 35     | 2    | NearLossless maximum absolute error per sample          |
+//! | 10+len | 4    | CRC-32 of every preceding header byte, magic included   |
+//!
+//! Fields after the LPC order were appended later; when a stream's header is too
+//! short to contain one, readers use the value older writers implied (Rice, 0).
+//! Version 7 Events streams continue with `spike::EventParams` (threshold, snippet
+//! geometry, codebook id) and then one `u32` length-prefixed payload per channel,
+//! mono included, or, when `packet_len` is set, length-prefixed `realtime` packets
+//! up to a zero length. With `FLAG_EVENT_TABLES` (set when there are neither packets
+//! nor FEC parity), a `coder::RansTables` section sits between the parameters and
+//! the payloads, and each payload is a `RansCoder::encode_shared` one, naming its
+//! table by ID. With `FLAG_EVENT_FEC` (set when FEC parity is), the parameters are
+//! `fec`-coded with `spike::PARAMS_PARITY` check bytes, and every payload or packet
+//! length, the terminating zero included, is its `u32` coded with the stream's
+//! parity, so one bad byte outside the payloads no longer loses the stream.
+//! Version 4 Events streams have no parameters, just a bare payload
+//! running to the end of the file.
+//! Hybrid streams continue as described in `hybrid`.
+//! NearLossless blocks are laid out as Lossless ones, their residuals being
+//! quantisation indices (see `lpc::NearLossless`).
+//! With `FLAG_WARM_UP`, every Lossless/Elite block carries a flags byte after its
+//! length; bit 0 marks a block whose prediction does not reach into the previous one.
+//! With `FLAG_INTER_CHANNEL`, a reference byte follows (after the flags byte, if
+//! any): 0 for none, else the distance back to an earlier channel of the same
+//! frame, plus 0x80 if the block codes its prediction residuals minus that
+//! channel's rather than its samples minus that channel's decoded samples.
+//! With `FLAG_BLOCK_CRC`, each block is wrapped as sync `FF B5`, frame index u32,
+//! channel u16, body length u32, body, and a CRC-32 of everything after the sync.
+//! With `FLAG_ELITE_RANS`, Elite blocks hold rANS-coded residual symbols whose
+//! frequency table each channel adapts from block to block, starting afresh at
+//! independent blocks; without it they are sparse + byte rANS payloads whose
+//! 16-bit values escape larger ones (see `simd_ops::SimdOps::serialize`).
+//! With `FLAG_SEEK_TABLE`, the last block is followed by a `SeekTable` and then the
+//! table's byte offset as a `u64`, so readers can find it from the end of the file.
+//!
+//! Writers emit exactly the fields above. Readers skip any bytes beyond them (still
+//! covered by the CRC), so parameters can be appended without a version bump; a new
+//! version is only needed when older readers must refuse the stream.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+
+use crate::coder::ResidualCoder;
+use crate::stream::{Mode, StreamInfo};
+
+pub const MAGIC: [u8; 4] = *b"NEUR";
+pub const CURRENT_VERSION: u32 = 7;
+/// Lossless/Elite blocks predict from the previous block of the same channel and
+/// start with a block flags byte marking independent blocks (seek points).
+pub const FLAG_WARM_UP: u16 = 0x0001;
+/// The stream ends with a `SeekTable` followed by the table's byte offset (u64).
+pub const FLAG_SEEK_TABLE: u16 = 0x0002;
+/// Every Lossless/Elite block is framed with a sync word, its position and a CRC-32.
+pub const FLAG_BLOCK_CRC: u16 = 0x0004;
+/// Elite blocks rANS-code residual symbols against per-channel tables shared
+/// across blocks (`coder::ResidualModel`) instead of the sparse byte layout.
+pub const FLAG_ELITE_RANS: u16 = 0x0008;
+/// Lossless/Elite blocks carry a reference byte naming an earlier channel of the
+/// same frame they are predicted from.
+pub const FLAG_INTER_CHANNEL: u16 = 0x0010;
+/// Events payloads are rANS-coded against tables shared by all channels.
+pub const FLAG_EVENT_TABLES: u16 = 0x0020;
+/// Events parameters and length prefixes are Reed–Solomon coded like the payloads.
+pub const FLAG_EVENT_FEC: u16 = 0x0040;
+/// Flag bits this build understands; streams using any other bit are rejected.
+pub const KNOWN_FLAGS: u16 = FLAG_WARM_UP
+    | FLAG_SEEK_TABLE
+    | FLAG_BLOCK_CRC
+    | FLAG_ELITE_RANS
+    | FLAG_INTER_CHANNEL
+    | FLAG_EVENT_TABLES
+    | FLAG_EVENT_FEC;
+
+const SEEK_MAGIC: [u8; 4] = *b"NSEK";
+
+/// Size of the version 7 fields between the length prefix and the CRC
+const V7_FIELDS_LEN: u16 = 27;
+/// Shortest header a version 7 reader accepts (fields up to the LPC order)
+const V7_MIN_FIELDS_LEN: u16 = 24;
+
+/// Errors raised while parsing a container header
+#[derive(Debug)]
+pub enum FormatError {
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u32),
+    /// A version 5 Elite stream, whose byte rANS payloads cannot be decoded
+    LegacyByteRans,
+    UnsupportedMode(u8),
+    UnsupportedFlags(u16),
+    UnsupportedCoder(u8),
+    TruncatedHeader,
+    HeaderChecksum { stored: u32, computed: u32 },
+    Io(io::Error),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::BadMagic(m) => write!(f, "Not a .neur stream (magic {:02x?})", m),
+            FormatError::UnsupportedVersion(v) => write!(f, "Unsupported format version {}", v),
+            FormatError::LegacyByteRans => write!(
+                f,
+                "Version 5 Elite streams hold byte rANS payloads that cannot be decoded; re-encode from the source recording"
+            ),
+            FormatError::UnsupportedMode(m) => write!(f, "Unsupported mode {}", m),
+            FormatError::UnsupportedFlags(bits) => write!(f, "Unsupported header flags {:#06x}", bits),
+            FormatError::UnsupportedCoder(c) => write!(f, "Unsupported residual coder {}", c),
+            FormatError::TruncatedHeader => write!(f, "Truncated header"),
+            FormatError::HeaderChecksum { stored, computed } => write!(
+                f,
+                "Header CRC-32 mismatch (stored {:08x}, computed {:08x})",
+                stored, computed
+            ),
+            FormatError::Io(e) => write!(f, "I/O error reading header: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            FormatError::TruncatedHeader
+        } else {
+            FormatError::Io(e)
+        }
+    }
+}
+
+/// Parsed container header
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub version: u32,
+    pub mode: Mode,
+    pub flags: u16,
+    pub info: StreamInfo,
+    /// Frames per block; 0 for legacy streams, which store it per block only
+    pub block_size: u32,
+    /// LPC order; 0 for legacy streams, which store it per block only
+    pub order: u8,
+    /// Entropy coder for Lossless residuals
+    pub residual_coder: ResidualCoder,
+    /// NearLossless: largest difference between a decoded and an original sample
+    pub max_error: u16,
+}
+
+fn coder_to_byte(coder: ResidualCoder) -> u8 {
+    match coder {
+        ResidualCoder::Rice => 0,
+        ResidualCoder::Partitioned => 1,
+        ResidualCoder::Adaptive => 2,
+    }
+}
+
+fn coder_from_byte(byte: u8) -> Result<ResidualCoder, FormatError> {
+    match byte {
+        0 => Ok(ResidualCoder::Rice),
+        1 => Ok(ResidualCoder::Partitioned),
+        2 => Ok(ResidualCoder::Adaptive),
+        other => Err(FormatError::UnsupportedCoder(other)),
+    }
+}
+
+fn mode_to_byte(mode: Mode) -> u8 {
+    match mode {
+        Mode::Lossless => 0,
+        Mode::Events => 1,
+        Mode::Elite => 2,
+        Mode::Hybrid => 3,
+        Mode::NearLossless => 4,
+    }
+}
+
+fn mode_from_byte(byte: u8) -> Result<Mode, FormatError> {
+    match byte {
+        0 => Ok(Mode::Lossless),
+        1 => Ok(Mode::Events),
+        2 => Ok(Mode::Elite),
+        3 => Ok(Mode::Hybrid),
+        4 => Ok(Mode::NearLossless),
+        other => Err(FormatError::UnsupportedMode(other)),
+    }
+}
+
+impl Header {
+    /// Header for a new stream in the current format version
+    pub fn new(mode: Mode, info: StreamInfo, block_size: u32, order: u8) -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            mode,
+            flags: 0,
+            info,
+            block_size,
+            order,
+            residual_coder: ResidualCoder::Partitioned,
+            max_error: 0,
+        }
+    }
+
+    /// True for legacy streams that store raw f64 LPC coefficients (v1)
+    pub fn float_coefficients(&self) -> bool {
+        self.version == 1
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(10 + V7_FIELDS_LEN as usize + 4);
+        buf.extend_from_slice(&MAGIC);
+        buf.write_u32::<BigEndian>(CURRENT_VERSION)?;
+        buf.write_u16::<BigEndian>(V7_FIELDS_LEN)?;
+        buf.write_u8(mode_to_byte(self.mode))?;
+        buf.write_u16::<BigEndian>(self.flags)?;
+        buf.write_u32::<BigEndian>(self.info.sample_rate)?;
+        buf.write_u16::<BigEndian>(self.info.channels)?;
+        buf.write_u16::<BigEndian>(self.info.bits_per_sample)?;
+        buf.write_u64::<BigEndian>(self.info.total_samples)?;
+        buf.write_u32::<BigEndian>(self.block_size)?;
+        buf.write_u8(self.order)?;
+        buf.write_u8(coder_to_byte(self.residual_coder))?;
+        buf.write_u16::<BigEndian>(self.max_error)?;
+        let crc = crc32fast::hash(&buf);
+        buf.write_u32::<BigEndian>(crc)?;
+        out.write_all(&buf)
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> Result<Self, FormatError> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(FormatError::BadMagic(magic));
+        }
+        let version = input.read_u32::<BigEndian>()?;
+        match version {
+            1 | 4 => Self::read_legacy(input, version),
+            5 => Err(FormatError::LegacyByteRans),
+            CURRENT_VERSION => Self::read_v7(input),
+            other => Err(FormatError::UnsupportedVersion(other)),
+        }
+    }
+
+    fn read_legacy<R: Read>(input: &mut R, version: u32) -> Result<Self, FormatError> {
+        let mode = if version == 1 { Mode::Lossless } else { Mode::Events };
+        let info = StreamInfo {
+            sample_rate: input.read_u32::<BigEndian>()?,
+            channels: input.read_u16::<BigEndian>()?,
+            bits_per_sample: input.read_u16::<BigEndian>()?,
+            total_samples: input.read_u64::<BigEndian>()?,
+        };
+        Ok(Self {
+            version,
+            mode,
+            flags: 0,
+            info,
+            block_size: 0,
+            order: 0,
+            residual_coder: ResidualCoder::Rice,
+            max_error: 0,
+        })
+    }
+
+    fn read_v7<R: Read>(input: &mut R) -> Result<Self, FormatError> {
+        let len = input.read_u16::<BigEndian>()?;
+        if len < V7_MIN_FIELDS_LEN {
+            return Err(FormatError::TruncatedHeader);
+        }
+        let mut fields = vec![0u8; len as usize];
+        input.read_exact(&mut fields)?;
+        let stored = input.read_u32::<BigEndian>()?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&MAGIC);
+        hasher.update(&CURRENT_VERSION.to_be_bytes());
+        hasher.update(&len.to_be_bytes());
+        hasher.update(&fields);
+        let computed = hasher.finalize();
+        if stored != computed {
+            return Err(FormatError::HeaderChecksum { stored, computed });
+        }
+
+        let mut cursor = Cursor::new(fields);
+        let mode = mode_from_byte(cursor.read_u8()?)?;
+        let flags = cursor.read_u16::<BigEndian>()?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(FormatError::UnsupportedFlags(flags & !KNOWN_FLAGS));
+        }
+        let info = StreamInfo {
+            sample_rate: cursor.read_u32::<BigEndian>()?,
+            channels: cursor.read_u16::<BigEndian>()?,
+            bits_per_sample: cursor.read_u16::<BigEndian>()?,
+            total_samples: cursor.read_u64::<BigEndian>()?,
+        };
+        let block_size = cursor.read_u32::<BigEndian>()?;
+        let order = cursor.read_u8()?;
+        let residual_coder = if len > V7_MIN_FIELDS_LEN {
+            coder_from_byte(cursor.read_u8()?)?
+        } else {
+            ResidualCoder::Rice
+        };
+        let max_error = if len >= V7_MIN_FIELDS_LEN + 3 { cursor.read_u16::<BigEndian>()? } else { 0 };
+        Ok(Self { version: CURRENT_VERSION, mode, flags, info, block_size, order, residual_coder, max_error })
+    }
+}
+
+/// Frame that decoding can start at: its blocks do not predict from earlier samples
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeekPoint {
+    /// Per-channel index of the frame's first sample
+    pub sample: u64,
+    /// Byte offset of the frame from the start of the stream
+    pub offset: u64,
+}
+
+/// Trailer mapping sample positions to byte offsets
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SeekTable {
+    /// Sorted by `sample`
+    pub points: Vec<SeekPoint>,
+}
+
+impl SeekTable {
+    /// The last seek point at or before `sample`
+    pub fn lookup(&self, sample: u64) -> Option<SeekPoint> {
+        let idx = self.points.partition_point(|p| p.sample <= sample);
+        idx.checked_sub(1).map(|i| self.points[i])
+    }
+
+    /// `NSEK`, point count u32, `(sample u64, offset u64)` per point, CRC-32 of the table
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(12 + self.points.len() * 16);
+        buf.extend_from_slice(&SEEK_MAGIC);
+        buf.write_u32::<BigEndian>(self.points.len() as u32)?;
+        for point in &self.points {
+            buf.write_u64::<BigEndian>(point.sample)?;
+            buf.write_u64::<BigEndian>(point.offset)?;
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.write_u32::<BigEndian>(crc)?;
+        out.write_all(&buf)
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> anyhow::Result<Self> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if magic != SEEK_MAGIC {
+            anyhow::bail!("Seek table not found");
+        }
+        let count = input.read_u32::<BigEndian>()?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&magic);
+        hasher.update(&count.to_be_bytes());
+        let mut points = Vec::new();
+        for _ in 0..count {
+            let mut entry = [0u8; 16];
+            input.read_exact(&mut entry)?;
+            hasher.update(&entry);
+            let mut cursor = Cursor::new(entry);
+            points.push(SeekPoint {
+                sample: cursor.read_u64::<BigEndian>()?,
+                offset: cursor.read_u64::<BigEndian>()?,
+            });
+        }
+        if input.read_u32::<BigEndian>()? != hasher.finalize() {
+            anyhow::bail!("Seek table CRC-32 mismatch");
+        }
+        if !points.windows(2).all(|w| w[0].sample < w[1].sample) {
+            anyhow::bail!("Seek table is not sorted");
+        }
+        Ok(Self { points })
+    }
+}
@@ -0,0 +1,226 @@
+//! Reed–Solomon forward error correction over GF(2^8) for telemetry packets.
+//!
+//! Data is cut into packets of `255 - parity` bytes, each followed by `parity`
+//! check bytes (the last packet is shortened). A packet with up to `parity / 2`
+//! corrupted bytes is repaired on decode.
+
+use anyhow::Result;
+
+/// Bytes in a full packet, data plus parity
+pub const PACKET_LEN: usize = 255;
+/// Most parity bytes a packet may carry
+pub const MAX_PARITY: u8 = 128;
+
+/// Primitive polynomial x^8 + x^4 + x^3 + x^2 + 1
+const PRIMITIVE: u16 = 0x11d;
+
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const fn build_tables() -> Tables {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= PRIMITIVE;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    Tables { exp, log }
+}
+
+static GF: Tables = build_tables();
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF.exp[GF.log[a as usize] as usize + GF.log[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    debug_assert!(b != 0, "division by zero in GF(256)");
+    if a == 0 {
+        return 0;
+    }
+    GF.exp[GF.log[a as usize] as usize + 255 - GF.log[b as usize] as usize]
+}
+
+/// alpha^power
+fn alpha(power: usize) -> u8 {
+    GF.exp[power % 255]
+}
+
+/// Evaluate a polynomial stored lowest degree first
+fn eval_ascending(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+/// Generator `(x - a^0)(x - a^1)...(x - a^(parity-1))`, highest degree first
+fn generator(parity: usize) -> Vec<u8> {
+    let mut g = vec![1u8];
+    for i in 0..parity {
+        let mut next = vec![0u8; g.len() + 1];
+        for (j, &c) in g.iter().enumerate() {
+            next[j] ^= c;
+            next[j + 1] ^= mul(c, alpha(i));
+        }
+        g = next;
+    }
+    g
+}
+
+/// Check bytes for one packet's data: the remainder of `data * x^parity` by the generator
+fn parity_bytes(data: &[u8], generator: &[u8]) -> Vec<u8> {
+    let parity = generator.len() - 1;
+    let mut remainder = vec![0u8; parity];
+    for &byte in data {
+        let factor = byte ^ remainder[0];
+        remainder.rotate_left(1);
+        remainder[parity - 1] = 0;
+        for (r, &g) in remainder.iter_mut().zip(&generator[1..]) {
+            *r ^= mul(g, factor);
+        }
+    }
+    remainder
+}
+
+/// Append `parity` check bytes to every `255 - parity` bytes of `data`.
+pub fn encode(data: &[u8], parity: u8) -> Vec<u8> {
+    let parity = parity as usize;
+    if parity == 0 {
+        return data.to_vec();
+    }
+    let generator = generator(parity);
+    let mut out = Vec::with_capacity(data.len() + data.len().div_ceil(PACKET_LEN - parity) * parity);
+    for chunk in data.chunks(PACKET_LEN - parity) {
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(&parity_bytes(chunk, &generator));
+    }
+    out
+}
+
+/// Strip the check bytes added by `encode`, repairing each packet on the way.
+/// Returns the data and the number of bytes corrected; fails if any packet has
+/// more corrupted bytes than its parity can repair.
+pub fn decode(data: &[u8], parity: u8) -> Result<(Vec<u8>, usize)> {
+    let parity = parity as usize;
+    if parity == 0 {
+        return Ok((data.to_vec(), 0));
+    }
+    let mut out = Vec::with_capacity(data.len());
+    let mut corrected = 0;
+    for (index, packet) in data.chunks(PACKET_LEN).enumerate() {
+        if packet.len() <= parity {
+            anyhow::bail!("FEC packet {} is truncated ({} bytes)", index, packet.len());
+        }
+        let mut packet = packet.to_vec();
+        match correct_packet(&mut packet, parity) {
+            Some(n) => corrected += n,
+            None => anyhow::bail!("FEC packet {} has more than {} corrupted bytes", index, parity / 2),
+        }
+        out.extend_from_slice(&packet[..packet.len() - parity]);
+    }
+    Ok((out, corrected))
+}
+
+/// Syndromes `c(a^j)` for `j < parity`; all zero for a valid codeword
+fn syndromes(codeword: &[u8], parity: usize) -> Vec<u8> {
+    (0..parity)
+        .map(|j| codeword.iter().fold(0, |acc, &c| mul(acc, alpha(j)) ^ c))
+        .collect()
+}
+
+/// Berlekamp–Massey decoding with Chien search and Forney's formula. Returns the
+/// number of bytes fixed, or `None` when the packet is beyond repair.
+fn correct_packet(codeword: &mut [u8], parity: usize) -> Option<usize> {
+    let synd = syndromes(codeword, parity);
+    if synd.iter().all(|&s| s == 0) {
+        return Some(0);
+    }
+
+    // Error locator, lowest degree first
+    let mut locator = vec![1u8];
+    let mut previous = vec![1u8];
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut last_discrepancy = 1u8;
+    for r in 0..parity {
+        let mut discrepancy = synd[r];
+        for i in 1..=errors.min(locator.len() - 1) {
+            discrepancy ^= mul(locator[i], synd[r - i]);
+        }
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let scale = div(discrepancy, last_discrepancy);
+        let mut next = locator.clone();
+        next.resize(next.len().max(previous.len() + shift), 0);
+        for (i, &p) in previous.iter().enumerate() {
+            next[i + shift] ^= mul(scale, p);
+        }
+        if 2 * errors <= r {
+            previous = std::mem::replace(&mut locator, next);
+            errors = r + 1 - errors;
+            last_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            locator = next;
+            shift += 1;
+        }
+    }
+    if 2 * errors > parity {
+        return None;
+    }
+
+    // Chien search: byte `idx` holds the coefficient of x^(len - 1 - idx)
+    let len = codeword.len();
+    let positions: Vec<usize> = (0..len)
+        .filter(|&idx| eval_ascending(&locator, alpha(255 - (len - 1 - idx) % 255)) == 0)
+        .collect();
+    if positions.len() != errors {
+        return None;
+    }
+
+    // Forney: e = X * omega(X^-1) / locator'(X^-1), with omega = S * locator mod x^parity
+    let mut omega = vec![0u8; parity];
+    for (i, &s) in synd.iter().enumerate() {
+        for (j, &l) in locator.iter().enumerate() {
+            if i + j < parity {
+                omega[i + j] ^= mul(s, l);
+            }
+        }
+    }
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, &c)| if i % 2 == 1 { c } else { 0 })
+        .collect();
+    for &idx in &positions {
+        let power = len - 1 - idx;
+        let x = alpha(power);
+        let x_inv = alpha(255 - power % 255);
+        let denominator = eval_ascending(&derivative, x_inv);
+        if denominator == 0 {
+            return None;
+        }
+        codeword[idx] ^= mul(x, div(eval_ascending(&omega, x_inv), denominator));
+    }
+    if syndromes(codeword, parity).iter().any(|&s| s != 0) {
+        return None;
+    }
+    Some(errors)
+}
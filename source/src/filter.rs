@@ -0,0 +1,86 @@
+//! Integer band-pass prefilter for spike detection.
+//!
+//! Second-order Butterworth high- and low-pass sections (bilinear transform),
+//! with coefficients quantised to `COEFF_BITS` fractional bits and evaluated in
+//! integer arithmetic, so every platform filters bit-identically. The filter is
+//! causal: events trail the raw waveform by its group delay, a few samples in the
+//! usual 300–6000 Hz band.
+
+use anyhow::Result;
+
+/// Fractional bits of the fixed-point coefficients
+const COEFF_BITS: u32 = 24;
+
+/// Direct form I biquad: `y = (b0 x + b1 x1 + b2 x2 - a1 y1 - a2 y2) >> COEFF_BITS`
+#[derive(Clone, Debug)]
+struct Biquad {
+    b: [i64; 3],
+    a: [i64; 2],
+    x: [i64; 2],
+    y: [i64; 2],
+}
+
+impl Biquad {
+    /// RBJ cookbook section with Butterworth Q at `cutoff` Hz
+    fn butterworth(cutoff: f64, sample_rate: f64, high_pass: bool) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff / sample_rate;
+        let alpha = w0.sin() / std::f64::consts::SQRT_2;
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        let (b0, b1) = if high_pass { ((1.0 + cos) / 2.0, -(1.0 + cos)) } else { ((1.0 - cos) / 2.0, 1.0 - cos) };
+        let q = |c: f64| (c / a0 * (1i64 << COEFF_BITS) as f64).round() as i64;
+        Self { b: [q(b0), q(b1), q(b0)], a: [q(-2.0 * cos), q(1.0 - alpha)], x: [0; 2], y: [0; 2] }
+    }
+
+    fn process(&mut self, x: i64) -> i64 {
+        let acc = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        // Round to nearest; keep the state bounded so a pathological input cannot overflow
+        let y = ((acc + (1 << (COEFF_BITS - 1))) >> COEFF_BITS).clamp(i32::MIN as i64, i32::MAX as i64);
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// High-pass at `low_cut` followed by low-pass at `high_cut`; either edge may be
+/// left out (0) for a plain low- or high-pass.
+#[derive(Clone, Debug)]
+pub struct BandPass {
+    stages: Vec<Biquad>,
+}
+
+impl BandPass {
+    pub fn new(low_cut: u32, high_cut: u32, sample_rate: u32) -> Result<Self> {
+        let nyquist = sample_rate / 2;
+        if low_cut == 0 && high_cut == 0 {
+            anyhow::bail!("Band-pass filter needs a low or high cutoff");
+        }
+        if low_cut >= nyquist || high_cut >= nyquist {
+            anyhow::bail!("Filter cutoffs must be below {} Hz at {} Hz", nyquist, sample_rate);
+        }
+        if high_cut != 0 && low_cut >= high_cut {
+            anyhow::bail!("Low cutoff {} Hz is not below high cutoff {} Hz", low_cut, high_cut);
+        }
+        let rate = sample_rate as f64;
+        let mut stages = Vec::new();
+        if low_cut > 0 {
+            stages.push(Biquad::butterworth(low_cut as f64, rate, true));
+        }
+        if high_cut > 0 {
+            stages.push(Biquad::butterworth(high_cut as f64, rate, false));
+        }
+        Ok(Self { stages })
+    }
+
+    /// Filter the next sample
+    pub fn process(&mut self, x: i32) -> i32 {
+        self.stages.iter_mut().fold(x as i64, |v, stage| stage.process(v)) as i32
+    }
+
+    /// Filter a whole channel, continuing from the current state
+    pub fn apply(&mut self, samples: &[i32]) -> Vec<i32> {
+        samples.iter().map(|&x| self.process(x)).collect()
+    }
+}
@@ -0,0 +1,172 @@
+//! Hybrid mode: spike windows stored losslessly, the background between them
+//! reduced to a cheap approximation.
+//!
+//! After the container header a Hybrid stream holds `HybridParams`, then per
+//! channel:
+//!
+//! | Size | Field                                                             |
+//! |------|-------------------------------------------------------------------|
+//! | 4    | window count                                                      |
+//! | ...  | per window: samples since the previous window's end (u32), then a |
+//! |      | Lossless block of the window's samples (no warm-up)               |
+//! | 4    | background value count (absent for `Background::None`)           |
+//! | ...  | `Decimated`: Lossless blocks of up to `block_size` segment means; |
+//! |      | `Noise`: one u16 noise level per segment                          |
+//!
+//! Windows come from `SpikeCompressor::spike_windows`. Background segments are
+//! `factor` samples long and ignore the samples inside spike windows.
+
+use anyhow::Result;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use clap::ValueEnum;
+use std::io::{Cursor, Read, Write};
+use std::ops::Range;
+
+/// Default background segment length in samples
+pub const DEFAULT_DECIMATION: u16 = 32;
+
+/// What a Hybrid stream keeps of the signal outside spike windows
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Background {
+    /// Nothing: silence between spikes
+    None,
+    /// One mean per segment, linearly interpolated on decode (LFP)
+    Decimated,
+    /// One noise level per segment, resynthesised as pseudo-random noise
+    Noise,
+}
+
+impl Background {
+    fn code(self) -> u8 {
+        match self {
+            Background::None => 0,
+            Background::Decimated => 1,
+            Background::Noise => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0 => Ok(Background::None),
+            1 => Ok(Background::Decimated),
+            2 => Ok(Background::Noise),
+            _ => anyhow::bail!("Unknown hybrid background {}", code),
+        }
+    }
+}
+
+/// `len u16` (bytes that follow), `background u8`, `factor u16`; readers skip
+/// bytes beyond the known fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HybridParams {
+    pub background: Background,
+    /// Samples per background segment
+    pub factor: u16,
+}
+
+const HYBRID_PARAMS_LEN: u16 = 3;
+
+impl HybridParams {
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_u16::<BigEndian>(HYBRID_PARAMS_LEN)?;
+        out.write_u8(self.background.code())?;
+        out.write_u16::<BigEndian>(self.factor)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let len = input.read_u16::<BigEndian>()?;
+        if len < HYBRID_PARAMS_LEN {
+            anyhow::bail!("Hybrid header too short ({} bytes)", len);
+        }
+        let mut fields = vec![0u8; len as usize];
+        input.read_exact(&mut fields)?;
+        let mut cursor = Cursor::new(fields);
+        let params = Self {
+            background: Background::from_code(cursor.read_u8()?)?,
+            factor: cursor.read_u16::<BigEndian>()?,
+        };
+        if params.factor == 0 {
+            anyhow::bail!("Hybrid background segments must be non-empty");
+        }
+        Ok(params)
+    }
+}
+
+/// True for every sample inside one of `windows`
+pub fn window_mask(windows: &[Range<usize>], len: usize) -> Vec<bool> {
+    let mut mask = vec![false; len];
+    for window in windows {
+        mask[window.clone()].fill(true);
+    }
+    mask
+}
+
+/// Samples of each `factor`-long segment outside the spike windows, or the whole
+/// segment if it lies entirely inside them
+fn background_of<'a>(samples: &'a [i32], mask: &'a [bool], factor: usize) -> impl Iterator<Item = Vec<i32>> + 'a {
+    samples.chunks(factor).zip(mask.chunks(factor)).map(|(segment, masked)| {
+        let outside: Vec<i32> = segment.iter().zip(masked).filter(|(_, &m)| !m).map(|(&x, _)| x).collect();
+        if outside.is_empty() {
+            segment.to_vec()
+        } else {
+            outside
+        }
+    })
+}
+
+/// Rounded mean of each segment's background
+pub fn decimate(samples: &[i32], mask: &[bool], factor: usize) -> Vec<i32> {
+    background_of(samples, mask, factor)
+        .map(|segment| (segment.iter().map(|&x| x as f64).sum::<f64>() / segment.len() as f64).round() as i32)
+        .collect()
+}
+
+/// `len` samples through the segment means, each placed at its segment's centre;
+/// held flat before the first centre and after the last.
+pub fn interpolate(means: &[i32], factor: usize, len: usize) -> Vec<i32> {
+    let centre = |i: usize| i as f64 * factor as f64 + (factor as f64 - 1.0) / 2.0;
+    (0..len)
+        .map(|t| {
+            let Some((&first, &last)) = means.first().zip(means.last()) else { return 0 };
+            let position = (t as f64 - centre(0)) / factor as f64;
+            if position <= 0.0 {
+                return first;
+            }
+            let i = position.floor() as usize;
+            if i + 1 >= means.len() {
+                return last;
+            }
+            let frac = position - i as f64;
+            (means[i] as f64 + (means[i + 1] - means[i]) as f64 * frac).round() as i32
+        })
+        .collect()
+}
+
+/// Noise standard deviation of each segment's background, saturated to u16. It
+/// is measured on successive differences (RMS / sqrt(2)), so the LFP's slope
+/// does not count as noise.
+pub fn noise_levels(samples: &[i32], mask: &[bool], factor: usize) -> Vec<u16> {
+    background_of(samples, mask, factor)
+        .map(|segment| {
+            let pairs = segment.len().saturating_sub(1).max(1) as f64;
+            let sum_sq: f64 = segment.windows(2).map(|w| (w[1] as f64 - w[0] as f64).powi(2)).sum();
+            (sum_sq / pairs / 2.0).sqrt().round().min(u16::MAX as f64) as u16
+        })
+        .collect()
+}
+
+/// Uniform pseudo-random noise with each segment's standard deviation; the same
+/// `seed` always gives the same samples.
+pub fn synthesize_noise(levels: &[u16], factor: usize, len: usize, seed: u32) -> Vec<i32> {
+    let mut state = seed ^ 0x9e37_79b9;
+    (0..len)
+        .map(|t| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let level = levels.get(t / factor).copied().unwrap_or(0) as f64;
+            // Uniform on [-a, a] has standard deviation a / sqrt(3)
+            let unit = (state >> 8) as f64 / (1u32 << 24) as f64 * 2.0 - 1.0;
+            (unit * level * 3f64.sqrt()).round() as i32
+        })
+        .collect()
+}
@@ -0,0 +1,85 @@
+//! Neuralink Data Compressor - Ouroboros Elite
+//!
+//! Lossless (LPC + Rice), NearLossless (LPC + Rice within a fixed error bound),
+//! Elite (LPC + adaptive rANS) and Events (spike VQ) codecs for neural recordings.
+//! `compress`/`decompress` work on whole buffers; `Encoder` and `Decoder` stream
+//! through `std::io::Write`/`Read` with bounded memory.
+
+use anyhow::Result;
+
+pub mod arith;
+pub mod codebook;
+pub mod coder;
+pub mod container;
+pub mod fec;
+pub mod filter;
+pub mod hybrid;
+pub mod lpc;
+pub mod realtime;
+pub mod simd_ops;
+pub mod sparse;
+pub mod spike;
+pub mod stream;
+
+pub use codebook::Codebook;
+pub use coder::ResidualCoder;
+pub use container::{FormatError, Header, SeekTable};
+pub use stream::{encode_recording, Config, Damage, Decoder, Encoder, Mode, StreamInfo};
+
+/// Output of `decompress`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Decoded {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    /// Interleaved samples, `channels` per frame
+    pub samples: Vec<i16>,
+    /// Zero-filled ranges of block-CRC streams whose blocks were corrupt
+    pub damaged: Vec<Damage>,
+}
+
+/// Compress interleaved 16-bit samples, whole frames of `config.channels`, into a
+/// `.neur` byte stream
+pub fn compress(samples: &[i16], config: Config) -> Result<Vec<u8>> {
+    let info = StreamInfo {
+        sample_rate: config.sample_rate,
+        channels: config.channels,
+        bits_per_sample: 16,
+        total_samples: samples.len() as u64,
+    };
+    let widened: Vec<i32> = samples.iter().map(|&s| s as i32).collect();
+    stream::encode_recording(Vec::new(), info, config, None, &widened)
+}
+
+/// Decompress a complete `.neur` byte stream
+pub fn decompress(data: &[u8]) -> Result<Decoded> {
+    let mut decoder = Decoder::new(data)?;
+    let info = *decoder.info();
+    let mut samples = Vec::with_capacity(info.total_samples as usize);
+    while let Some(block) = decoder.read_block()? {
+        samples.extend(block.into_iter().map(|s| s as i16));
+    }
+    Ok(Decoded {
+        sample_rate: info.sample_rate,
+        channels: info.channels,
+        bits_per_sample: info.bits_per_sample,
+        samples,
+        damaged: decoder.damaged().to_vec(),
+    })
+}
+
+/// Decompress per-channel samples `start..end` of a `.neur` byte stream, using the
+/// seek table to skip straight to the first block that covers `start`.
+pub fn decode_range(data: &[u8], start: u64, end: u64) -> Result<Decoded> {
+    let mut decoder = Decoder::new(std::io::Cursor::new(data))?;
+    let info = *decoder.info();
+    let samples = decoder.read_range(start, end)?;
+    Ok(Decoded {
+        sample_rate: info.sample_rate,
+        channels: info.channels,
+        bits_per_sample: info.bits_per_sample,
+        samples: samples.into_iter().map(|s| s as i16).collect(),
+        damaged: decoder.damaged().to_vec(),
+    })
+}
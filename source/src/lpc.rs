@@ -1,4 +1,8 @@
+use anyhow::Result;
+use bitstream_io::{BigEndian, BitRead, BitReader, BitWrite, BitWriter};
+use byteorder::{ReadBytesExt, WriteBytesExt};
 use std::f64;
+use std::io::{Cursor, Read, Write};
 
 /// Compute Autocorrelation coefficients
 pub fn autocorrelation(signal: &[i32], order: usize) -> Vec<f64> {
@@ -48,27 +52,6 @@ pub fn levinson_durbin(r: &[f64], order: usize) -> Vec<f64> {
     a[1..].to_vec()
 }
 
-/// Apply LPC prediction to calculate residuals
-pub fn compute_residuals(signal: &[i32], coeffs: &[f64]) -> Vec<i32> {
-    let mut residuals = Vec::with_capacity(signal.len());
-    let order = coeffs.len();
-
-    for i in 0..signal.len() {
-        let mut prediction = 0.0;
-        for j in 0..order {
-            if i > j {
-                prediction -= coeffs[j] * (signal[i - j - 1] as f64);
-            }
-        }
-        
-        // Round to nearest integer for integer-to-integer reversibility
-        let pred_int = prediction.round() as i32;
-        let residual = signal[i] - pred_int;
-        residuals.push(residual);
-    }
-    residuals
-}
-
 /// Restore signal from residuals using LPC coefficients
 pub fn restore_signal(residuals: &[i32], coeffs: &[f64]) -> Vec<i32> {
     let mut signal = Vec::with_capacity(residuals.len());
@@ -88,3 +71,209 @@ pub fn restore_signal(residuals: &[i32], coeffs: &[f64]) -> Vec<i32> {
     }
     signal
 }
+
+/// Largest coefficient shift we allow; keeps `coeff << shift` products well inside i64.
+const MAX_QLP_SHIFT: i32 = 15;
+
+/// LPC coefficients quantized to fixed-point integers (FLAC-style "qlp" coefficients).
+/// Prediction is `(sum coeffs[j] * x[i - j - 1]) >> shift`, so encoder and decoder
+/// agree bit-for-bit on every platform.
+pub struct QuantizedCoeffs {
+    pub coeffs: Vec<i32>,
+    pub precision: u32,
+    pub shift: u32,
+}
+
+/// Coefficient precisions a stream can carry: from the 2 bits of the narrowest
+/// fixed predictor up to the widest `qlp_precision`
+const QLP_PRECISION_RANGE: std::ops::RangeInclusive<u32> = 2..=13;
+
+/// Coefficient precision in bits (including sign) for a given block size.
+/// Short blocks cannot amortise wide coefficients, so they get fewer bits.
+pub fn qlp_precision(block_size: usize) -> u32 {
+    match block_size {
+        0..=192 => 7,
+        193..=384 => 8,
+        385..=576 => 9,
+        577..=1152 => 10,
+        1153..=2304 => 11,
+        2305..=4608 => 12,
+        _ => 13,
+    }
+}
+
+impl QuantizedCoeffs {
+    /// Quantize Levinson-Durbin output (`a[1..]`, prediction = -sum a*x) to `precision` bits.
+    /// Rounding error is fed forward into the next coefficient, as in FLAC.
+    pub fn quantize(lp: &[f64], precision: u32) -> Self {
+        let cmax = lp.iter().fold(0.0f64, |m, c| m.max(c.abs()));
+        if cmax <= 0.0 || !cmax.is_finite() {
+            return Self { coeffs: vec![0; lp.len()], precision, shift: 0 };
+        }
+        let qmax = (1i32 << (precision - 1)) - 1;
+        let qmin = -(1i32 << (precision - 1));
+        // Exponent such that cmax = m * 2^log2cmax with m in [0.5, 1)
+        let log2cmax = cmax.log2().floor() as i32 + 1;
+        let shift = (precision as i32 - 1 - log2cmax).clamp(0, MAX_QLP_SHIFT);
+
+        let scale = (1i64 << shift) as f64;
+        let mut error = 0.0;
+        let coeffs = lp
+            .iter()
+            .map(|&a| {
+                error += -a * scale;
+                let q = (error.round() as i32).clamp(qmin, qmax);
+                error -= q as f64;
+                q
+            })
+            .collect();
+        Self { coeffs, precision, shift: shift as u32 }
+    }
+
+    /// Serialise as `precision u8, shift u8` followed by the coefficients bit-packed
+    /// at `precision` bits each (two's complement), padded to a byte boundary.
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_u8(self.precision as u8)?;
+        out.write_u8(self.shift as u8)?;
+        let mut writer = BitWriter::endian(Vec::new(), BigEndian);
+        for &c in &self.coeffs {
+            writer.write_signed(self.precision, c)?;
+        }
+        writer.byte_align()?;
+        out.write_all(&writer.into_writer())?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: &mut R, order: usize) -> Result<Self> {
+        let precision = input.read_u8()? as u32;
+        let shift = input.read_u8()? as u32;
+        if !QLP_PRECISION_RANGE.contains(&precision) || shift > MAX_QLP_SHIFT as u32 {
+            anyhow::bail!("Invalid LPC coefficient precision {} / shift {}", precision, shift);
+        }
+        let mut packed = vec![0u8; (order * precision as usize).div_ceil(8)];
+        input.read_exact(&mut packed)?;
+        let mut reader = BitReader::endian(Cursor::new(packed), BigEndian);
+        let mut coeffs = Vec::with_capacity(order);
+        for _ in 0..order {
+            coeffs.push(reader.read_signed::<i32>(precision)?);
+        }
+        Ok(Self { coeffs, precision, shift })
+    }
+
+    fn predict(&self, history: &[i32], i: usize) -> i32 {
+        let mut sum: i64 = 0;
+        for (j, &c) in self.coeffs.iter().enumerate() {
+            if i > j {
+                sum += c as i64 * history[i - j - 1] as i64;
+            }
+        }
+        (sum >> self.shift) as i32
+    }
+}
+
+/// Highest-order fixed polynomial predictor
+pub const MAX_FIXED_ORDER: usize = 4;
+
+/// FLAC's fixed polynomial predictor of `order` 0..=4 (`order`-th difference),
+/// expressed as exact integer coefficients with zero shift.
+pub fn fixed_predictor(order: usize) -> QuantizedCoeffs {
+    let order = order.min(MAX_FIXED_ORDER);
+    let mut binomial = 1i32;
+    let mut coeffs = Vec::with_capacity(order);
+    for j in 1..=order {
+        binomial = binomial * (order as i32 - j as i32 + 1) / j as i32;
+        coeffs.push(if j % 2 == 1 { binomial } else { -binomial });
+    }
+    let widest = coeffs.iter().map(|&c| 33 - (c ^ (c >> 31)).leading_zeros()).max().unwrap_or(2);
+    QuantizedCoeffs { coeffs, precision: widest.max(2), shift: 0 }
+}
+
+/// Apply integer LPC prediction to calculate residuals
+pub fn compute_residuals_fixed(signal: &[i32], qlp: &QuantizedCoeffs) -> Vec<i32> {
+    compute_residuals_continued(&[], signal, qlp)
+}
+
+/// Integer-only counterpart of `restore_signal`
+pub fn restore_signal_fixed(residuals: &[i32], qlp: &QuantizedCoeffs) -> Vec<i32> {
+    restore_signal_continued(&[], residuals, qlp)
+}
+
+/// `compute_residuals_fixed` with the preceding samples (`history`, oldest first)
+/// available to the predictor, so prediction runs on across a block boundary.
+pub fn compute_residuals_continued(history: &[i32], signal: &[i32], qlp: &QuantizedCoeffs) -> Vec<i32> {
+    let mut buffer = Vec::with_capacity(history.len() + signal.len());
+    buffer.extend_from_slice(history);
+    buffer.extend_from_slice(signal);
+    (history.len()..buffer.len())
+        .map(|i| buffer[i].wrapping_sub(qlp.predict(&buffer, i)))
+        .collect()
+}
+
+/// Inverse of `compute_residuals_continued`
+pub fn restore_signal_continued(history: &[i32], residuals: &[i32], qlp: &QuantizedCoeffs) -> Vec<i32> {
+    let mut buffer = Vec::with_capacity(history.len() + residuals.len());
+    buffer.extend_from_slice(history);
+    for &r in residuals {
+        let prediction = qlp.predict(&buffer, buffer.len());
+        buffer.push(r.wrapping_add(prediction));
+    }
+    buffer.split_off(history.len())
+}
+
+/// Reconstruction a near-lossless block decodes to: residuals are quantised to
+/// multiples of `2 * max_error + 1` inside the prediction loop (as in JPEG-LS), so
+/// every reconstructed sample is within `max_error` of the original and the
+/// predictor only ever sees samples the decoder will also have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NearLossless {
+    pub max_error: u32,
+    /// Reconstructions are clamped to the sample format's range
+    pub bits_per_sample: u32,
+}
+
+impl NearLossless {
+    fn step(&self) -> i64 {
+        2 * self.max_error as i64 + 1
+    }
+
+    fn quantize(&self, residual: i64) -> i64 {
+        let magnitude = (residual.abs() + self.max_error as i64) / self.step();
+        magnitude * residual.signum()
+    }
+
+    fn reconstruct(&self, prediction: i32, index: i32) -> i32 {
+        self.clamp(prediction as i64 + index as i64 * self.step())
+    }
+
+    /// `sample` limited to the range of `bits_per_sample`-bit samples
+    pub fn clamp(&self, sample: i64) -> i32 {
+        let bits = self.bits_per_sample.clamp(2, 32);
+        let max = (1i64 << (bits - 1)) - 1;
+        sample.clamp(-max - 1, max) as i32
+    }
+
+    /// Quantised residual indices of `signal` and the samples they decode to
+    pub fn compute_residuals(&self, history: &[i32], signal: &[i32], qlp: &QuantizedCoeffs) -> (Vec<i32>, Vec<i32>) {
+        let mut buffer = Vec::with_capacity(history.len() + signal.len());
+        buffer.extend_from_slice(history);
+        let mut indices = Vec::with_capacity(signal.len());
+        for &x in signal {
+            let prediction = qlp.predict(&buffer, buffer.len());
+            let index = self.quantize(x as i64 - prediction as i64) as i32;
+            indices.push(index);
+            buffer.push(self.reconstruct(prediction, index));
+        }
+        (indices, buffer.split_off(history.len()))
+    }
+
+    /// Inverse of `compute_residuals`
+    pub fn restore_signal(&self, history: &[i32], indices: &[i32], qlp: &QuantizedCoeffs) -> Vec<i32> {
+        let mut buffer = Vec::with_capacity(history.len() + indices.len());
+        buffer.extend_from_slice(history);
+        for &index in indices {
+            let prediction = qlp.predict(&buffer, buffer.len());
+            buffer.push(self.reconstruct(prediction, index));
+        }
+        buffer.split_off(history.len())
+    }
+}
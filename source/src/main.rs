@@ -1,18 +1,19 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{Parser, Subcommand};
 use console::style;
 use hound::{WavReader, WavWriter};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressBar;
 use std::fs::File;
-use std::io::{Read, Write, BufReader, BufWriter, Seek};
+use std::io::{BufReader, BufWriter, Seek};
 use std::path::PathBuf;
-use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 
-mod lpc;
-mod coder;
-mod spike;
-mod sparse;
-mod simd_ops;
+use neuralink_compressor::hybrid::Background;
+use neuralink_compressor::realtime;
+use neuralink_compressor::spike::{NoiseEstimator, Polarity, SpikeCompressor};
+use neuralink_compressor::{encode_recording, Codebook, Config, ResidualCoder, Decoder, Encoder, Mode, StreamInfo};
+
+/// Samples handed to the encoder per call; bounds memory independently of file length.
+const READ_CHUNK: usize = 4096;
 
 #[derive(Parser)]
 #[command(name = "neuralink_compressor")]
@@ -33,179 +34,305 @@ enum Commands {
         block_size: usize,
         #[arg(short, long, value_enum, default_value_t = Mode::Events)]
         mode: Mode,
+        /// Residual entropy coder for lossless mode
+        #[arg(long, value_enum, default_value_t = ResidualCoder::Partitioned)]
+        coder: ResidualCoder,
+        /// Per block, try every LPC order up to --order and the fixed predictors
+        #[arg(long)]
+        order_search: bool,
+        /// Frames between blocks that do not predict from the previous block
+        /// (0: only the first, 1: every block, disabling warm-up)
+        #[arg(long, default_value_t = 64)]
+        seek_interval: u32,
+        /// Sync word and CRC-32 per block, so damaged blocks can be skipped on decode
+        #[arg(long)]
+        block_crc: bool,
         #[arg(long, default_value_t = 6.0)]
         threshold: f32,
+        /// Snippet length of the built-in codebook (ignored with --codebook)
+        #[arg(long, default_value_t = 16)]
+        snippet_len: usize,
+        /// Events detail: 0 index only, 1 +gain, 2 +4-bit residual, 3 +8-bit residual
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=3))]
+        quality: u8,
+        /// Events mode: Reed–Solomon check bytes per 255-byte packet; each packet
+        /// survives up to half this many corrupted bytes
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=128))]
+        parity: u8,
+        /// Events mode: send a self-contained packet every this many milliseconds,
+        /// with a running noise threshold, for real-time telemetry
+        #[arg(long)]
+        packet_ms: Option<f64>,
+        /// Events mode: noise estimate behind the threshold (MAD ignores the spikes)
+        #[arg(long, value_enum, default_value_t = NoiseEstimator::Rms)]
+        noise: NoiseEstimator,
+        /// Samples per window for windowed-mad and the real-time noise estimate
+        #[arg(long, default_value_t = 16_384, value_parser = clap::value_parser!(u32).range(1..))]
+        noise_window: u32,
+        /// Events mode: high-pass the signal at this many Hz before detection
+        /// (e.g. 300 to remove LFP and drift); the lossless modes are unaffected
+        #[arg(long, default_value_t = 0)]
+        low_cut: u16,
+        /// Events mode: low-pass at this many Hz before detection (e.g. 6000)
+        #[arg(long, default_value_t = 0)]
+        high_cut: u16,
+        /// Events mode: spike polarity to detect
+        #[arg(long, value_enum, default_value_t = Polarity::Bipolar)]
+        polarity: Polarity,
+        /// Events mode: snippet samples before the peak (default: half the snippet)
+        #[arg(long)]
+        pre_samples: Option<usize>,
+        /// Events mode: samples after a peak in which no new spike is detected
+        /// (default: the snippet length)
+        #[arg(long)]
+        refractory: Option<usize>,
+        /// Hybrid mode: what to keep between the lossless spike windows
+        #[arg(long, value_enum, default_value_t = Background::Decimated)]
+        background: Background,
+        /// Hybrid mode: samples per background segment (decimation factor)
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u16).range(1..))]
+        decimation: u16,
+        /// Near-lossless mode: largest allowed error per sample, in LSBs
+        #[arg(long, default_value_t = 0)]
+        max_error: u16,
+        /// Per block, also try predicting each channel from a neighbouring one
+        /// (its decoded samples or its LPC residual)
+        #[arg(long)]
+        inter_channel: bool,
+        /// Trained codebook for events mode (from `train-codebook`)
+        #[arg(long)]
+        codebook: Option<PathBuf>,
     },
     Decode {
         input: PathBuf,
         output: PathBuf,
+        /// Start of the window to decode, in seconds
+        #[arg(long)]
+        start: Option<f64>,
+        /// End of the window to decode, in seconds
+        #[arg(long)]
+        end: Option<f64>,
+        /// Codebook the events stream was encoded with, if not the built-in one
+        #[arg(long)]
+        codebook: Option<PathBuf>,
+    },
+    /// Train a spike VQ codebook on snippets detected in a corpus of WAV files
+    TrainCodebook {
+        output: PathBuf,
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        #[arg(long, default_value_t = 256)]
+        size: usize,
+        #[arg(long, default_value_t = 20)]
+        iterations: usize,
+        #[arg(long, default_value_t = 6.0)]
+        threshold: f32,
+        /// Band-pass as the encoder will (see `encode --low-cut/--high-cut`)
+        #[arg(long, default_value_t = 0)]
+        low_cut: u16,
+        #[arg(long, default_value_t = 0)]
+        high_cut: u16,
+        /// Detection settings as the encoder will use them
+        #[arg(long, value_enum, default_value_t = NoiseEstimator::Rms)]
+        noise: NoiseEstimator,
+        #[arg(long, default_value_t = 16_384, value_parser = clap::value_parser!(u32).range(1..))]
+        noise_window: u32,
+        #[arg(long, value_enum, default_value_t = Polarity::Bipolar)]
+        polarity: Polarity,
+        #[arg(long)]
+        pre_samples: Option<usize>,
+        #[arg(long)]
+        refractory: Option<usize>,
     },
-}
-
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
-enum Mode {
-    Lossless, // Proven LPC+Rice
-    Events,   // 2500x VQ
-    Elite,    // LPC + Sparse + rANS (Research Breakthroughs)
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.command {
-        Commands::Encode { input, output, order, block_size, mode, threshold } => {
-            encode(&input, &output, order, block_size, mode, threshold)?;
+        Commands::Encode { input, output, order, block_size, mode, coder, order_search, seek_interval, block_crc, threshold, snippet_len, quality, parity, packet_ms, noise, noise_window, low_cut, high_cut, polarity, pre_samples, refractory, background, decimation, max_error, inter_channel, codebook } => {
+            let codebook = codebook.as_ref().map(load_codebook).transpose()?;
+            let config = Config::default()
+                .with_mode(mode)
+                .with_order(order)
+                .with_block_size(block_size)
+                .with_threshold(threshold)
+                .with_snippet_len(snippet_len)
+                .with_quality(quality)
+                .with_residual_coder(coder)
+                .with_order_search(order_search)
+                .with_seek_interval(seek_interval)
+                .with_block_crc(block_crc)
+                .with_parity(parity)
+                .with_noise(noise, noise_window as usize)
+                .with_band_pass(low_cut, high_cut)
+                .with_polarity(polarity)
+                .with_pre_samples(pre_samples)
+                .with_refractory(refractory)
+                .with_background(background)
+                .with_decimation(decimation)
+                .with_max_error(max_error)
+                .with_inter_channel(inter_channel);
+            encode(&input, &output, config, packet_ms, codebook)?;
+        }
+        Commands::Decode { input, output, start, end, codebook } => {
+            let codebook = codebook.as_ref().map(load_codebook).transpose()?;
+            decode(&input, &output, start, end, codebook)?;
         }
-        Commands::Decode { input, output } => {
-            decode(&input, &output)?;
+        Commands::TrainCodebook { output, inputs, size, iterations, threshold, low_cut, high_cut, noise, noise_window, polarity, pre_samples, refractory } => {
+            let detector = |sample_rate| {
+                let mut detector = SpikeCompressor::new(threshold)
+                    .with_noise(noise, noise_window as usize)
+                    .with_polarity(polarity)
+                    .with_band_pass(low_cut, high_cut, sample_rate)?;
+                if let Some(pre) = pre_samples {
+                    detector = detector.with_pre_samples(pre)?;
+                }
+                if let Some(refractory) = refractory {
+                    detector = detector.with_refractory(refractory);
+                }
+                Ok(detector)
+            };
+            train_codebook(&inputs, &output, size, iterations, detector)?;
         }
     }
     Ok(())
 }
 
-fn encode(input: &PathBuf, output: &PathBuf, order: usize, block_size: usize, mode: Mode, threshold: f32) -> Result<()> {
+fn load_codebook(path: &PathBuf) -> Result<Codebook> {
+    let mut file = BufReader::new(File::open(path).context("Failed to open codebook")?);
+    Codebook::read(&mut file)
+}
+
+fn encode(
+    input: &PathBuf,
+    output: &PathBuf,
+    mut config: Config,
+    packet_ms: Option<f64>,
+    codebook: Option<Codebook>,
+) -> Result<()> {
     println!("{}", style("Initiating Ouroboros Elite Compression Protocol...").magenta().bold());
     let mut reader = WavReader::open(input).context("Failed to open WAV file")?;
     let spec = reader.spec();
-    let samples: Vec<i32> = reader.samples::<i32>().map(|s| s.unwrap_or(0)).collect();
-    let mut out_file = BufWriter::new(File::create(output)?);
-    out_file.write_all(b"NEUR")?; 
-
-    match mode {
-        Mode::Lossless => {
-            out_file.write_u32::<BigEndian>(1)?; 
-            out_file.write_u32::<BigEndian>(spec.sample_rate)?;
-            out_file.write_u16::<BigEndian>(spec.channels)?;
-            out_file.write_u16::<BigEndian>(spec.bits_per_sample)?;
-            out_file.write_u64::<BigEndian>(samples.len() as u64)?;
-            encode_lossless_rice(&samples, &mut out_file, order, block_size)?;
-        }
-        Mode::Events => {
-            out_file.write_u32::<BigEndian>(4)?;
-            out_file.write_u32::<BigEndian>(spec.sample_rate)?;
-            out_file.write_u16::<BigEndian>(spec.channels)?;
-            out_file.write_u16::<BigEndian>(spec.bits_per_sample)?;
-            out_file.write_u64::<BigEndian>(samples.len() as u64)?;
-            let compressor = spike::SpikeCompressor::new(threshold);
-            let data = compressor.encode(&samples)?;
-            out_file.write_all(&data)?;
+    if let Some(ms) = packet_ms {
+        config.packet_len = ((ms * spec.sample_rate as f64 / 1000.0).round() as usize).max(1);
+        let snippet_len = codebook.as_ref().map_or(config.snippet_len, |c| c.snippet_len);
+        let pre_samples = config.pre_samples.unwrap_or(snippet_len / 2);
+        let latency = realtime::worst_case_latency(config.packet_len, snippet_len, pre_samples);
+        println!(
+            "Packets of {} samples, worst-case event latency {:.2} ms",
+            config.packet_len,
+            latency as f64 * 1000.0 / spec.sample_rate as f64
+        );
+    }
+    let info = StreamInfo {
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        bits_per_sample: spec.bits_per_sample,
+        total_samples: reader.len() as u64,
+    };
+    let out_file = BufWriter::new(File::create(output)?);
+    let mut out_file = if config.streams() {
+        let mut encoder = Encoder::new(out_file, info, config)?;
+        if let Some(codebook) = codebook {
+            encoder = encoder.with_codebook(codebook);
         }
-        Mode::Elite => {
-            out_file.write_u32::<BigEndian>(5)?; // Ver 5 (Breakthrough Stack)
-            out_file.write_u32::<BigEndian>(spec.sample_rate)?;
-            out_file.write_u16::<BigEndian>(spec.channels)?;
-            out_file.write_u16::<BigEndian>(spec.bits_per_sample)?;
-            out_file.write_u64::<BigEndian>(samples.len() as u64)?;
-            encode_elite(&samples, &mut out_file, order, block_size)?;
+        let pb = ProgressBar::new(info.total_samples);
+        let mut chunk = Vec::with_capacity(READ_CHUNK);
+        for sample in reader.samples::<i32>() {
+            chunk.push(sample.context("Failed to read WAV samples")?);
+            if chunk.len() == READ_CHUNK {
+                encoder.write_samples(&chunk)?;
+                pb.inc(chunk.len() as u64);
+                chunk.clear();
+            }
         }
-    }
-
+        encoder.write_samples(&chunk)?;
+        pb.finish_and_clear();
+        encoder.finish()?
+    } else {
+        // Spike detection needs the whole recording's noise level
+        let samples: Vec<i32> = reader.samples::<i32>().collect::<Result<_, _>>().context("Failed to read WAV samples")?;
+        encode_recording(out_file, info, config, codebook, &samples)?
+    };
     let compressed_size = out_file.stream_position()?;
-    let ratio = (samples.len() * 2) as f64 / compressed_size as f64;
+    let ratio = (info.total_samples * 2) as f64 / compressed_size as f64;
     println!("Final Ratio: {:.2}x", ratio);
     Ok(())
 }
 
-fn encode_elite<W: Write>(samples: &[i32], out_file: &mut W, order: usize, block_size: usize) -> Result<()> {
-    let pb = ProgressBar::new(samples.len() as u64);
-    for chunk in samples.chunks(block_size) {
-        // 1. Predictive (LPC)
-        let autocorr = lpc::autocorrelation(chunk, order);
-        let coeffs = lpc::levinson_durbin(&autocorr, order);
-        let residuals = lpc::compute_residuals(chunk, &coeffs);
-        
-        // 2. Sparse (CSR)
-        let sparse_data = sparse::SparseEncoder::encode(&residuals);
-        
-        // 3. SIMD / Serialization
-        let serialized = simd_ops::SimdOps::serialize(&sparse_data);
-        
-        // 4. rANS Entropy Coding
-        let compressed = coder::RansCoder::encode(&serialized)?;
-
-        out_file.write_u32::<BigEndian>(chunk.len() as u32)?;
-        out_file.write_u8(order as u8)?;
-        for &c in &coeffs { out_file.write_f64::<BigEndian>(c)?; }
-        out_file.write_u32::<BigEndian>(compressed.len() as u32)?;
-        out_file.write_all(&compressed)?;
-        pb.inc(chunk.len() as u64);
+fn decode(
+    input: &PathBuf,
+    output: &PathBuf,
+    start: Option<f64>,
+    end: Option<f64>,
+    codebook: Option<Codebook>,
+) -> Result<()> {
+    println!("{}", style("Initiating Elite Decompression...").green().bold());
+    let mut decoder = Decoder::new(BufReader::new(File::open(input)?))?;
+    if let Some(codebook) = codebook {
+        decoder = decoder.with_codebook(codebook);
     }
-    pb.finish_and_clear();
-    Ok(())
-}
-
-fn encode_lossless_rice<W: Write>(samples: &[i32], out_file: &mut W, order: usize, block_size: usize) -> Result<()> {
-    for chunk in samples.chunks(block_size) {
-        let autocorr = lpc::autocorrelation(chunk, order);
-        let coeffs = lpc::levinson_durbin(&autocorr, order);
-        let residuals = lpc::compute_residuals(chunk, &coeffs);
-        let mean_abs: f64 = residuals.iter().map(|x| x.abs() as f64).sum::<f64>() / residuals.len() as f64;
-        let k = (mean_abs.log2().max(0.0) as u32).min(15);
-        let encoded_data = coder::encode_rice(&residuals, k)?;
-        out_file.write_u32::<BigEndian>(chunk.len() as u32)?;
-        out_file.write_u8(order as u8)?;
-        out_file.write_u8(k as u8)?;
-        for &c in &coeffs { out_file.write_f64::<BigEndian>(c)?; }
-        out_file.write_u32::<BigEndian>(encoded_data.len() as u32)?;
-        out_file.write_all(&encoded_data)?;
+    let info = *decoder.info();
+    if decoder.header().mode == Mode::NearLossless {
+        println!("Near-lossless stream: every sample within ±{} of the original", decoder.header().max_error);
+    }
+    let spec = hound::WavSpec {
+        channels: info.channels,
+        sample_rate: info.sample_rate,
+        bits_per_sample: info.bits_per_sample,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = WavWriter::create(output, spec)?;
+    if start.is_some() || end.is_some() {
+        let to_sample = |seconds: f64| (seconds.max(0.0) * info.sample_rate as f64).round() as u64;
+        let start = start.map_or(0, to_sample);
+        let end = end.map_or(u64::MAX, to_sample);
+        for sample in decoder.read_range(start, end)? { writer.write_sample(sample as i16)?; }
+    } else {
+        while let Some(block) = decoder.read_block()? {
+            for sample in block { writer.write_sample(sample as i16)?; }
+        }
+    }
+    writer.finalize()?;
+    for damage in decoder.damaged() {
+        eprintln!(
+            "{} channel {} samples {}..{} were corrupt and have been zero-filled",
+            style("Warning:").yellow().bold(),
+            damage.channel,
+            damage.start,
+            damage.end
+        );
     }
     Ok(())
 }
 
-fn decode(input: &PathBuf, output: &PathBuf) -> Result<()> {
-    println!("{}", style("Initiating Elite Decompression...").green().bold());
-    let mut in_file = BufReader::new(File::open(input)?);
-    let mut magic = [0u8; 4];
-    in_file.read_exact(&mut magic)?;
-    let version = in_file.read_u32::<BigEndian>()?;
-    let sample_rate = in_file.read_u32::<BigEndian>()?;
-    let channels = in_file.read_u16::<BigEndian>()?;
-    let bits_per_sample = in_file.read_u16::<BigEndian>()?;
-    let total_samples = in_file.read_u64::<BigEndian>()?;
-
-    let spec = hound::WavSpec { channels, sample_rate, bits_per_sample, sample_format: hound::SampleFormat::Int };
-    let mut writer = WavWriter::create(output, spec)?;
-    
-    if version == 5 {
-        let mut samples_read = 0;
-        while samples_read < total_samples {
-            let block_size = in_file.read_u32::<BigEndian>()? as usize;
-            let order = in_file.read_u8()? as usize;
-            let mut coeffs = Vec::with_capacity(order);
-            for _ in 0..order { coeffs.push(in_file.read_f64::<BigEndian>()?); }
-            let data_len = in_file.read_u32::<BigEndian>()? as usize;
-            let mut compressed = vec![0u8; data_len];
-            in_file.read_exact(&mut compressed)?;
-            
-            let serialized = coder::RansCoder::decode(&compressed, 0)?; // Count is embedded
-            let sparse_data = simd_ops::SimdOps::deserialize(&serialized);
-            let residuals = sparse::SparseEncoder::decode(&sparse_data);
-            let signal = lpc::restore_signal(&residuals, &coeffs);
-            for sample in signal { writer.write_sample(sample as i16)?; }
-            samples_read += block_size as u64;
+fn train_codebook(
+    inputs: &[PathBuf],
+    output: &PathBuf,
+    size: usize,
+    iterations: usize,
+    detector: impl Fn(u32) -> Result<SpikeCompressor>,
+) -> Result<()> {
+    println!("{}", style("Training Spike Codebook...").cyan().bold());
+    let mut snippets = Vec::new();
+    for input in inputs {
+        let mut reader = WavReader::open(input).with_context(|| format!("Failed to open {:?}", input))?;
+        let detector = detector(reader.spec().sample_rate)?;
+        let channels = reader.spec().channels.max(1) as usize;
+        let samples: Vec<i32> = reader.samples::<i32>().collect::<Result<_, _>>().context("Failed to read WAV samples")?;
+        for ch in 0..channels {
+            let channel: Vec<i32> = samples.iter().skip(ch).step_by(channels).copied().collect();
+            snippets.extend(detector.snippets(&channel));
         }
-    } else if version == 1 {
-        // ... Re-implement Rice Decode ...
-        let mut samples_read = 0;
-        while samples_read < total_samples {
-            let block_size = in_file.read_u32::<BigEndian>()? as usize;
-            let order = in_file.read_u8()? as usize;
-            let k = in_file.read_u8()? as u32;
-            let mut coeffs = Vec::with_capacity(order);
-            for _ in 0..order { coeffs.push(in_file.read_f64::<BigEndian>()?); }
-            let data_len = in_file.read_u32::<BigEndian>()? as usize;
-            let mut encoded_data = vec![0u8; data_len];
-            in_file.read_exact(&mut encoded_data)?;
-            let residuals = coder::decode_rice(&encoded_data, block_size, k)?;
-            let signal = lpc::restore_signal(&residuals, &coeffs);
-            for sample in signal { writer.write_sample(sample as i16)?; }
-            samples_read += block_size as u64;
-        }
-    } else if version == 4 {
-        let mut data = Vec::new();
-        in_file.read_to_end(&mut data)?;
-        let compressor = spike::SpikeCompressor::new(4.0);
-        let signal = compressor.decode(&data, total_samples as usize)?;
-        for sample in signal { writer.write_sample(sample as i16)?; }
     }
+    let codebook = Codebook::train(&snippets, size, iterations)?;
+    let mut out_file = BufWriter::new(File::create(output)?);
+    codebook.write(&mut out_file)?;
+    println!(
+        "{} templates from {} snippets, id {:08x}",
+        codebook.templates.len(),
+        snippets.len(),
+        codebook.id()
+    );
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,314 @@
+//! Packetised events for real-time telemetry.
+//!
+//! Samples arrive in frames of `packet_len` samples (e.g. 1 ms) and every frame
+//! yields one packet that decodes on its own, given the session's `EventParams`:
+//!
+//! | Size | Field                                                          |
+//! |------|----------------------------------------------------------------|
+//! | 4    | sequence number (per channel, from 0)                          |
+//! | 2    | channel                                                        |
+//! | 8    | timestamp base: index of the frame's first sample              |
+//! | 2    | samples in the frame (0 for the closing packet)                |
+//! | 4    | running noise level at the end of the frame (f32)              |
+//! | 2    | event count                                                    |
+//! | ...  | per event: peak offset from the base (i32), then the event     |
+//! |      | as stored by `SpikeCompressor` (index, gain, residual)         |
+//! | 4    | CRC-32 of all preceding packet bytes                           |
+//!
+//! With parity set, the packet is then protected with `fec`.
+//!
+//! Thresholds use a running noise estimate rather than the whole recording: an
+//! exponential average of x² for `NoiseEstimator::Rms`, otherwise a running
+//! median of |x| whose step scales with the estimate, so both adapt over about a
+//! noise window.
+//! An event is sent in the packet of the frame where its snippet completes, so it
+//! reaches the receiver at most `worst_case_latency` samples after its threshold
+//! crossing (plus transport). Detection, peak alignment and the refractory period
+//! match `SpikeCompressor`.
+
+use anyhow::Result;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::VecDeque;
+use std::io::{Cursor, Read};
+
+use crate::fec;
+use crate::filter::BandPass;
+use crate::spike::{median_magnitude, Detector, NoiseEstimator, SpikeCompressor, MAD_SCALE};
+
+/// Samples between a threshold crossing and its packet leaving the encoder, worst
+/// case: the peak is found at the end of the search window (the snippet's
+/// post-peak length), and the sample completing its snippet opens a frame that
+/// must then fill up.
+pub fn worst_case_latency(packet_len: usize, snippet_len: usize, align_offset: usize) -> usize {
+    let post = snippet_len.saturating_sub(align_offset).max(1);
+    packet_len + post.max(2 * post - 2)
+}
+
+/// Online spike encoder for one channel
+pub struct PacketEncoder {
+    compressor: SpikeCompressor,
+    channel: u16,
+    packet_len: usize,
+    noise: NoiseEstimator,
+    noise_window: usize,
+    /// Prefilter state, carried across frames
+    band_pass: Option<BandPass>,
+    snippet_len: usize,
+    align_offset: usize,
+    threshold_multiplier: f64,
+    parity: u8,
+    /// Running mean square (RMS) or median |x| (MAD); `None` until the first
+    /// frame seeds it
+    level: Option<f64>,
+    sequence: u32,
+    /// Index of the next sample
+    position: u64,
+    /// Recent samples: a snippet plus the peak search window
+    recent: VecDeque<i32>,
+    recent_len: usize,
+    detector: Detector,
+    /// Peaks whose snippets are still incomplete
+    open: VecDeque<u64>,
+}
+
+impl PacketEncoder {
+    pub fn new(compressor: SpikeCompressor, channel: u16, packet_len: usize) -> Self {
+        let params = compressor.params();
+        Self {
+            channel,
+            packet_len: packet_len.clamp(1, u16::MAX as usize),
+            noise: params.noise,
+            noise_window: params.noise_window.max(1) as usize,
+            band_pass: compressor.band_pass(),
+            snippet_len: params.snippet_len as usize,
+            align_offset: params.align_offset as usize,
+            threshold_multiplier: params.threshold_multiplier as f64,
+            parity: params.parity,
+            level: None,
+            sequence: 0,
+            position: 0,
+            recent: VecDeque::new(),
+            recent_len: 2 * params.snippet_len as usize - params.align_offset as usize,
+            detector: compressor.detector(),
+            open: VecDeque::new(),
+            compressor,
+        }
+    }
+
+    pub fn with_noise_window(mut self, samples: usize) -> Self {
+        self.noise_window = samples.max(1);
+        self
+    }
+
+    /// See `worst_case_latency`
+    pub fn latency(&self) -> usize {
+        worst_case_latency(self.packet_len, self.snippet_len, self.align_offset)
+    }
+
+    /// Encode the next frame (at most `packet_len` samples) into one packet.
+    pub fn push(&mut self, frame: &[i32]) -> Result<Vec<u8>> {
+        if frame.len() > self.packet_len {
+            anyhow::bail!("Frame of {} samples exceeds packet length {}", frame.len(), self.packet_len);
+        }
+        let base = self.position;
+        let filtered;
+        let frame = match &mut self.band_pass {
+            Some(filter) => {
+                filtered = filter.apply(frame);
+                &filtered[..]
+            }
+            None => frame,
+        };
+        // The first frame seeds the estimate, so the opening threshold is not zero.
+        let mut level = match self.level {
+            Some(level) => level,
+            None if self.noise == NoiseEstimator::Rms => {
+                frame.iter().map(|&x| (x as f64).powi(2)).sum::<f64>() / frame.len().max(1) as f64
+            }
+            None => median_magnitude(frame),
+        };
+        let mut events = Vec::new();
+        for &x in frame {
+            let t = self.position;
+            if self.recent.len() == self.recent_len {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(x);
+            let threshold = self.sigma(level) * self.threshold_multiplier;
+            self.open.extend(self.detector.push(t, x, threshold));
+            level = self.update(level, x);
+            self.position += 1;
+            while let Some(&peak) = self.open.front() {
+                if self.snippet_start(peak) + self.snippet_len as u64 > self.position {
+                    break;
+                }
+                self.open.pop_front();
+                events.push((peak, self.snippet(peak)?));
+            }
+        }
+        self.level = Some(level);
+        self.packet(base, frame.len(), &events)
+    }
+
+    /// Noise standard deviation for a running `level`
+    fn sigma(&self, level: f64) -> f64 {
+        match self.noise {
+            NoiseEstimator::Rms => level.sqrt(),
+            NoiseEstimator::Mad | NoiseEstimator::WindowedMad => level / MAD_SCALE,
+        }
+    }
+
+    fn update(&self, level: f64, x: i32) -> f64 {
+        let window = self.noise_window as f64;
+        match self.noise {
+            NoiseEstimator::Rms => level + ((x as f64).powi(2) - level) / window,
+            NoiseEstimator::Mad | NoiseEstimator::WindowedMad => {
+                let step = level.max(1.0) / window;
+                let magnitude = x.unsigned_abs() as f64;
+                if magnitude > level {
+                    level + step
+                } else if magnitude < level {
+                    (level - step).max(0.0)
+                } else {
+                    level
+                }
+            }
+        }
+    }
+
+    /// Closing packet for snippets still open at the end of the recording, padded
+    /// with their last sample; `None` if there are none.
+    pub fn flush(&mut self) -> Result<Option<Vec<u8>>> {
+        self.open.extend(self.detector.finish());
+        if self.open.is_empty() {
+            return Ok(None);
+        }
+        let events = std::mem::take(&mut self.open)
+            .into_iter()
+            .map(|peak| Ok((peak, self.snippet(peak)?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.packet(self.position, 0, &events).map(Some)
+    }
+
+    fn snippet_start(&self, peak: u64) -> u64 {
+        peak.saturating_sub(self.align_offset as u64)
+    }
+
+    /// Snippet for `peak` from the recent samples, repeating the newest sample
+    /// past the end of the data seen so far. Fails if the snippet starts before
+    /// the oldest sample still held.
+    fn snippet(&self, peak: u64) -> Result<Vec<i16>> {
+        let oldest = self.position - self.recent.len() as u64;
+        let start = self.snippet_start(peak).checked_sub(oldest).ok_or_else(|| {
+            anyhow::anyhow!("Snippet for event at {} starts before the oldest held sample {}", peak, oldest)
+        })? as usize;
+        let last = self.recent.back().copied().unwrap_or(0);
+        Ok((0..self.snippet_len)
+            .map(|j| self.recent.get(start + j).copied().unwrap_or(last) as i16)
+            .collect())
+    }
+
+    fn packet(&mut self, base: u64, frame_len: usize, events: &[(u64, Vec<i16>)]) -> Result<Vec<u8>> {
+        if events.len() > u16::MAX as usize {
+            anyhow::bail!("Too many events for one packet ({})", events.len());
+        }
+        let mut packet = Vec::new();
+        packet.write_u32::<BigEndian>(self.sequence)?;
+        packet.write_u16::<BigEndian>(self.channel)?;
+        packet.write_u64::<BigEndian>(base)?;
+        packet.write_u16::<BigEndian>(frame_len as u16)?;
+        packet.write_f32::<BigEndian>(self.sigma(self.level.unwrap_or(0.0)) as f32)?;
+        packet.write_u16::<BigEndian>(events.len() as u16)?;
+        for (peak, snippet) in events {
+            packet.write_i32::<BigEndian>((*peak as i64 - base as i64) as i32)?;
+            self.compressor.write_event(snippet, &mut packet)?;
+        }
+        let crc = crc32fast::hash(&packet);
+        packet.write_u32::<BigEndian>(crc)?;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(fec::encode(&packet, self.parity))
+    }
+}
+
+/// One spike recovered from a packet
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketEvent {
+    /// Sample index of the spike's peak
+    pub timestamp: u64,
+    /// Sample index of the snippet's first sample
+    pub start: u64,
+    pub snippet: Vec<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedPacket {
+    pub sequence: u32,
+    pub channel: u16,
+    pub base: u64,
+    pub frame_len: usize,
+    /// Running noise standard deviation estimate
+    pub noise: f32,
+    pub events: Vec<PacketEvent>,
+}
+
+impl DecodedPacket {
+    /// Write the event snippets into `output`, a channel indexed from sample 0.
+    pub fn render(&self, output: &mut [i32]) {
+        for event in &self.events {
+            for (j, &v) in event.snippet.iter().enumerate() {
+                if let Some(slot) = output.get_mut(event.start as usize + j) {
+                    *slot = v.clamp(i16::MIN as i32, i16::MAX as i32);
+                }
+            }
+        }
+    }
+}
+
+/// Receiver side: decodes packets independently of each other
+pub struct PacketDecoder {
+    compressor: SpikeCompressor,
+    align_offset: u64,
+    parity: u8,
+}
+
+impl PacketDecoder {
+    /// `compressor` must be rebuilt from the session's `EventParams`
+    pub fn new(compressor: SpikeCompressor) -> Self {
+        let params = compressor.params();
+        Self { align_offset: params.align_offset as u64, parity: params.parity, compressor }
+    }
+
+    pub fn decode(&self, packet: &[u8]) -> Result<DecodedPacket> {
+        let (packet, _corrected) = fec::decode(packet, self.parity)?;
+        if packet.len() < 4 {
+            anyhow::bail!("Packet too short ({} bytes)", packet.len());
+        }
+        let (body, crc) = packet.split_at(packet.len() - 4);
+        if crc32fast::hash(body).to_be_bytes() != crc {
+            anyhow::bail!("Packet CRC-32 mismatch");
+        }
+        let mut cursor = Cursor::new(body);
+        let sequence = cursor.read_u32::<BigEndian>()?;
+        let channel = cursor.read_u16::<BigEndian>()?;
+        let base = cursor.read_u64::<BigEndian>()?;
+        let frame_len = cursor.read_u16::<BigEndian>()? as usize;
+        let noise = cursor.read_f32::<BigEndian>()?;
+        let count = cursor.read_u16::<BigEndian>()?;
+        let mut events = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let offset = cursor.read_i32::<BigEndian>()? as i64;
+            let Ok(timestamp) = u64::try_from(base as i64 + offset) else {
+                anyhow::bail!("Event before the start of the recording");
+            };
+            let snippet = self.compressor.read_event(&mut cursor)?;
+            let start = timestamp.saturating_sub(self.align_offset);
+            events.push(PacketEvent { timestamp, start, snippet });
+        }
+        let mut trailing = Vec::new();
+        cursor.read_to_end(&mut trailing)?;
+        if !trailing.is_empty() {
+            anyhow::bail!("{} unexpected bytes at the end of the packet", trailing.len());
+        }
+        Ok(DecodedPacket { sequence, channel, base, frame_len, noise, events })
+    }
+}
@@ -1,11 +1,17 @@
+use anyhow::Result;
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 use std::io::Cursor;
+use crate::coder::{M, M_BITS, RANS_LANES, RANS_WORD_L};
 use crate::sparse::SparseData;
 
+const ESCAPE: u16 = 0xFFFF;
+
 /// SIMD-ready serialization logic for Sparse Data
 pub struct SimdOps;
 
 impl SimdOps {
+    /// Values that do not fit 16 bits are written as `0xFFFF` followed by the
+    /// full zigzag value as a `u32`.
     pub fn serialize(data: &SparseData) -> Vec<u8> {
         let mut out = Vec::new();
         out.write_u32::<BigEndian>(data.original_len).unwrap();
@@ -13,8 +19,14 @@ impl SimdOps {
         
         for &v in &data.values {
             // ZigZag encode values for smaller representation
-            let u = if v >= 0 { (v as u32) << 1 } else { ((v.abs() as u32) << 1) - 1 };
-            out.write_u16::<BigEndian>(u as i16 as u16).unwrap();
+            let u = if v >= 0 { (v as u32) << 1 } else { (v.unsigned_abs() << 1) - 1 };
+            // Residuals of 16-bit input need up to 17 bits; 0xFFFF escapes to a full u32.
+            if u < ESCAPE as u32 {
+                out.write_u16::<BigEndian>(u as u16).unwrap();
+            } else {
+                out.write_u16::<BigEndian>(ESCAPE).unwrap();
+                out.write_u32::<BigEndian>(u).unwrap();
+            }
         }
         
         for &idx in &data.indices {
@@ -30,8 +42,9 @@ impl SimdOps {
         
         let mut values = Vec::with_capacity(count);
         for _ in 0..count {
-            let u = cursor.read_u16::<BigEndian>().unwrap() as u32;
-            let v = if u % 2 == 0 { (u >> 1) as i32 } else { -(((u + 1) >> 1) as i32) };
+            let mut u = cursor.read_u16::<BigEndian>().unwrap() as u32;
+            if u == ESCAPE as u32 { u = cursor.read_u32::<BigEndian>().unwrap(); }
+            let v = if u.is_multiple_of(2) { (u >> 1) as i32 } else { -(((u + 1) >> 1) as i32) };
             values.push(v);
         }
         
@@ -43,3 +56,108 @@ impl SimdOps {
         SparseData { values, indices, original_len }
     }
 }
+
+/// Decoder state of an interleaved rANS payload (`RansCoder::encode_interleaved`)
+pub(crate) struct RansLanes<'a> {
+    pub states: [u32; RANS_LANES],
+    pub words: &'a [u16],
+    /// Next renormalisation word
+    pub pos: usize,
+}
+
+/// Fills `out` from `lanes`, given the table's packed slot entries
+pub(crate) type RansKernel = fn(&mut RansLanes, &[u32], &mut [u8]) -> Result<()>;
+
+/// One symbol at a time; symbol `i` of `out` comes from state `i % RANS_LANES`
+pub(crate) fn rans_decode_scalar(lanes: &mut RansLanes, entries: &[u32], out: &mut [u8]) -> Result<()> {
+    for (i, symbol) in out.iter_mut().enumerate() {
+        let state = &mut lanes.states[i % RANS_LANES];
+        let entry = entries[(*state & (M - 1)) as usize];
+        *symbol = (entry >> 24) as u8;
+        *state = ((entry & 0xFFF) + 1) * (*state >> M_BITS) + ((entry >> 12) & 0xFFF);
+        if *state < RANS_WORD_L {
+            let Some(&word) = lanes.words.get(lanes.pos) else {
+                anyhow::bail!("Interleaved rANS payload is truncated");
+            };
+            *state = (*state << 16) | word as u32;
+            lanes.pos += 1;
+        }
+    }
+    Ok(())
+}
+
+/// All lanes per step with AVX2 when available, the scalar loop otherwise and
+/// for the last few symbols
+pub(crate) fn rans_decode(lanes: &mut RansLanes, entries: &[u32], out: &mut [u8]) -> Result<()> {
+    assert_eq!(entries.len(), M as usize, "one entry per rANS slot");
+    #[allow(unused_mut)]
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") {
+        // SAFETY: AVX2 is available, and every slot indexes `entries`
+        done = unsafe { rans_decode_avx2(lanes, entries, out) };
+    }
+    // `done` is a whole number of steps, so symbol `done` is lane 0's again
+    rans_decode_scalar(lanes, entries, &mut out[done..])
+}
+
+/// Permutation spreading consecutive words to the lanes set in a mask: lane `j`
+/// takes the word after those of the set lanes below it
+#[cfg(target_arch = "x86_64")]
+const SPREAD: [[u32; RANS_LANES]; 1 << RANS_LANES] = {
+    let mut table = [[0u32; RANS_LANES]; 1 << RANS_LANES];
+    let mut mask = 0;
+    while mask < 1 << RANS_LANES {
+        let mut lane = 0;
+        while lane < RANS_LANES {
+            table[mask][lane] = (mask & ((1 << lane) - 1)).count_ones();
+            lane += 1;
+        }
+        mask += 1;
+    }
+    table
+};
+
+/// Decodes whole steps of `RANS_LANES` symbols while a full step's worth of
+/// words remains, returning the number of symbols written
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn rans_decode_avx2(lanes: &mut RansLanes, entries: &[u32], out: &mut [u8]) -> usize {
+    use std::arch::x86_64::*;
+
+    let mut x = _mm256_loadu_si256(lanes.states.as_ptr() as *const __m256i);
+    let slot_mask = _mm256_set1_epi32((M - 1) as i32);
+    let field = _mm256_set1_epi32(0xFFF);
+    let one = _mm256_set1_epi32(1);
+    // Byte 3 (the symbol) of each entry into the low 4 bytes of each half
+    let symbols = _mm256_setr_epi8(
+        3, 7, 11, 15, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+        3, 7, 11, 15, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1, -1,
+    );
+    let mut done = 0;
+    for step in out.chunks_exact_mut(RANS_LANES) {
+        if lanes.pos + RANS_LANES > lanes.words.len() {
+            break;
+        }
+        let slot = _mm256_and_si256(x, slot_mask);
+        let entry = _mm256_i32gather_epi32::<4>(entries.as_ptr() as *const i32, slot);
+        let freq = _mm256_add_epi32(_mm256_and_si256(entry, field), one);
+        let bias = _mm256_and_si256(_mm256_srli_epi32::<12>(entry), field);
+        x = _mm256_add_epi32(_mm256_mullo_epi32(freq, _mm256_srli_epi32::<{ M_BITS as i32 }>(x)), bias);
+
+        let packed = _mm256_shuffle_epi8(entry, symbols);
+        step[..4].copy_from_slice(&_mm256_extract_epi32::<0>(packed).to_le_bytes());
+        step[4..].copy_from_slice(&_mm256_extract_epi32::<4>(packed).to_le_bytes());
+
+        // Lanes below 2^16 take the next words, in lane order
+        let low = _mm256_cmpeq_epi32(_mm256_srli_epi32::<16>(x), _mm256_setzero_si256());
+        let mask = _mm256_movemask_ps(_mm256_castsi256_ps(low)) as usize;
+        let next = _mm256_cvtepu16_epi32(_mm_loadu_si128(lanes.words.as_ptr().add(lanes.pos) as *const __m128i));
+        let spread = _mm256_permutevar8x32_epi32(next, _mm256_loadu_si256(SPREAD[mask].as_ptr() as *const __m256i));
+        x = _mm256_blendv_epi8(x, _mm256_or_si256(_mm256_slli_epi32::<16>(x), spread), low);
+        lanes.pos += mask.count_ones() as usize;
+        done += RANS_LANES;
+    }
+    _mm256_storeu_si256(lanes.states.as_mut_ptr() as *mut __m256i, x);
+    done
+}
@@ -1,4 +1,5 @@
-/// Sparse Representation using CSR (Compressed Sparse Row) format
+/// Sparse Representation using CSR (Compressed Sparse Row) format; the residual
+/// layout of Elite streams written without `FLAG_ELITE_RANS`
 pub struct SparseEncoder;
 
 pub struct SparseData {
@@ -1,91 +1,593 @@
 use anyhow::Result;
-use std::io::{Cursor};
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::io::{Cursor, Read, Write};
+use std::ops::Range;
+use bitstream_io::{BigEndian as BitBigEndian, BitRead, BitReader, BitWrite, BitWriter};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::codebook::Codebook;
+use crate::fec;
+use crate::filter::BandPass;
+
+/// Default snippet length for the synthetic codebook
+pub const DEFAULT_SNIPPET_LEN: usize = 16;
+
+/// Default samples per noise window (`NoiseEstimator::WindowedMad`, and the span of
+/// the running estimate in `realtime`)
+pub const DEFAULT_NOISE_WINDOW: usize = 16_384;
+
+/// median(|x|) / MAD_SCALE estimates the standard deviation of Gaussian noise
+pub(crate) const MAD_SCALE: f64 = 0.6745;
+
+/// How the noise level that scales the spike threshold is estimated
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum NoiseEstimator {
+    /// Root mean square of the channel; inflated by the spikes themselves
+    Rms,
+    /// median(|x|) / 0.6745 of the channel, which spikes barely move
+    Mad,
+    /// `Mad` per window of `noise_window` samples, following slow drifts
+    WindowedMad,
+}
+
+impl NoiseEstimator {
+    fn code(self) -> u8 {
+        match self {
+            NoiseEstimator::Rms => 0,
+            NoiseEstimator::Mad => 1,
+            NoiseEstimator::WindowedMad => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0 => Ok(NoiseEstimator::Rms),
+            1 => Ok(NoiseEstimator::Mad),
+            2 => Ok(NoiseEstimator::WindowedMad),
+            _ => anyhow::bail!("Unknown noise estimator {}", code),
+        }
+    }
+}
+
+/// Which threshold crossings count as spikes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Polarity {
+    /// Below `-threshold`; extracellular spikes are mostly negative-going
+    Negative,
+    /// Above `threshold`
+    Positive,
+    /// Either, following whichever side the signal crossed
+    Bipolar,
+}
+
+/// Streaming threshold detector shared by `SpikeCompressor` and `realtime`. After
+/// a crossing it follows the excursion for up to `search` samples and reports the
+/// sample of its extremum; crossings within `refractory` samples of that peak
+/// are ignored.
+#[derive(Clone, Debug)]
+pub(crate) struct Detector {
+    polarity: Polarity,
+    search: u64,
+    refractory: u64,
+    excursion: Option<Excursion>,
+    next_allowed: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Excursion {
+    crossing: u64,
+    peak: u64,
+    /// 1 for a positive excursion, -1 for a negative one
+    sign: i64,
+    /// `sign * x` at the peak
+    height: i64,
+}
+
+impl Detector {
+    fn new(polarity: Polarity, search: usize, refractory: usize) -> Self {
+        Self { polarity, search: search.max(1) as u64, refractory: refractory as u64, excursion: None, next_allowed: 0 }
+    }
+
+    /// Feed sample `x` at index `t`; returns the peak of an excursion that just ended.
+    pub(crate) fn push(&mut self, t: u64, x: i32, threshold: f64) -> Option<u64> {
+        let mut ended = None;
+        if let Some(excursion) = &mut self.excursion {
+            let height = excursion.sign * x as i64;
+            if t < excursion.crossing + self.search && height as f64 > threshold {
+                if height > excursion.height {
+                    excursion.peak = t;
+                    excursion.height = height;
+                }
+                return None;
+            }
+            ended = Some(excursion.peak);
+            self.next_allowed = excursion.peak + self.refractory;
+            self.excursion = None;
+        }
+        if t >= self.next_allowed {
+            let sign = match self.polarity {
+                Polarity::Negative => -1,
+                Polarity::Positive => 1,
+                Polarity::Bipolar => (x as i64).signum(),
+            };
+            let height = sign * x as i64;
+            if height as f64 > threshold {
+                self.excursion = Some(Excursion { crossing: t, peak: t, sign, height });
+            }
+        }
+        ended
+    }
+
+    /// Peak of the excursion still open at the end of the data
+    pub(crate) fn finish(&mut self) -> Option<u64> {
+        self.excursion.take().map(|excursion| excursion.peak)
+    }
+}
+
+/// median(|x|); 0 for no samples
+pub(crate) fn median_magnitude(samples: &[i32]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let mut magnitudes: Vec<u32> = samples.iter().map(|x| x.unsigned_abs()).collect();
+    let mid = magnitudes.len() / 2;
+    *magnitudes.select_nth_unstable(mid).1 as f64
+}
+
+/// median(|x|) / 0.6745, the noise standard deviation with spikes all but ignored
+pub fn mad_noise(samples: &[i32]) -> f64 {
+    median_magnitude(samples) / MAD_SCALE
+}
+
+/// Everything the events decoder needs, written at the head of every events stream
+/// so decoding never depends on command-line flags:
+/// `len u16` (bytes that follow), `threshold f32`, `snippet_len u16`,
+/// `align_offset u16`, `codebook_id u32`, `quality u8`, `parity u8`, `packet_len u16`,
+/// `noise u8`, `noise_window u32`, `low_cut u16`, `high_cut u16`.
+/// Readers skip bytes beyond the known fields; fields after `quality` were appended
+/// later and default to 0 (RMS noise for `noise`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EventParams {
+    pub threshold_multiplier: f32,
+    pub snippet_len: u16,
+    /// Samples between the start of a snippet and its event timestamp
+    pub align_offset: u16,
+    pub codebook_id: u32,
+    /// 0 index only, 1 adds a per-event gain, 2 and 3 add a 4/8-bit residual
+    pub quality: u8,
+    /// Reed–Solomon check bytes per packet of each channel payload (0 = none)
+    pub parity: u8,
+    /// Samples per real-time packet (`realtime`); 0 for one payload per channel
+    pub packet_len: u16,
+    pub noise: NoiseEstimator,
+    /// Samples per noise window; used by `WindowedMad` and the real-time estimate
+    pub noise_window: u32,
+    /// Band-pass applied before detection, in Hz (0 = that edge unfiltered);
+    /// reconstructed snippets are of the filtered signal
+    pub low_cut: u16,
+    pub high_cut: u16,
+}
+
+const EVENT_PARAMS_LEN: u16 = 25;
+/// Shortest events header accepted (fields up to `quality`)
+const EVENT_PARAMS_MIN_LEN: u16 = 13;
+
+/// Reed–Solomon check bytes on the `EventParams` of streams with
+/// `container::FLAG_EVENT_FEC`
+pub const PARAMS_PARITY: u8 = 16;
+
+/// Highest supported `EventParams::quality`
+pub const MAX_QUALITY: u8 = 3;
+
+impl EventParams {
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        out.write_u16::<BigEndian>(EVENT_PARAMS_LEN)?;
+        out.write_f32::<BigEndian>(self.threshold_multiplier)?;
+        out.write_u16::<BigEndian>(self.snippet_len)?;
+        out.write_u16::<BigEndian>(self.align_offset)?;
+        out.write_u32::<BigEndian>(self.codebook_id)?;
+        out.write_u8(self.quality)?;
+        out.write_u8(self.parity)?;
+        out.write_u16::<BigEndian>(self.packet_len)?;
+        out.write_u8(self.noise.code())?;
+        out.write_u32::<BigEndian>(self.noise_window)?;
+        out.write_u16::<BigEndian>(self.low_cut)?;
+        out.write_u16::<BigEndian>(self.high_cut)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let len = input.read_u16::<BigEndian>()?;
+        if len < EVENT_PARAMS_MIN_LEN {
+            anyhow::bail!("Events header too short ({} bytes)", len);
+        }
+        let mut fields = vec![0u8; len as usize];
+        input.read_exact(&mut fields)?;
+        let mut cursor = Cursor::new(fields);
+        Ok(Self {
+            threshold_multiplier: cursor.read_f32::<BigEndian>()?,
+            snippet_len: cursor.read_u16::<BigEndian>()?,
+            align_offset: cursor.read_u16::<BigEndian>()?,
+            codebook_id: cursor.read_u32::<BigEndian>()?,
+            quality: cursor.read_u8()?,
+            parity: if len > EVENT_PARAMS_MIN_LEN { cursor.read_u8()? } else { 0 },
+            packet_len: if len >= EVENT_PARAMS_MIN_LEN + 3 { cursor.read_u16::<BigEndian>()? } else { 0 },
+            noise: if len >= EVENT_PARAMS_MIN_LEN + 4 {
+                NoiseEstimator::from_code(cursor.read_u8()?)?
+            } else {
+                NoiseEstimator::Rms
+            },
+            noise_window: if len >= EVENT_PARAMS_MIN_LEN + 8 { cursor.read_u32::<BigEndian>()? } else { 0 },
+            low_cut: if len >= EVENT_PARAMS_LEN { cursor.read_u16::<BigEndian>()? } else { 0 },
+            high_cut: if len >= EVENT_PARAMS_LEN { cursor.read_u16::<BigEndian>()? } else { 0 },
+        })
+    }
+
+    /// `write` followed by `PARAMS_PARITY` Reed–Solomon check bytes
+    pub fn write_protected<W: Write>(&self, out: &mut W) -> Result<()> {
+        let mut fields = Vec::new();
+        self.write(&mut fields)?;
+        out.write_all(&fec::encode(&fields, PARAMS_PARITY))?;
+        Ok(())
+    }
+
+    /// Inverse of `write_protected`, repairing up to `PARAMS_PARITY / 2` bad bytes
+    pub fn read_protected<R: Read>(input: &mut R) -> Result<Self> {
+        let mut coded = [0u8; 2 + EVENT_PARAMS_LEN as usize + PARAMS_PARITY as usize];
+        input.read_exact(&mut coded)?;
+        let (fields, _corrected) = fec::decode(&coded, PARAMS_PARITY)?;
+        Self::read(&mut fields.as_slice())
+    }
+}
+
 /// Spike Event Coder with Vector Quantization (VQ)
+#[derive(Clone)]
 pub struct SpikeCompressor {
     threshold_multiplier: f32,
     snippet_len: usize,
-    codebook: Vec<Vec<i16>>,
+    align_offset: usize,
+    quality: u8,
+    parity: u8,
+    noise: NoiseEstimator,
+    noise_window: usize,
+    low_cut: u16,
+    high_cut: u16,
+    /// Prefilter for detection; only the encoder needs it
+    band_pass: Option<BandPass>,
+    polarity: Polarity,
+    refractory: usize,
+    codebook: Codebook,
 }
 
 impl SpikeCompressor {
+    /// Compressor using the built-in synthetic codebook
     pub fn new(threshold_multiplier: f32) -> Self {
-        // Initialize a pseudo-random but deterministic codebook for VQ
-        // In a real system, this would be trained on neural data.
-        let mut codebook = Vec::with_capacity(256);
-        for i in 0..256 {
-            let mut template = vec![0i16; 16];
-            for j in 0..16 {
-                // Generate various "spike-like" shapes
-                let phase = (j as f32 / 16.0) * 2.0 * std::f32::consts::PI;
-                let val = (i as f32 / 128.0 - 1.0) * (phase.sin() * 1000.0);
-                template[j] = val as i16;
-            }
-            codebook.push(template);
-        }
+        Self::with_codebook(threshold_multiplier, Codebook::synthetic(DEFAULT_SNIPPET_LEN))
+    }
 
+    /// Snippets are centred on the spike's peak
+    pub fn with_codebook(threshold_multiplier: f32, codebook: Codebook) -> Self {
         Self {
             threshold_multiplier,
-            snippet_len: 16,
+            snippet_len: codebook.snippet_len,
+            align_offset: codebook.snippet_len / 2,
+            quality: 0,
+            parity: 0,
+            noise: NoiseEstimator::Rms,
+            noise_window: DEFAULT_NOISE_WINDOW,
+            low_cut: 0,
+            high_cut: 0,
+            band_pass: None,
+            polarity: Polarity::Bipolar,
+            refractory: codebook.snippet_len,
+            codebook,
+        }
+    }
+
+    /// Select how much of each spike beyond its template index is stored
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = quality.min(MAX_QUALITY);
+        self
+    }
+
+    /// Protect each encoded payload with `parity` Reed–Solomon check bytes per
+    /// packet, correcting up to `parity / 2` corrupted bytes in every packet.
+    pub fn with_parity(mut self, parity: u8) -> Self {
+        self.parity = parity.min(fec::MAX_PARITY);
+        self
+    }
+
+    /// Scale the threshold by `noise` instead of the RMS; `window` is in samples.
+    pub fn with_noise(mut self, noise: NoiseEstimator, window: usize) -> Self {
+        self.noise = noise;
+        self.noise_window = window.clamp(1, u32::MAX as usize);
+        self
+    }
+
+    /// Band-pass the signal (`low_cut`..`high_cut` Hz, 0 leaving that edge open)
+    /// before detection, so drift and LFP do not trigger events. Both 0 disables it.
+    pub fn with_band_pass(mut self, low_cut: u16, high_cut: u16, sample_rate: u32) -> Result<Self> {
+        self.band_pass = if low_cut == 0 && high_cut == 0 {
+            None
+        } else {
+            Some(BandPass::new(low_cut as u32, high_cut as u32, sample_rate)?)
+        };
+        self.low_cut = low_cut;
+        self.high_cut = high_cut;
+        Ok(self)
+    }
+
+    /// Detect crossings of `polarity` only
+    pub fn with_polarity(mut self, polarity: Polarity) -> Self {
+        self.polarity = polarity;
+        self
+    }
+
+    /// Samples after a spike's peak in which no new spike is detected (default:
+    /// the snippet length)
+    pub fn with_refractory(mut self, samples: usize) -> Self {
+        self.refractory = samples;
+        self
+    }
+
+    /// Snippet samples before the peak; the rest of the snippet follows it
+    pub fn with_pre_samples(mut self, samples: usize) -> Result<Self> {
+        if samples >= self.snippet_len {
+            anyhow::bail!("Pre-peak samples {} must be below the snippet length {}", samples, self.snippet_len);
+        }
+        self.align_offset = samples;
+        Ok(self)
+    }
+
+    /// Rebuild the compressor a stream was encoded with, refusing parameters this
+    /// decoder cannot honour.
+    pub fn from_params(params: &EventParams, codebook: Codebook) -> Result<Self> {
+        if !params.threshold_multiplier.is_finite() || params.threshold_multiplier <= 0.0 {
+            anyhow::bail!("Invalid threshold multiplier {}", params.threshold_multiplier);
+        }
+        let snippet_len = params.snippet_len as usize;
+        if snippet_len == 0 || params.align_offset as usize >= snippet_len {
+            anyhow::bail!("Invalid snippet geometry: length {}, alignment offset {}", snippet_len, params.align_offset);
+        }
+        if codebook.id() != params.codebook_id {
+            anyhow::bail!("Codebook {:08x} does not match stream codebook {:08x}", codebook.id(), params.codebook_id);
+        }
+        if codebook.snippet_len != snippet_len {
+            anyhow::bail!("Codebook snippet length {} does not match stream snippet length {}", codebook.snippet_len, snippet_len);
+        }
+        if params.quality > MAX_QUALITY {
+            anyhow::bail!("Unsupported event quality level {}", params.quality);
+        }
+        if params.parity > fec::MAX_PARITY {
+            anyhow::bail!("Unsupported FEC parity {}", params.parity);
+        }
+        if params.noise == NoiseEstimator::WindowedMad && params.noise_window == 0 {
+            anyhow::bail!("Windowed noise estimate with an empty window");
+        }
+        Ok(Self {
+            threshold_multiplier: params.threshold_multiplier,
+            snippet_len,
+            align_offset: params.align_offset as usize,
+            quality: params.quality,
+            parity: params.parity,
+            noise: params.noise,
+            noise_window: if params.noise_window == 0 { DEFAULT_NOISE_WINDOW } else { params.noise_window as usize },
+            low_cut: params.low_cut,
+            high_cut: params.high_cut,
+            band_pass: None,
+            polarity: Polarity::Bipolar,
+            refractory: snippet_len,
             codebook,
+        })
+    }
+
+    pub fn params(&self) -> EventParams {
+        EventParams {
+            threshold_multiplier: self.threshold_multiplier,
+            snippet_len: self.snippet_len as u16,
+            align_offset: self.align_offset as u16,
+            codebook_id: self.codebook.id(),
+            quality: self.quality,
+            parity: self.parity,
+            packet_len: 0,
+            noise: self.noise,
+            noise_window: self.noise_window as u32,
+            low_cut: self.low_cut,
+            high_cut: self.high_cut,
+        }
+    }
+
+    /// Fresh prefilter state for one channel, if band-passing
+    pub(crate) fn band_pass(&self) -> Option<BandPass> {
+        self.band_pass.clone()
+    }
+
+    /// Fresh detector; a peak is searched for up to the snippet's post-peak length
+    pub(crate) fn detector(&self) -> Detector {
+        Detector::new(self.polarity, self.snippet_len - self.align_offset, self.refractory)
+    }
+
+    /// The channel as detection sees it
+    fn prefiltered<'a>(&self, samples: &'a [i32]) -> Cow<'a, [i32]> {
+        match self.band_pass() {
+            Some(mut filter) => Cow::Owned(filter.apply(samples)),
+            None => Cow::Borrowed(samples),
         }
     }
 
+    pub fn codebook(&self) -> &Codebook {
+        &self.codebook
+    }
+
     /// Vector Quantization: Find closest template in codebook
     fn quantize(&self, snippet: &[i16]) -> u8 {
-        let mut best_idx = 0;
-        let mut min_dist = f64::MAX;
+        self.codebook.nearest(snippet)
+    }
 
-        for (idx, template) in self.codebook.iter().enumerate() {
-            let mut dist = 0.0;
-            for i in 0..self.snippet_len {
-                dist += (snippet[i] as f64 - template[i] as f64).powi(2);
+    /// Noise level for each window of `window` samples (the whole channel unless
+    /// windowed), with the window length
+    fn noise_levels(&self, samples: &[i32]) -> (Vec<f64>, usize) {
+        match self.noise {
+            NoiseEstimator::Rms => {
+                let sum_sq: f64 = samples.iter().map(|&x| (x as f64).powi(2)).sum();
+                (vec![(sum_sq / samples.len() as f64).sqrt()], samples.len().max(1))
             }
-            if dist < min_dist {
-                min_dist = dist;
-                best_idx = idx as u8;
+            NoiseEstimator::Mad => (vec![mad_noise(samples)], samples.len().max(1)),
+            NoiseEstimator::WindowedMad if samples.is_empty() => (vec![0.0], 1),
+            NoiseEstimator::WindowedMad => {
+                (samples.chunks(self.noise_window).map(mad_noise).collect(), self.noise_window)
             }
         }
-        best_idx
     }
 
-    pub fn encode(&self, samples: &[i32]) -> Result<Vec<u8>> {
-        let sum_sq: f64 = samples.iter().map(|&x| (x as f64).powi(2)).sum();
-        let rms = (sum_sq / samples.len() as f64).sqrt();
-        let threshold = rms * self.threshold_multiplier as f64;
-        
-        let mut events = Vec::new();
-        let mut i = 0;
+    /// Detect spikes and return the channel's noise level (the median over windows
+    /// when windowed) and `(peak, snippet)` for each spike
+    fn detect(&self, samples: &[i32]) -> (f64, Vec<(u32, Vec<i16>)>) {
+        let samples = &*self.prefiltered(samples);
+        let (noise, peaks) = self.peaks(samples);
         let len = samples.len();
+        let spikes = peaks
+            .into_iter()
+            .map(|peak| {
+                let start = (peak as usize).saturating_sub(self.align_offset);
+                let snippet = (0..self.snippet_len).map(|j| samples[(start + j).min(len - 1)] as i16).collect();
+                (peak as u32, snippet)
+            })
+            .collect();
+        (noise, spikes)
+    }
 
-        while i < len {
-            if (samples[i] as f64).abs() > threshold {
-                let start = i.saturating_sub(self.snippet_len / 2);
-                let mut snippet = Vec::with_capacity(self.snippet_len);
-                for j in 0..self.snippet_len {
-                    let idx = (start + j).min(len - 1);
-                    snippet.push(samples[idx] as i16);
-                }
+    /// Noise level and spike peaks of an already prefiltered channel
+    fn peaks(&self, samples: &[i32]) -> (f64, Vec<u64>) {
+        let (levels, window) = self.noise_levels(samples);
+        let thresholds: Vec<f64> = levels.iter().map(|level| level * self.threshold_multiplier as f64).collect();
+        let mut sorted = levels;
+        sorted.sort_by(f64::total_cmp);
+        let noise = sorted[sorted.len() / 2];
 
-                let template_idx = self.quantize(&snippet);
-                events.push((i as u32, template_idx));
-                i += self.snippet_len;
-            } else {
-                i += 1;
+        let mut detector = self.detector();
+        let mut peaks: Vec<u64> = samples
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &x)| detector.push(i as u64, x, thresholds[i / window]))
+            .collect();
+        peaks.extend(detector.finish());
+        (noise, peaks)
+    }
+
+    /// Sample ranges covered by the snippets of the detected spikes, with
+    /// overlapping or touching ranges merged
+    pub fn spike_windows(&self, samples: &[i32]) -> Vec<Range<usize>> {
+        let (_, peaks) = self.peaks(&self.prefiltered(samples));
+        let mut windows: Vec<Range<usize>> = Vec::new();
+        for peak in peaks {
+            let start = (peak as usize).saturating_sub(self.align_offset);
+            let end = (start + self.snippet_len).min(samples.len());
+            match windows.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ => windows.push(start..end),
+            }
+        }
+        windows
+    }
+
+    /// Spike snippets as seen by the encoder, for codebook training
+    pub fn snippets(&self, samples: &[i32]) -> Vec<Vec<i16>> {
+        self.detect(samples).1.into_iter().map(|(_, snippet)| snippet).collect()
+    }
+
+    /// Bits per residual sample for the current quality level (0 = no residual)
+    fn residual_bits(&self) -> u32 {
+        match self.quality {
+            0 | 1 => 0,
+            2 => 4,
+            _ => 8,
+        }
+    }
+
+    /// Quantize one spike and append it to `payload`: VQ index, then the gain code
+    /// (quality >= 1), then a residual shift and bit-packed residual (quality >= 2).
+    pub(crate) fn write_event(&self, snippet: &[i16], payload: &mut Vec<u8>) -> Result<()> {
+        if self.quality == 0 {
+            payload.write_u8(self.quantize(snippet))?; // VQ index
+            return Ok(());
+        }
+        let (idx, gain) = self.codebook.nearest_with_gain(snippet);
+        let gain_code = quantize_gain(gain);
+        payload.write_u8(idx)?;
+        payload.write_u8(gain_code)?;
+
+        let bits = self.residual_bits();
+        if bits == 0 {
+            return Ok(());
+        }
+        // Residual against exactly what the decoder will reconstruct
+        let template = &self.codebook.templates[idx as usize];
+        let gain = dequantize_gain(gain_code);
+        let residual: Vec<i32> = snippet
+            .iter()
+            .zip(template.iter())
+            .map(|(&s, &t)| s as i32 - scale_template(t, gain))
+            .collect();
+        let qmax = (1i32 << (bits - 1)) - 1;
+        let peak = residual.iter().map(|r| r.abs()).max().unwrap_or(0);
+        let mut shift = 0u32;
+        while peak > qmax << shift {
+            shift += 1;
+        }
+        payload.write_u8(shift as u8)?;
+        let mut writer = BitWriter::endian(Vec::new(), BitBigEndian);
+        for &r in &residual {
+            let q = ((r as f64) / (1i64 << shift) as f64).round() as i32;
+            writer.write_signed(bits, q.clamp(-qmax, qmax))?;
+        }
+        writer.byte_align()?;
+        payload.extend_from_slice(&writer.into_writer());
+        Ok(())
+    }
+
+    /// Inverse of `write_event`: the reconstructed snippet
+    pub(crate) fn read_event(&self, cursor: &mut Cursor<&[u8]>) -> Result<Vec<i32>> {
+        let idx = cursor.read_u8()? as usize;
+        let Some(template) = self.codebook.templates.get(idx) else {
+            anyhow::bail!("Template index {} outside codebook of {}", idx, self.codebook.templates.len());
+        };
+        if self.quality == 0 {
+            return Ok(template.iter().map(|&t| t as i32).collect());
+        }
+        let gain = dequantize_gain(cursor.read_u8()?);
+        let mut snippet: Vec<i32> = template.iter().map(|&t| scale_template(t, gain)).collect();
+
+        let bits = self.residual_bits();
+        if bits > 0 {
+            let shift = cursor.read_u8()? as u32;
+            if shift > 16 {
+                anyhow::bail!("Invalid residual shift {}", shift);
+            }
+            let mut packed = vec![0u8; (self.snippet_len * bits as usize).div_ceil(8)];
+            cursor.read_exact(&mut packed)?;
+            let mut reader = BitReader::endian(Cursor::new(packed), BitBigEndian);
+            for s in snippet.iter_mut() {
+                *s += reader.read_signed::<i32>(bits)? << shift;
             }
         }
+        Ok(snippet)
+    }
+
+    pub fn encode(&self, samples: &[i32]) -> Result<Vec<u8>> {
+        let (noise, spikes) = self.detect(samples);
 
         let mut buffer = Vec::new();
-        buffer.write_f32::<BigEndian>(rms as f32)?;
-        buffer.write_u32::<BigEndian>(events.len() as u32)?;
+        buffer.write_f32::<BigEndian>(noise as f32)?;
+        buffer.write_u32::<BigEndian>(spikes.len() as u32)?;
 
         let mut payload = Vec::new();
         let mut last_ts = 0;
-        for (ts, idx) in events {
+        for (ts, snippet) in &spikes {
             payload.write_u32::<BigEndian>(ts - last_ts)?; // Delta timestamp
-            payload.write_u8(idx)?; // VQ index
-            last_ts = ts;
+            self.write_event(snippet, &mut payload)?;
+            last_ts = *ts;
         }
 
         // Add CRC-32 for payload integrity
@@ -96,12 +598,15 @@ impl SpikeCompressor {
         buffer.write_u32::<BigEndian>(checksum)?;
         buffer.extend_from_slice(&payload);
 
-        Ok(buffer)
+        Ok(fec::encode(&buffer, self.parity))
     }
 
     pub fn decode(&self, data: &[u8], total_samples: usize) -> Result<Vec<i32>> {
+        // Repair what the parity allows before the CRC gets a say
+        let (data, _corrected) = fec::decode(data, self.parity)?;
+        let data = data.as_slice();
         let mut cursor = Cursor::new(data);
-        let _rms = cursor.read_f32::<BigEndian>()?;
+        let _noise = cursor.read_f32::<BigEndian>()?;
         let event_count = cursor.read_u32::<BigEndian>()?;
         let stored_checksum = cursor.read_u32::<BigEndian>()?;
         
@@ -120,18 +625,33 @@ impl SpikeCompressor {
 
         for _ in 0..event_count {
             let delta = cursor.read_u32::<BigEndian>()?;
-            let idx = cursor.read_u8()? as usize;
             current_ts += delta;
+            let snippet = self.read_event(&mut cursor)?;
 
-            let template = &self.codebook[idx];
-            let start = (current_ts as usize).saturating_sub(self.snippet_len / 2);
-            for j in 0..self.snippet_len {
+            let start = (current_ts as usize).saturating_sub(self.align_offset);
+            for (j, &v) in snippet.iter().enumerate() {
                 if start + j < total_samples {
-                    output[start + j] = template[j] as i32;
+                    output[start + j] = v.clamp(i16::MIN as i32, i16::MAX as i32);
                 }
             }
         }
 
         Ok(output)
     }
-}
\ No newline at end of file
+}
+
+/// Gain code `q` stands for `2^((q - 128) / 16)`: 1/256 up to ~250x in ~4.4% steps.
+fn quantize_gain(gain: f64) -> u8 {
+    if gain <= 0.0 {
+        return 0;
+    }
+    (gain.log2() * 16.0 + 128.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn dequantize_gain(code: u8) -> f64 {
+    ((code as f64 - 128.0) / 16.0).exp2()
+}
+
+fn scale_template(t: i16, gain: f64) -> i32 {
+    (t as f64 * gain).round() as i32
+}
@@ -0,0 +1,1707 @@
+use anyhow::Result;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::codebook::Codebook;
+use crate::coder::ResidualCoder;
+use crate::container::{self, Header, SeekPoint, SeekTable};
+use crate::filter::BandPass;
+use crate::realtime::{PacketDecoder, PacketEncoder};
+use crate::{arith, coder, fec, hybrid, lpc, simd_ops, sparse, spike};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Mode {
+    Lossless, // Proven LPC+Rice
+    Events,   // 2500x VQ
+    Elite,    // LPC + rANS over residual symbols with shared tables
+    Hybrid,   // Lossless spike windows + approximate background
+    NearLossless, // LPC + Rice with in-loop quantised residuals, |error| <= max_error
+}
+
+/// Stream-level parameters stored in the `.neur` header
+#[derive(Clone, Copy, Debug)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    /// Interleaved sample count (frames * channels)
+    pub total_samples: u64,
+}
+
+impl StreamInfo {
+    fn channel_count(&self) -> usize {
+        self.channels.max(1) as usize
+    }
+}
+
+/// Frames between independent blocks when prediction warms up across blocks
+pub const DEFAULT_SEEK_INTERVAL: u32 = 64;
+/// Samples per read in `Encoder::write_from`
+const READ_SAMPLES: usize = 4096;
+
+/// Block flag (warm-up streams only): the block ignores the previous block's samples
+const BLOCK_INDEPENDENT: u8 = 0x01;
+
+/// Marks the start of every block in block-CRC streams
+const BLOCK_SYNC: [u8; 2] = [0xFF, 0xB5];
+
+/// Furthest earlier channel the encoder tries as an inter-channel reference
+pub const MAX_REFERENCE_DISTANCE: usize = 4;
+
+/// Earlier channel of the same frame a block is predicted from
+/// (`FLAG_INTER_CHANNEL` streams), by distance back from the block's channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reference {
+    None,
+    /// The block codes its samples minus the reference's decoded samples
+    Samples(usize),
+    /// The block codes its prediction residuals minus the reference's
+    Residuals(usize),
+}
+
+/// Reference byte bit: residuals rather than samples are subtracted
+const REFERENCE_RESIDUALS: u8 = 0x80;
+
+impl Reference {
+    fn code(self) -> u8 {
+        match self {
+            Reference::None => 0,
+            Reference::Samples(distance) => distance as u8,
+            Reference::Residuals(distance) => REFERENCE_RESIDUALS | distance as u8,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self> {
+        let distance = (code & !REFERENCE_RESIDUALS) as usize;
+        match (code & REFERENCE_RESIDUALS != 0, distance) {
+            (false, 0) => Ok(Reference::None),
+            (true, 0) => anyhow::bail!("Invalid inter-channel reference {:#04x}", code),
+            (false, distance) => Ok(Reference::Samples(distance)),
+            (true, distance) => Ok(Reference::Residuals(distance)),
+        }
+    }
+
+    /// The referenced channel, for a block of `channel`
+    fn channel(self, channel: usize) -> Result<Option<usize>> {
+        match self {
+            Reference::None => Ok(None),
+            Reference::Samples(distance) | Reference::Residuals(distance) => match channel.checked_sub(distance) {
+                Some(reference) => Ok(Some(reference)),
+                None => anyhow::bail!("Channel {} cannot reference {} channels back", channel, distance),
+            },
+        }
+    }
+}
+
+/// A channel's block of the current frame, as later channels may reference it
+#[derive(Clone)]
+struct FrameBlock {
+    /// Samples as the decoder reconstructs them
+    samples: Vec<i32>,
+    /// Prediction residuals, before any reference's are subtracted
+    residuals: Vec<i32>,
+}
+
+/// `a - b` over the samples both hold, aligned at the end
+fn difference(a: &[i32], b: &[i32]) -> Vec<i32> {
+    let len = a.len().min(b.len());
+    a[a.len() - len..].iter().zip(&b[b.len() - len..]).map(|(&x, &y)| x.wrapping_sub(y)).collect()
+}
+
+/// Entropy coder state a channel carries from block to block, reset at
+/// independent blocks
+#[derive(Clone, Default)]
+struct ChannelModels {
+    /// Elite residual statistics
+    residuals: coder::ResidualModel,
+    /// `ResidualCoder::Adaptive` probabilities
+    contexts: arith::ContextModel,
+}
+
+impl ChannelModels {
+    fn reset(&mut self) {
+        self.residuals.reset();
+        self.contexts.reset();
+    }
+}
+
+/// Codec settings for `compress`, `Encoder` and `encode_recording`. Build it from
+/// `Config::default()` with the `with_*` setters.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Config {
+    pub mode: Mode,
+    /// Stream layout for `compress`; `Encoder` and `encode_recording` take it
+    /// from their `StreamInfo` instead
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub order: usize,
+    pub block_size: usize,
+    pub threshold: f32,
+    /// Snippet length of the synthetic codebook; a trained codebook sets its own
+    pub snippet_len: usize,
+    /// Events reconstruction quality, 0..=`spike::MAX_QUALITY`
+    pub quality: u8,
+    /// Entropy coder for Lossless residuals
+    pub residual_coder: ResidualCoder,
+    /// Per block, keep whichever of the fixed predictors and LPC orders
+    /// `0..=order` encodes smallest instead of always using `order`
+    pub order_search: bool,
+    /// Lossless/Elite blocks predict from the previous block's tail except every
+    /// `seek_interval`-th frame, which stands alone as a seek point. 0 makes only
+    /// the first frame independent; 1 disables warm-up altogether.
+    pub seek_interval: u32,
+    /// Frame every Lossless/Elite block with a sync word and CRC-32 so the decoder
+    /// can skip damaged blocks instead of failing
+    pub block_crc: bool,
+    /// Reed–Solomon check bytes per events packet, 0..=`fec::MAX_PARITY`
+    pub parity: u8,
+    /// Events mode: emit a self-contained `realtime` packet every `packet_len`
+    /// samples per channel instead of one payload per channel at `finish`
+    pub packet_len: usize,
+    /// Events mode: noise estimate that scales the spike threshold
+    pub noise: spike::NoiseEstimator,
+    /// Samples per noise window (windowed MAD and the real-time estimate)
+    pub noise_window: usize,
+    /// Events mode: band-pass before detection, in Hz (0 leaves that edge open);
+    /// Lossless/Elite always code the unfiltered samples
+    pub low_cut: u16,
+    pub high_cut: u16,
+    /// Events mode: which threshold crossings are spikes
+    pub polarity: spike::Polarity,
+    /// Events mode: snippet samples before the peak (default: half the snippet)
+    pub pre_samples: Option<usize>,
+    /// Events mode: samples after a peak in which no spike is detected
+    /// (default: the snippet length)
+    pub refractory: Option<usize>,
+    /// Hybrid mode: what is kept between spike windows
+    pub background: hybrid::Background,
+    /// Hybrid mode: samples per background segment
+    pub decimation: u16,
+    /// NearLossless mode: largest allowed difference between a decoded and an
+    /// original sample (0 is lossless)
+    pub max_error: u16,
+    /// Lossless/Elite/NearLossless: per block, also try predicting each channel
+    /// from one of the `MAX_REFERENCE_DISTANCE` channels before it
+    pub inter_channel: bool,
+}
+
+impl Config {
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn with_channels(mut self, channels: u16) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub fn with_order(mut self, order: usize) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn with_snippet_len(mut self, snippet_len: usize) -> Self {
+        self.snippet_len = snippet_len;
+        self
+    }
+
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    pub fn with_residual_coder(mut self, residual_coder: ResidualCoder) -> Self {
+        self.residual_coder = residual_coder;
+        self
+    }
+
+    pub fn with_order_search(mut self, order_search: bool) -> Self {
+        self.order_search = order_search;
+        self
+    }
+
+    pub fn with_seek_interval(mut self, seek_interval: u32) -> Self {
+        self.seek_interval = seek_interval;
+        self
+    }
+
+    pub fn with_block_crc(mut self, block_crc: bool) -> Self {
+        self.block_crc = block_crc;
+        self
+    }
+
+    pub fn with_parity(mut self, parity: u8) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub fn with_packet_len(mut self, packet_len: usize) -> Self {
+        self.packet_len = packet_len;
+        self
+    }
+
+    pub fn with_noise(mut self, noise: spike::NoiseEstimator, window: usize) -> Self {
+        self.noise = noise;
+        self.noise_window = window;
+        self
+    }
+
+    pub fn with_band_pass(mut self, low_cut: u16, high_cut: u16) -> Self {
+        self.low_cut = low_cut;
+        self.high_cut = high_cut;
+        self
+    }
+
+    pub fn with_polarity(mut self, polarity: spike::Polarity) -> Self {
+        self.polarity = polarity;
+        self
+    }
+
+    pub fn with_pre_samples(mut self, pre_samples: Option<usize>) -> Self {
+        self.pre_samples = pre_samples;
+        self
+    }
+
+    pub fn with_refractory(mut self, refractory: Option<usize>) -> Self {
+        self.refractory = refractory;
+        self
+    }
+
+    pub fn with_background(mut self, background: hybrid::Background) -> Self {
+        self.background = background;
+        self
+    }
+
+    pub fn with_decimation(mut self, decimation: u16) -> Self {
+        self.decimation = decimation;
+        self
+    }
+
+    pub fn with_max_error(mut self, max_error: u16) -> Self {
+        self.max_error = max_error;
+        self
+    }
+
+    pub fn with_inter_channel(mut self, inter_channel: bool) -> Self {
+        self.inter_channel = inter_channel;
+        self
+    }
+
+    /// False for Events without packets and Hybrid, which detect spikes against
+    /// the whole recording: only `encode_recording` writes those.
+    pub fn streams(&self) -> bool {
+        match self.mode {
+            Mode::Events => self.packet_len > 0,
+            Mode::Hybrid => false,
+            Mode::Lossless | Mode::Elite | Mode::NearLossless => true,
+        }
+    }
+}
+
+impl Default for Config {
+    /// Lossless, mono, 20 kHz with the CLI's LPC order, block size and threshold
+    fn default() -> Self {
+        Self {
+            mode: Mode::Lossless,
+            sample_rate: 20_000,
+            channels: 1,
+            order: 8,
+            block_size: 32,
+            threshold: 6.0,
+            snippet_len: spike::DEFAULT_SNIPPET_LEN,
+            quality: 0,
+            residual_coder: ResidualCoder::Partitioned,
+            order_search: false,
+            seek_interval: DEFAULT_SEEK_INTERVAL,
+            block_crc: false,
+            parity: 0,
+            packet_len: 0,
+            noise: spike::NoiseEstimator::Rms,
+            noise_window: spike::DEFAULT_NOISE_WINDOW,
+            low_cut: 0,
+            high_cut: 0,
+            polarity: spike::Polarity::Bipolar,
+            pre_samples: None,
+            refractory: None,
+            background: hybrid::Background::Decimated,
+            decimation: hybrid::DEFAULT_DECIMATION,
+            max_error: 0,
+            inter_channel: false,
+        }
+    }
+}
+
+/// Incremental encoder: interleaved samples go in through `write_samples` or
+/// `write_from`, and each frame (one block per channel, or one events packet per
+/// channel) is written to `out` as soon as every channel has it in full, so at
+/// most one frame is held in memory.
+///
+/// Events without packets and Hybrid detect spikes against the noise of the whole
+/// recording and cannot be streamed; `Encoder::new` rejects them, and
+/// `encode_recording` writes them from samples already in memory.
+pub struct Encoder<W: Write> {
+    out: W,
+    config: Config,
+    sample_rate: u32,
+    /// Interleaved samples the header declares, and those written so far
+    total_samples: u64,
+    samples_written: u64,
+    /// Bytes per sample of `write_from` input
+    sample_bytes: usize,
+    codebook: Option<Codebook>,
+    pending: Vec<Vec<i32>>,
+    next_channel: usize,
+    /// Trailing samples of each channel's previous block, for warm-up streams
+    history: Vec<Vec<i32>>,
+    frames_written: u64,
+    /// Bytes written to `out` so far
+    position: u64,
+    seek_table: SeekTable,
+    /// One per channel once a packetised events stream has started
+    packets: Vec<PacketEncoder>,
+    /// Set for NearLossless streams
+    near_lossless: Option<lpc::NearLossless>,
+    /// Entropy coder state per channel, shared by its blocks
+    models: Vec<ChannelModels>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes the stream header immediately; `info.total_samples` must be known up
+    /// front, and `finish` fails unless exactly that many samples were written.
+    pub fn new(out: W, info: StreamInfo, config: Config) -> Result<Self> {
+        if !config.streams() {
+            anyhow::bail!("{:?} mode without packets needs the whole recording; use encode_recording", config.mode);
+        }
+        Self::open(out, info, config)
+    }
+
+    fn open(mut out: W, info: StreamInfo, config: Config) -> Result<Self> {
+        if config.block_size == 0 {
+            anyhow::bail!("Block size must be non-zero");
+        }
+        if config.order > u8::MAX as usize {
+            anyhow::bail!("LPC order must be at most {}", u8::MAX);
+        }
+        if !info.total_samples.is_multiple_of(info.channel_count() as u64) {
+            anyhow::bail!("{} samples do not make whole frames of {} channels", info.total_samples, info.channel_count());
+        }
+        let detects = matches!(config.mode, Mode::Events | Mode::Hybrid);
+        if detects && !(1..=u16::MAX as usize).contains(&config.snippet_len) {
+            anyhow::bail!("Snippet length must be between 1 and {}", u16::MAX);
+        }
+        if config.quality > spike::MAX_QUALITY {
+            anyhow::bail!("Event quality must be at most {}", spike::MAX_QUALITY);
+        }
+        if config.parity > fec::MAX_PARITY {
+            anyhow::bail!("FEC parity must be at most {} bytes", fec::MAX_PARITY);
+        }
+        if config.packet_len > u16::MAX as usize {
+            anyhow::bail!("Packet length must be at most {} samples", u16::MAX);
+        }
+        if config.mode == Mode::Hybrid && config.decimation == 0 {
+            anyhow::bail!("Hybrid background segments must be non-empty");
+        }
+        if detects && (config.low_cut != 0 || config.high_cut != 0) {
+            BandPass::new(config.low_cut as u32, config.high_cut as u32, info.sample_rate)?;
+        }
+        let near_lossless = (config.mode == Mode::NearLossless).then_some(lpc::NearLossless {
+            max_error: config.max_error as u32,
+            bits_per_sample: info.bits_per_sample as u32,
+        });
+        let mut header = Header {
+            residual_coder: config.residual_coder,
+            max_error: if near_lossless.is_some() { config.max_error } else { 0 },
+            ..Header::new(config.mode, info, config.block_size as u32, config.order as u8)
+        };
+        if matches!(config.mode, Mode::Lossless | Mode::Elite | Mode::NearLossless) {
+            header.flags |= container::FLAG_SEEK_TABLE;
+            if config.seek_interval != 1 {
+                header.flags |= container::FLAG_WARM_UP;
+            }
+            if config.block_crc {
+                header.flags |= container::FLAG_BLOCK_CRC;
+            }
+            if config.inter_channel && info.channel_count() > 1 {
+                header.flags |= container::FLAG_INTER_CHANNEL;
+            }
+        }
+        if config.mode == Mode::Elite {
+            header.flags |= container::FLAG_ELITE_RANS;
+        }
+        if config.mode == Mode::Events && config.packet_len == 0 && config.parity == 0 {
+            header.flags |= container::FLAG_EVENT_TABLES;
+        }
+        if config.mode == Mode::Events && config.parity > 0 {
+            header.flags |= container::FLAG_EVENT_FEC;
+        }
+        let mut header_bytes = Vec::new();
+        header.write(&mut header_bytes)?;
+        out.write_all(&header_bytes)?;
+        let channels = info.channel_count();
+        Ok(Self {
+            out,
+            config,
+            sample_rate: info.sample_rate,
+            total_samples: info.total_samples,
+            samples_written: 0,
+            sample_bytes: (info.bits_per_sample as usize).div_ceil(8),
+            codebook: None,
+            pending: vec![Vec::with_capacity(config.block_size); channels],
+            next_channel: 0,
+            history: vec![Vec::new(); channels],
+            frames_written: 0,
+            position: header_bytes.len() as u64,
+            seek_table: SeekTable::default(),
+            packets: Vec::new(),
+            near_lossless,
+            models: vec![ChannelModels::default(); channels],
+        })
+    }
+
+    /// Quantize events against a trained codebook instead of the synthetic one.
+    /// The stream records the codebook's id, so the decoder needs the same file.
+    pub fn with_codebook(mut self, codebook: Codebook) -> Self {
+        self.codebook = Some(codebook);
+        self
+    }
+
+    /// Feed interleaved samples; the slice need not end on a frame boundary.
+    pub fn write_samples(&mut self, samples: &[i32]) -> Result<()> {
+        if self.samples_written + samples.len() as u64 > self.total_samples {
+            anyhow::bail!("More samples than the {} the stream declares", self.total_samples);
+        }
+        self.samples_written += samples.len() as u64;
+        let channels = self.pending.len();
+        for &s in samples {
+            self.pending[self.next_channel].push(s);
+            self.next_channel = (self.next_channel + 1) % channels;
+            if self.next_channel == 0 && Some(self.pending[0].len()) == self.frame_len() {
+                self.flush_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Feed interleaved signed little-endian PCM, `bits_per_sample` rounded up to
+    /// whole bytes per sample, from `input` until it ends. Returns the number of
+    /// samples read.
+    pub fn write_from<R: Read>(&mut self, mut input: R) -> Result<u64> {
+        let width = self.sample_bytes;
+        if !(1..=4).contains(&width) {
+            anyhow::bail!("Cannot read {}-byte samples", width);
+        }
+        let mut buf = vec![0u8; width * READ_SAMPLES];
+        let mut filled = 0;
+        let mut samples = Vec::with_capacity(READ_SAMPLES);
+        let mut read = 0;
+        loop {
+            let n = match input.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            filled += n;
+            let whole = filled / width * width;
+            samples.clear();
+            samples.extend(buf[..whole].chunks_exact(width).map(|bytes| {
+                let shift = 32 - 8 * width as u32;
+                let raw = bytes.iter().rev().fold(0u32, |v, &b| (v << 8) | b as u32);
+                ((raw << shift) as i32) >> shift
+            }));
+            self.write_samples(&samples)?;
+            read += samples.len() as u64;
+            buf.copy_within(whole..filled, 0);
+            filled -= whole;
+        }
+        if filled != 0 {
+            anyhow::bail!("Input ends {} bytes into a sample", filled);
+        }
+        Ok(read)
+    }
+
+    /// Samples per channel in each frame written as the input arrives; `None`
+    /// for whole-recording events, which are only written by `finish`
+    fn frame_len(&self) -> Option<usize> {
+        match self.config.mode {
+            Mode::Events if self.config.packet_len == 0 => None,
+            Mode::Hybrid => None,
+            Mode::Events => Some(self.config.packet_len),
+            _ => Some(self.config.block_size),
+        }
+    }
+
+    fn event_compressor(&mut self) -> Result<spike::SpikeCompressor> {
+        let codebook = self
+            .codebook
+            .take()
+            .unwrap_or_else(|| Codebook::synthetic(self.config.snippet_len));
+        let mut compressor = spike::SpikeCompressor::with_codebook(self.config.threshold, codebook)
+            .with_quality(self.config.quality)
+            .with_parity(self.config.parity)
+            .with_noise(self.config.noise, self.config.noise_window)
+            .with_polarity(self.config.polarity)
+            .with_band_pass(self.config.low_cut, self.config.high_cut, self.sample_rate)?;
+        if let Some(pre) = self.config.pre_samples {
+            compressor = compressor.with_pre_samples(pre)?;
+        }
+        if let Some(refractory) = self.config.refractory {
+            compressor = compressor.with_refractory(refractory);
+        }
+        Ok(compressor)
+    }
+
+    /// Parity-protected in `FLAG_EVENT_FEC` streams, as the payloads are
+    fn write_event_params(&mut self, params: &spike::EventParams) -> Result<()> {
+        if self.config.parity > 0 {
+            params.write_protected(&mut self.out)
+        } else {
+            params.write(&mut self.out)
+        }
+    }
+
+    /// Write one packet per channel for the pending frame, starting the
+    /// packetised stream (event params, one `PacketEncoder` per channel) first.
+    fn flush_packets(&mut self) -> Result<()> {
+        if self.packets.is_empty() {
+            let compressor = self.event_compressor()?;
+            let params = spike::EventParams { packet_len: self.config.packet_len as u16, ..compressor.params() };
+            self.write_event_params(&params)?;
+            self.packets = (0..self.pending.len())
+                .map(|channel| PacketEncoder::new(compressor.clone(), channel as u16, self.config.packet_len))
+                .collect();
+        }
+        for (frame, encoder) in self.pending.iter_mut().zip(self.packets.iter_mut()) {
+            if !frame.is_empty() {
+                let packet = encoder.push(frame)?;
+                write_event_len(&mut self.out, packet.len(), self.config.parity)?;
+                self.out.write_all(&packet)?;
+            }
+            frame.clear();
+        }
+        Ok(())
+    }
+
+    /// Flush the trailing partial frame (or the buffered events payload) and return the writer.
+    pub fn finish(mut self) -> Result<W> {
+        if self.samples_written != self.total_samples {
+            anyhow::bail!("Wrote {} samples, but the stream declares {}", self.samples_written, self.total_samples);
+        }
+        match self.config.mode {
+            Mode::Events if self.config.packet_len > 0 => {
+                self.flush_packets()?;
+                for encoder in &mut self.packets {
+                    if let Some(packet) = encoder.flush()? {
+                        write_event_len(&mut self.out, packet.len(), self.config.parity)?;
+                        self.out.write_all(&packet)?;
+                    }
+                }
+                write_event_len(&mut self.out, 0, self.config.parity)?; // end of packets
+            }
+            Mode::Events => {
+                // Each channel gets its own RMS and therefore its own spike threshold.
+                let compressor = self.event_compressor()?;
+                self.write_event_params(&compressor.params())?;
+                let mut payloads = self.pending.iter().map(|channel| compressor.encode(channel)).collect::<Result<Vec<_>>>()?;
+                // FEC must see the bytes as stored, so only payloads without parity are coded.
+                if self.config.parity == 0 {
+                    let mut tables = coder::RansTables::default();
+                    payloads = payloads.iter().map(|data| coder::RansCoder::encode_shared(data, &mut tables)).collect::<Result<_>>()?;
+                    tables.write(&mut self.out)?;
+                }
+                for data in payloads {
+                    write_event_len(&mut self.out, data.len(), self.config.parity)?;
+                    self.out.write_all(&data)?;
+                }
+            }
+            Mode::Hybrid => self.finish_hybrid()?,
+            _ => {
+                if !self.pending[0].is_empty() {
+                    self.flush_frame()?;
+                }
+                self.seek_table.write(&mut self.out)?;
+                self.out.write_u64::<BigEndian>(self.position)?;
+            }
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Spike windows as Lossless blocks, then the background (see `hybrid`)
+    fn finish_hybrid(&mut self) -> Result<()> {
+        let detector = self.event_compressor()?;
+        let params = hybrid::HybridParams { background: self.config.background, factor: self.config.decimation };
+        params.write(&mut self.out)?;
+        let factor = params.factor as usize;
+        let independent = BlockContext {
+            history: &[],
+            flags: None,
+            reference: None,
+            reference_residuals: None,
+            near_lossless: None,
+            residual_table: None,
+            contexts: None,
+        };
+        for channel in &self.pending {
+            let windows = detector.spike_windows(channel);
+            let mut out = Vec::new();
+            out.write_u32::<BigEndian>(windows.len() as u32)?;
+            let mut previous_end = 0;
+            for window in &windows {
+                out.write_u32::<BigEndian>((window.start - previous_end) as u32)?;
+                out.write_all(&smallest_block(&channel[window.clone()], &independent, &self.config, encode_lossless_block)?.0.body)?;
+                previous_end = window.end;
+            }
+            let mask = hybrid::window_mask(&windows, channel.len());
+            match params.background {
+                hybrid::Background::None => {}
+                hybrid::Background::Decimated => {
+                    let means = hybrid::decimate(channel, &mask, factor);
+                    out.write_u32::<BigEndian>(means.len() as u32)?;
+                    for chunk in means.chunks(self.config.block_size) {
+                        out.write_all(&smallest_block(chunk, &independent, &self.config, encode_lossless_block)?.0.body)?;
+                    }
+                }
+                hybrid::Background::Noise => {
+                    let levels = hybrid::noise_levels(channel, &mask, factor);
+                    out.write_u32::<BigEndian>(levels.len() as u32)?;
+                    for level in levels {
+                        out.write_u16::<BigEndian>(level)?;
+                    }
+                }
+            }
+            self.out.write_all(&out)?;
+        }
+        Ok(())
+    }
+
+    /// Seek points fall every `seek_interval` frames; streams without warm-up,
+    /// where any frame would do, still space them `DEFAULT_SEEK_INTERVAL` apart.
+    fn at_seek_point(&self) -> bool {
+        match self.config.seek_interval {
+            0 => self.frames_written == 0,
+            1 => self.frames_written.is_multiple_of(DEFAULT_SEEK_INTERVAL as u64),
+            interval => self.frames_written.is_multiple_of(interval as u64),
+        }
+    }
+
+    fn flush_frame(&mut self) -> Result<()> {
+        if self.config.mode == Mode::Events {
+            return self.flush_packets();
+        }
+        let warm_up = self.config.seek_interval != 1;
+        let independent = !warm_up || self.at_seek_point();
+        if self.at_seek_point() {
+            self.seek_table.points.push(SeekPoint {
+                sample: self.frames_written * self.config.block_size as u64,
+                offset: self.position,
+            });
+        }
+        let keep = history_len(self.config.order);
+        if independent {
+            self.history.iter_mut().for_each(Vec::clear);
+            self.models.iter_mut().for_each(ChannelModels::reset);
+        }
+        let flags = warm_up.then_some(if independent { BLOCK_INDEPENDENT } else { 0 });
+        let mut frame = Vec::with_capacity(self.pending.len());
+        for channel in 0..self.pending.len() {
+            let (CodedBlock { body, contexts }, coded, block) = self.encode_channel(channel, &frame, flags)?;
+            if self.config.mode == Mode::Elite {
+                self.models[channel].residuals.update(&coded);
+            }
+            if let Some(contexts) = contexts {
+                self.models[channel].contexts = contexts;
+            }
+            if self.config.block_crc {
+                let Ok(frame) = u32::try_from(self.frames_written) else {
+                    anyhow::bail!("Too many frames for a block-CRC stream");
+                };
+                let mut fields = Vec::with_capacity(10);
+                fields.write_u32::<BigEndian>(frame)?;
+                fields.write_u16::<BigEndian>(channel as u16)?;
+                fields.write_u32::<BigEndian>(body.len() as u32)?;
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(&fields);
+                hasher.update(&body);
+                self.out.write_all(&BLOCK_SYNC)?;
+                self.out.write_all(&fields)?;
+                self.out.write_all(&body)?;
+                self.out.write_u32::<BigEndian>(hasher.finalize())?;
+                self.position += (BLOCK_SYNC.len() + fields.len() + body.len() + 4) as u64;
+            } else {
+                self.out.write_all(&body)?;
+                self.position += body.len() as u64;
+            }
+            frame.push(block);
+        }
+        // Histories advance only now, so sample references see the same
+        // pre-frame history on both sides
+        if warm_up {
+            for (history, block) in self.history.iter_mut().zip(&frame) {
+                // Later blocks predict from what the decoder will reconstruct
+                push_history(history, &block.samples, keep);
+            }
+        }
+        self.pending.iter_mut().for_each(Vec::clear);
+        self.frames_written += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Encoder<W> {
+    /// The smallest block for `channel` of the current frame against the reference
+    /// with the lowest `reference_cost`, with the residuals it codes and what later
+    /// channels see of it. `frame` holds the frame's earlier channels.
+    fn encode_channel(&self, channel: usize, frame: &[FrameBlock], flags: Option<u8>) -> Result<(CodedBlock, Vec<i32>, FrameBlock)> {
+        let chunk = &self.pending[channel];
+        let model = &self.models[channel];
+        let elite = self.config.mode == Mode::Elite;
+        let adaptive = !elite && self.config.residual_coder == ResidualCoder::Adaptive;
+        let residual_table = elite.then(|| model.residuals.table());
+        let encode: BlockEncoder = match self.config.mode {
+            Mode::Lossless | Mode::NearLossless => encode_lossless_block,
+            Mode::Elite => encode_elite_block,
+            Mode::Events | Mode::Hybrid => unreachable!("whole-recording modes are encoded in finish"),
+        };
+        let inter_channel = self.config.inter_channel && self.pending.len() > 1;
+        // Signal, history and near-lossless quantiser a reference leaves to predict,
+        // and the residuals it subtracts
+        let referenced = |reference: Reference| match reference {
+            Reference::None => (Cow::Borrowed(chunk.as_slice()), Cow::Borrowed(self.history[channel].as_slice()), self.near_lossless, None),
+            Reference::Samples(distance) => (
+                Cow::Owned(difference(chunk, &frame[channel - distance].samples)),
+                Cow::Owned(difference(&self.history[channel], &self.history[channel - distance])),
+                // Differences of two samples need one more bit
+                self.near_lossless.map(|q| lpc::NearLossless { bits_per_sample: q.bits_per_sample + 1, ..q }),
+                None,
+            ),
+            Reference::Residuals(distance) => (
+                Cow::Borrowed(chunk.as_slice()),
+                Cow::Borrowed(self.history[channel].as_slice()),
+                self.near_lossless,
+                Some(frame[channel - distance].residuals.as_slice()),
+            ),
+        };
+        let base = BlockContext {
+            history: &[],
+            flags,
+            reference: None,
+            reference_residuals: None,
+            near_lossless: None,
+            residual_table: residual_table.as_ref(),
+            contexts: adaptive.then_some(&model.contexts),
+        };
+        let code = |reference: Reference| inter_channel.then_some(reference.code());
+
+        // Rank references by a rough cost, then search predictors for the cheapest alone
+        let reach = if inter_channel { channel.min(MAX_REFERENCE_DISTANCE) } else { 0 };
+        let reference = if reach == 0 {
+            Reference::None
+        } else {
+            std::iter::once(Reference::None)
+                .chain((1..=reach).flat_map(|distance| [Reference::Samples(distance), Reference::Residuals(distance)]))
+                .min_by_key(|&reference| {
+                    let (signal, history, near_lossless, reference_residuals) = referenced(reference);
+                    let context = BlockContext { history: &history, reference: code(reference), reference_residuals, near_lossless, ..base };
+                    reference_cost(&signal, &context, &self.config)
+                })
+                .expect("at least one reference")
+        };
+        let (signal, history, near_lossless, reference_residuals) = referenced(reference);
+        let context = BlockContext { history: &history, reference: code(reference), reference_residuals, near_lossless, ..base };
+        let (block, qlp) = smallest_block(&signal, &context, &self.config, encode)?;
+        let (prediction, decoded) = predict(&signal, &context, &qlp);
+        let coded = coded_residuals(prediction.clone(), &context);
+        let samples = match (decoded, reference, self.near_lossless) {
+            (Some(decoded), Reference::Samples(distance), Some(quantizer)) => {
+                let base = &frame[channel - distance].samples;
+                decoded.iter().zip(base).map(|(&d, &b)| quantizer.clamp(d as i64 + b as i64)).collect()
+            }
+            (Some(decoded), _, _) => decoded,
+            (None, _, _) => chunk.clone(),
+        };
+        Ok((block, coded, FrameBlock { samples, residuals: prediction }))
+    }
+}
+
+/// Encode a recording held in memory as interleaved samples, in any mode; the
+/// only way to write Events without packets and Hybrid (see `Encoder`). `codebook`
+/// replaces the synthetic one for events, as `Encoder::with_codebook`.
+pub fn encode_recording<W: Write>(
+    out: W,
+    info: StreamInfo,
+    config: Config,
+    codebook: Option<Codebook>,
+    samples: &[i32],
+) -> Result<W> {
+    let mut encoder = Encoder::open(out, info, config)?;
+    encoder.codebook = codebook;
+    encoder.write_samples(samples)?;
+    encoder.finish()
+}
+
+/// Summed magnitude of the residuals a block would code against `context` under
+/// the configured LPC order: a cheap stand-in for its size when choosing among
+/// inter-channel references, each of which would otherwise cost a full
+/// predictor search.
+fn reference_cost(signal: &[i32], context: &BlockContext, config: &Config) -> u64 {
+    let qlp = lpc_predictor(signal, config.order, config.block_size);
+    let coded = coded_residuals(predict(signal, context, &qlp).0, context);
+    coded.iter().map(|&r| r.unsigned_abs() as u64).sum()
+}
+
+fn lpc_predictor(chunk: &[i32], order: usize, block_size: usize) -> lpc::QuantizedCoeffs {
+    let autocorr = lpc::autocorrelation(chunk, order);
+    let coeffs = lpc::levinson_durbin(&autocorr, order);
+    lpc::QuantizedCoeffs::quantize(&coeffs, lpc::qlp_precision(block_size))
+}
+
+/// Predictors tried for one block: the configured LPC order alone, or with
+/// `order_search` the fixed polynomial predictors and every LPC order up to it.
+fn candidate_predictors(chunk: &[i32], config: &Config) -> Vec<lpc::QuantizedCoeffs> {
+    let lpc_of_order = |order| lpc_predictor(chunk, order, config.block_size);
+    if !config.order_search {
+        return vec![lpc_of_order(config.order)];
+    }
+    let mut candidates: Vec<_> = (0..=lpc::MAX_FIXED_ORDER).map(lpc::fixed_predictor).collect();
+    candidates.extend((1..=config.order).map(lpc_of_order));
+    candidates
+}
+
+/// Samples of history a warm-up stream keeps per channel: enough for the longest
+/// LPC or fixed predictor the stream can use.
+fn history_len(order: usize) -> usize {
+    order.max(lpc::MAX_FIXED_ORDER)
+}
+
+fn push_history(history: &mut Vec<i32>, block: &[i32], keep: usize) {
+    history.extend_from_slice(block);
+    let excess = history.len().saturating_sub(keep);
+    history.drain(..excess);
+}
+
+/// What a block is coded against besides its own samples
+struct BlockContext<'a> {
+    /// Preceding samples of the channel; empty for independent blocks
+    history: &'a [i32],
+    /// Block flags byte, written only in warm-up streams
+    flags: Option<u8>,
+    /// Reference byte, written only in `FLAG_INTER_CHANNEL` streams
+    reference: Option<u8>,
+    /// Residuals of the referenced channel, subtracted from the block's own
+    reference_residuals: Option<&'a [i32]>,
+    /// Quantise residuals in the prediction loop (NearLossless streams)
+    near_lossless: Option<lpc::NearLossless>,
+    /// Elite: the channel's current residual table
+    residual_table: Option<&'a coder::FrequencyTable>,
+    /// `ResidualCoder::Adaptive`: the channel's probabilities so far; blocks
+    /// without them start from a fresh model
+    contexts: Option<&'a arith::ContextModel>,
+}
+
+/// An encoded block body, with the adaptive probabilities coding it left
+/// behind (`ResidualCoder::Adaptive` only)
+struct CodedBlock {
+    body: Vec<u8>,
+    contexts: Option<arith::ContextModel>,
+}
+
+type BlockEncoder = fn(&[i32], &BlockContext, &lpc::QuantizedCoeffs, &Config) -> Result<CodedBlock>;
+
+/// Encode `chunk` once per candidate predictor and keep the shortest block,
+/// along with the predictor it used.
+fn smallest_block(
+    chunk: &[i32],
+    context: &BlockContext,
+    config: &Config,
+    encode: BlockEncoder,
+) -> Result<(CodedBlock, lpc::QuantizedCoeffs)> {
+    let mut best: Option<(CodedBlock, lpc::QuantizedCoeffs)> = None;
+    for qlp in candidate_predictors(chunk, config) {
+        let block = encode(chunk, context, &qlp, config)?;
+        if best.as_ref().is_none_or(|(b, _)| block.body.len() < b.body.len()) {
+            best = Some((block, qlp));
+        }
+    }
+    Ok(best.expect("at least one candidate predictor"))
+}
+
+/// Prediction residuals of `signal` (quantisation indices in NearLossless blocks)
+/// and, for NearLossless blocks, the signal the decoder reconstructs
+fn predict(signal: &[i32], context: &BlockContext, qlp: &lpc::QuantizedCoeffs) -> (Vec<i32>, Option<Vec<i32>>) {
+    match context.near_lossless {
+        Some(near_lossless) => {
+            let (indices, decoded) = near_lossless.compute_residuals(context.history, signal, qlp);
+            (indices, Some(decoded))
+        }
+        None => (lpc::compute_residuals_continued(context.history, signal, qlp), None),
+    }
+}
+
+/// What a block stores: its prediction residuals less the referenced channel's
+fn coded_residuals(prediction: Vec<i32>, context: &BlockContext) -> Vec<i32> {
+    match context.reference_residuals {
+        Some(reference) => prediction.iter().zip(reference).map(|(&e, &r)| e.wrapping_sub(r)).collect(),
+        None => prediction,
+    }
+}
+
+fn encode_elite_block(
+    chunk: &[i32],
+    context: &BlockContext,
+    qlp: &lpc::QuantizedCoeffs,
+    _config: &Config,
+) -> Result<CodedBlock> {
+    let residuals = coded_residuals(predict(chunk, context, qlp).0, context);
+    let table = context.residual_table.expect("Elite blocks are coded against a residual table");
+    let compressed = coder::encode_residuals_rans(&residuals, table);
+
+    let mut out = Vec::new();
+    out.write_u32::<BigEndian>(chunk.len() as u32)?;
+    if let Some(flags) = context.flags {
+        out.write_u8(flags)?;
+    }
+    if let Some(reference) = context.reference {
+        out.write_u8(reference)?;
+    }
+    out.write_u8(qlp.coeffs.len() as u8)?;
+    qlp.write(&mut out)?;
+    // The rANS payload ends where its decoder stops reading, so it has no length
+    out.write_all(&compressed)?;
+    Ok(CodedBlock { body: out, contexts: None })
+}
+
+fn encode_lossless_block(
+    chunk: &[i32],
+    context: &BlockContext,
+    qlp: &lpc::QuantizedCoeffs,
+    config: &Config,
+) -> Result<CodedBlock> {
+    let residuals = coded_residuals(predict(chunk, context, qlp).0, context);
+    let mut contexts = None;
+    let mut out = Vec::new();
+    out.write_u32::<BigEndian>(chunk.len() as u32)?;
+    if let Some(flags) = context.flags {
+        out.write_u8(flags)?;
+    }
+    if let Some(reference) = context.reference {
+        out.write_u8(reference)?;
+    }
+    out.write_u8(qlp.coeffs.len() as u8)?;
+    let encoded_data = match config.residual_coder {
+        ResidualCoder::Rice => {
+            let mean_abs: f64 = residuals.iter().map(|x| x.abs() as f64).sum::<f64>() / residuals.len() as f64;
+            let k = (mean_abs.log2().max(0.0) as u32).min(15);
+            out.write_u8(k as u8)?;
+            coder::encode_rice(&residuals, k)?
+        }
+        ResidualCoder::Partitioned => coder::encode_rice_partitioned(&residuals, coder::MAX_PARTITION_ORDER)?,
+        ResidualCoder::Adaptive => {
+            let mut model = context.contexts.cloned().unwrap_or_default();
+            let encoded = arith::encode(&residuals, &mut model);
+            contexts = Some(model);
+            encoded
+        }
+    };
+    qlp.write(&mut out)?;
+    out.write_u32::<BigEndian>(encoded_data.len() as u32)?;
+    out.write_all(&encoded_data)?;
+    Ok(CodedBlock { body: out, contexts })
+}
+
+/// Samples a corrupt or missing block should have held, replaced by zeros
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Damage {
+    pub channel: u16,
+    /// Per-channel sample range, end exclusive
+    pub start: u64,
+    pub end: u64,
+}
+
+/// One channel block as stored, before prediction is undone
+struct RawBlock {
+    flags: u8,
+    reference: Reference,
+    predictor: Predictor,
+    residuals: Vec<i32>,
+}
+
+/// A block-CRC stream block whose checksum matched
+struct CheckedBlock {
+    frame: u32,
+    channel: u16,
+    body: Vec<u8>,
+}
+
+/// Reader with push-back, so resynchronisation can rescan the bytes of a
+/// candidate block it rejected.
+struct Rewindable<R> {
+    inner: R,
+    /// Pushed-back bytes, next byte last
+    replay: Vec<u8>,
+}
+
+impl<R: Read> Rewindable<R> {
+    fn unread(&mut self, bytes: &[u8]) {
+        self.replay.extend(bytes.iter().rev());
+    }
+
+    /// Up to `len` bytes; fewer only at end of stream
+    fn read_up_to(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(len);
+        self.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl<R: Read> Read for Rewindable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.replay.is_empty() {
+            return self.inner.read(buf);
+        }
+        let n = buf.len().min(self.replay.len());
+        for b in &mut buf[..n] {
+            *b = self.replay.pop().expect("replay holds n bytes");
+        }
+        Ok(n)
+    }
+}
+
+/// Incremental decoder: `read_block` returns one frame of interleaved samples at a
+/// time, so Lossless and Elite streams decode in memory proportional to a block.
+/// Events streams are a single payload per channel and come back as one frame.
+///
+/// Header problems surface as `container::FormatError` inside the returned error.
+/// In block-CRC streams, corrupt or missing blocks are zero-filled and listed in
+/// `damaged` instead of failing the decode.
+pub struct Decoder<R: Read> {
+    input: Rewindable<R>,
+    header: Header,
+    codebook: Option<Codebook>,
+    frames_read: u64,
+    history: Vec<Vec<i32>>,
+    /// Channels whose history was lost to damage, until their next independent block
+    desynced: Vec<bool>,
+    /// Entropy coder state per channel, mirroring the encoder's
+    models: Vec<ChannelModels>,
+    /// Blocks of the current frame so far, for inter-channel references; `None`
+    /// where a block was lost
+    frame: Vec<Option<FrameBlock>>,
+    /// Valid block read ahead of its slot while resynchronising
+    lookahead: Option<CheckedBlock>,
+    damaged: Vec<Damage>,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let header = Header::read(&mut input)?;
+        let channels = coded_channels(&header);
+        Ok(Self {
+            input: Rewindable { inner: input, replay: Vec::new() },
+            header,
+            codebook: None,
+            frames_read: 0,
+            history: vec![Vec::new(); channels],
+            desynced: vec![false; channels],
+            models: vec![ChannelModels::default(); channels],
+            frame: Vec::with_capacity(channels),
+            lookahead: None,
+            damaged: Vec::new(),
+        })
+    }
+
+    /// Codebook for events streams encoded with a trained codebook
+    pub fn with_codebook(mut self, codebook: Codebook) -> Self {
+        self.codebook = Some(codebook);
+        self
+    }
+
+    pub fn info(&self) -> &StreamInfo {
+        &self.header.info
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Sample ranges zero-filled so far because their blocks were corrupt or missing
+    pub fn damaged(&self) -> &[Damage] {
+        &self.damaged
+    }
+
+    fn warm_up(&self) -> bool {
+        self.header.flags & container::FLAG_WARM_UP != 0
+    }
+
+    fn near_lossless(&self) -> Option<lpc::NearLossless> {
+        (self.header.mode == Mode::NearLossless).then_some(lpc::NearLossless {
+            max_error: self.header.max_error as u32,
+            bits_per_sample: self.header.info.bits_per_sample as u32,
+        })
+    }
+
+    fn total_frames(&self) -> u64 {
+        self.header.info.total_samples / coded_channels(&self.header) as u64
+    }
+
+    /// Packetised events: `u32` length-prefixed `realtime` packets until a zero
+    /// length, rendered into silent channels of `frames` samples.
+    fn read_packets(
+        &mut self,
+        compressor: spike::SpikeCompressor,
+        channel_count: usize,
+        frames: usize,
+        length_parity: u8,
+    ) -> Result<Vec<Vec<i32>>> {
+        let decoder = PacketDecoder::new(compressor);
+        let mut block = vec![vec![0i32; frames]; channel_count];
+        loop {
+            let len = read_event_len(&mut self.input, length_parity)?;
+            if len == 0 {
+                return Ok(block);
+            }
+            let packet = decoder.decode(&read_payload(&mut self.input, len)?)?;
+            let Some(channel) = block.get_mut(packet.channel as usize) else {
+                anyhow::bail!("Packet for channel {} of a {}-channel stream", packet.channel, channel_count);
+            };
+            packet.render(channel);
+        }
+    }
+
+    /// Next frame of interleaved samples, or `None` once the stream is exhausted.
+    pub fn read_block(&mut self) -> Result<Option<Vec<i32>>> {
+        if self.frames_read >= self.total_frames() {
+            return Ok(None);
+        }
+        let channel_count = coded_channels(&self.header);
+        let mut block = Vec::with_capacity(channel_count);
+        match self.header.mode {
+            Mode::Lossless | Mode::Elite | Mode::NearLossless => {
+                self.frame.clear();
+                for channel in 0..channel_count {
+                    if self.header.flags & container::FLAG_BLOCK_CRC != 0 {
+                        block.push(self.read_checked_block(channel)?);
+                    } else {
+                        let raw = read_raw_block(&mut self.input, &self.header, None, Some(&mut self.models[channel]))?;
+                        block.push(self.restore_block(channel, raw)?);
+                    }
+                }
+                // As in the encoder, histories advance once the whole frame is in
+                if self.warm_up() {
+                    let keep = history_len(self.header.order as usize);
+                    for (history, samples) in self.history.iter_mut().zip(&block) {
+                        push_history(history, samples, keep);
+                    }
+                }
+            }
+            Mode::Hybrid => {
+                let params = hybrid::HybridParams::read(&mut self.input)?;
+                let frames = self.total_frames() as usize;
+                for channel in 0..channel_count {
+                    block.push(self.read_hybrid_channel(channel, &params, frames)?);
+                }
+            }
+            Mode::Events => {
+                let frames = self.total_frames() as usize;
+                let mut length_parity = 0;
+                let compressor = if self.header.version == 4 {
+                    spike::SpikeCompressor::new(4.0)
+                } else {
+                    let params = if self.header.flags & container::FLAG_EVENT_FEC != 0 {
+                        let params = spike::EventParams::read_protected(&mut self.input)?;
+                        length_parity = params.parity;
+                        params
+                    } else {
+                        spike::EventParams::read(&mut self.input)?
+                    };
+                    let codebook = self.select_codebook(&params)?;
+                    let compressor = spike::SpikeCompressor::from_params(&params, codebook)?;
+                    if params.packet_len > 0 {
+                        let block = self.read_packets(compressor, channel_count, frames, length_parity)?;
+                        self.frames_read += frames as u64;
+                        return Ok(Some(interleave(&block)));
+                    }
+                    compressor
+                };
+                // Legacy v4 streams are a bare payload running to the end of the file.
+                if self.header.version == 4 {
+                    let mut data = Vec::new();
+                    self.input.read_to_end(&mut data)?;
+                    block.push(compressor.decode(&data, frames)?);
+                } else {
+                    let tables = if self.header.flags & container::FLAG_EVENT_TABLES != 0 {
+                        Some(coder::RansTables::read(&mut self.input)?)
+                    } else {
+                        None
+                    };
+                    for _ in 0..channel_count {
+                        let data_len = read_event_len(&mut self.input, length_parity)?;
+                        let mut data = read_payload(&mut self.input, data_len)?;
+                        if let Some(tables) = &tables {
+                            data = coder::RansCoder::decode_shared(&data, 0, tables)?;
+                        }
+                        block.push(compressor.decode(&data, frames)?);
+                    }
+                }
+            }
+        }
+        self.frames_read += block[0].len() as u64;
+        Ok(Some(interleave(&block)))
+    }
+
+    /// The background of one Hybrid channel with its spike windows pasted in
+    fn read_hybrid_channel(&mut self, channel: usize, params: &hybrid::HybridParams, frames: usize) -> Result<Vec<i32>> {
+        let window_count = self.input.read_u32::<BigEndian>()?;
+        let mut windows = Vec::new();
+        let mut position = 0usize;
+        for _ in 0..window_count {
+            let start = position + self.input.read_u32::<BigEndian>()? as usize;
+            let raw = read_raw_block(&mut self.input, &self.header, None, None)?;
+            let samples = raw.predictor.restore(&[], &raw.residuals);
+            position = start + samples.len();
+            if position > frames {
+                anyhow::bail!("Spike window {}..{} beyond the {} samples of channel {}", start, position, frames, channel);
+            }
+            windows.push((start, samples));
+        }
+
+        let factor = params.factor as usize;
+        let mut output = match params.background {
+            hybrid::Background::None => vec![0; frames],
+            background => {
+                let count = self.input.read_u32::<BigEndian>()? as usize;
+                if count != frames.div_ceil(factor) {
+                    anyhow::bail!("{} background segments for {} samples of {}", count, frames, factor);
+                }
+                if background == hybrid::Background::Noise {
+                    let levels = (0..count).map(|_| self.input.read_u16::<BigEndian>()).collect::<std::io::Result<Vec<_>>>()?;
+                    hybrid::synthesize_noise(&levels, factor, frames, channel as u32)
+                } else {
+                    let mut means = Vec::with_capacity(count);
+                    while means.len() < count {
+                        let raw = read_raw_block(&mut self.input, &self.header, None, None)?;
+                        means.extend(raw.predictor.restore(&[], &raw.residuals));
+                    }
+                    if means.len() != count {
+                        anyhow::bail!("Background blocks hold {} segment means, expected {}", means.len(), count);
+                    }
+                    hybrid::interpolate(&means, factor, frames)
+                }
+            }
+        };
+        for (start, samples) in windows {
+            output[start..start + samples.len()].copy_from_slice(&samples);
+        }
+        Ok(output)
+    }
+
+    /// Undo prediction and any inter-channel reference, resetting the channel's
+    /// history at independent blocks. Fails if the block references a lost one.
+    fn restore_block(&mut self, channel: usize, raw: RawBlock) -> Result<Vec<i32>> {
+        if raw.flags & BLOCK_INDEPENDENT != 0 {
+            self.history[channel].clear();
+        }
+        let base = match raw.reference.channel(channel)? {
+            Some(reference) => match &self.frame[reference] {
+                Some(base) if base.samples.len() == raw.residuals.len() => Some((reference, base)),
+                Some(_) => anyhow::bail!("Channel {} references a block of another length", channel),
+                None => anyhow::bail!("Channel {} references lost channel {}", channel, reference),
+            },
+            None => None,
+        };
+        let mut near_lossless = self.near_lossless();
+        let (history, residuals) = match (raw.reference, base) {
+            (Reference::Samples(_), Some((reference, _))) => {
+                near_lossless = near_lossless.map(|q| lpc::NearLossless { bits_per_sample: q.bits_per_sample + 1, ..q });
+                (Cow::Owned(difference(&self.history[channel], &self.history[reference])), raw.residuals)
+            }
+            (Reference::Residuals(_), Some((_, base))) => {
+                let residuals = raw.residuals.iter().zip(&base.residuals).map(|(&r, &b)| r.wrapping_add(b)).collect();
+                (Cow::Borrowed(self.history[channel].as_slice()), residuals)
+            }
+            _ => (Cow::Borrowed(self.history[channel].as_slice()), raw.residuals),
+        };
+        let signal = match (&raw.predictor, near_lossless) {
+            (Predictor::Fixed(qlp), Some(near_lossless)) => near_lossless.restore_signal(&history, &residuals, qlp),
+            (predictor, _) => predictor.restore(&history, &residuals),
+        };
+        let samples = match (raw.reference, base, self.near_lossless()) {
+            (Reference::Samples(_), Some((_, base)), Some(quantizer)) => {
+                signal.iter().zip(&base.samples).map(|(&d, &b)| quantizer.clamp(d as i64 + b as i64)).collect()
+            }
+            (Reference::Samples(_), Some((_, base)), None) => {
+                signal.iter().zip(&base.samples).map(|(&d, &b)| d.wrapping_add(b)).collect()
+            }
+            _ => signal,
+        };
+        self.frame.push(Some(FrameBlock { samples: samples.clone(), residuals }));
+        Ok(samples)
+    }
+
+    /// The block for `channel` of the current frame in a block-CRC stream, or zeros
+    /// (recorded in `damaged`) if it is corrupt, missing or predicts from a lost block.
+    fn read_checked_block(&mut self, channel: usize) -> Result<Vec<i32>> {
+        let block_size = self.header.block_size.max(1) as u64;
+        let frame = self.frames_read / block_size;
+        let len = block_size.min(self.total_frames() - self.frames_read) as usize;
+        let slot = (frame, channel as u16);
+        loop {
+            let candidate = match self.lookahead.take() {
+                Some(block) => Some(block),
+                None => self.next_checked_block()?,
+            };
+            let Some(block) = candidate else {
+                return Ok(self.lose_block(channel, len));
+            };
+            match (block.frame as u64, block.channel).cmp(&slot) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Greater => {
+                    self.lookahead = Some(block);
+                    return Ok(self.lose_block(channel, len));
+                }
+                std::cmp::Ordering::Equal => {}
+            }
+            let model = Some(&mut self.models[channel]);
+            let raw = match read_raw_block(&mut block.body.as_slice(), &self.header, Some(len), model) {
+                Ok(raw) => raw,
+                Err(_) => return Ok(self.lose_block(channel, len)),
+            };
+            if raw.flags & BLOCK_INDEPENDENT != 0 {
+                self.desynced[channel] = false;
+            }
+            if self.desynced[channel] {
+                return Ok(self.lose_block(channel, len));
+            }
+            return match self.restore_block(channel, raw) {
+                Ok(samples) => Ok(samples),
+                Err(_) => Ok(self.lose_block(channel, len)),
+            };
+        }
+    }
+
+    /// Zero-fill a block and record the damage; warm-up blocks that follow cannot
+    /// be restored until the channel reaches an independent block.
+    fn lose_block(&mut self, channel: usize, len: usize) -> Vec<i32> {
+        let start = self.frames_read;
+        let end = start + len as u64;
+        match self.damaged.iter_mut().rev().find(|d| d.channel == channel as u16) {
+            Some(last) if last.end == start => last.end = end,
+            _ => self.damaged.push(Damage { channel: channel as u16, start, end }),
+        }
+        self.desynced[channel] = self.warm_up();
+        self.history[channel].clear();
+        self.frame.push(None);
+        vec![0; len]
+    }
+
+    /// Next block whose CRC matches, scanning forward for a sync word past any
+    /// damage. `None` at the end of the stream.
+    fn next_checked_block(&mut self) -> Result<Option<CheckedBlock>> {
+        let max_body = max_block_bytes(self.header.block_size as usize) as u32;
+        let mut window = [0u8; 2];
+        loop {
+            let mut byte = [0u8; 1];
+            if self.input.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            window = [window[1], byte[0]];
+            if window != BLOCK_SYNC {
+                continue;
+            }
+            window = [0; 2];
+            let fields = self.input.read_up_to(10)?;
+            if fields.len() < 10 {
+                self.input.unread(&fields);
+                continue;
+            }
+            let frame = u32::from_be_bytes(fields[0..4].try_into().expect("4 bytes"));
+            let channel = u16::from_be_bytes(fields[4..6].try_into().expect("2 bytes"));
+            let len = u32::from_be_bytes(fields[6..10].try_into().expect("4 bytes"));
+            if channel as usize >= self.header.info.channel_count() || len > max_body {
+                self.input.unread(&fields);
+                continue;
+            }
+            let rest = self.input.read_up_to(len as usize + 4)?;
+            if rest.len() < len as usize + 4 {
+                self.input.unread(&rest);
+                self.input.unread(&fields);
+                continue;
+            }
+            let (body, crc) = rest.split_at(len as usize);
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&fields);
+            hasher.update(body);
+            if hasher.finalize().to_be_bytes() != crc {
+                self.input.unread(&rest);
+                self.input.unread(&fields);
+                continue;
+            }
+            return Ok(Some(CheckedBlock { frame, channel, body: body.to_vec() }));
+        }
+    }
+
+    fn select_codebook(&self, params: &spike::EventParams) -> Result<Codebook> {
+        if let Some(codebook) = &self.codebook {
+            if codebook.id() == params.codebook_id {
+                return Ok(codebook.clone());
+            }
+        }
+        let synthetic = Codebook::synthetic(params.snippet_len as usize);
+        if synthetic.id() == params.codebook_id {
+            return Ok(synthetic);
+        }
+        anyhow::bail!(
+            "Stream was encoded with codebook {:08x}; supply that codebook to decode it",
+            params.codebook_id
+        )
+    }
+}
+
+/// Upper bound on a block body's size, to reject corrupt lengths before allocating
+fn max_block_bytes(block_size: usize) -> usize {
+    block_size * 16 + 1024
+}
+
+/// `len` bytes of a length-prefixed payload. The buffer grows only as bytes
+/// arrive, so a corrupt length fails at the end of the stream instead of
+/// allocating up front.
+fn read_payload<R: Read>(input: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    input.take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        anyhow::bail!("Stream ends {} bytes into a {}-byte payload", data.len(), len);
+    }
+    Ok(data)
+}
+
+/// A block's `u32` length-prefixed residual data, at most `max_block_bytes`
+fn read_block_data<R: Read>(input: &mut R, block_size: usize) -> Result<Vec<u8>> {
+    let len = input.read_u32::<BigEndian>()? as usize;
+    if len > max_block_bytes(block_size) {
+        anyhow::bail!("Block data of {} bytes for {} samples", len, block_size);
+    }
+    read_payload(input, len)
+}
+
+/// Parse one Lossless or Elite block. `expected_len`, when known, must match the
+/// stored block length. Elite blocks of `FLAG_ELITE_RANS` streams and Adaptive
+/// blocks are decoded with, and then added to, the channel's `model`; Adaptive
+/// blocks without one (Hybrid windows) start from a fresh model.
+fn read_raw_block<R: Read>(
+    input: &mut R,
+    header: &Header,
+    expected_len: Option<usize>,
+    mut model: Option<&mut ChannelModels>,
+) -> Result<RawBlock> {
+    let block_size = input.read_u32::<BigEndian>()? as usize;
+    if expected_len.is_some_and(|len| len != block_size) {
+        anyhow::bail!("Block holds {} samples, expected {}", block_size, expected_len.unwrap_or(0));
+    }
+    if block_size as u64 > header.info.total_samples {
+        anyhow::bail!("Block of {} samples in a stream of {}", block_size, header.info.total_samples);
+    }
+    let flags = if header.flags & container::FLAG_WARM_UP != 0 {
+        let flags = input.read_u8()?;
+        if flags & !BLOCK_INDEPENDENT != 0 {
+            anyhow::bail!("Unknown block flags {:#04x}", flags);
+        }
+        flags
+    } else {
+        BLOCK_INDEPENDENT
+    };
+    let reference = if header.flags & container::FLAG_INTER_CHANNEL != 0 {
+        Reference::from_code(input.read_u8()?)?
+    } else {
+        Reference::None
+    };
+    let order = input.read_u8()? as usize;
+    let float_coeffs = header.float_coefficients();
+    if flags & BLOCK_INDEPENDENT != 0 {
+        if let Some(model) = model.as_deref_mut() {
+            model.reset();
+        }
+    }
+    let (predictor, residuals) = match header.mode {
+        Mode::Elite if header.flags & container::FLAG_ELITE_RANS != 0 => {
+            let Some(model) = model else {
+                anyhow::bail!("Elite block outside an Elite channel");
+            };
+            let predictor = read_predictor(input, float_coeffs, order)?;
+            let residuals = coder::decode_residuals_rans(input, block_size, &model.residuals.table())?;
+            model.residuals.update(&residuals);
+            (predictor, residuals)
+        }
+        Mode::Elite => {
+            let predictor = read_predictor(input, float_coeffs, order)?;
+            let compressed = read_block_data(input, block_size)?;
+
+            let serialized = coder::RansCoder::decode(&compressed, 0)?; // Count is embedded
+            let sparse_data = simd_ops::SimdOps::deserialize(&serialized);
+            let mut residuals = sparse::SparseEncoder::decode(&sparse_data);
+            residuals.resize(block_size, 0);
+            (predictor, residuals)
+        }
+        Mode::Lossless | Mode::Hybrid | Mode::NearLossless => {
+            let k = match header.residual_coder {
+                ResidualCoder::Rice => Some(input.read_u8()? as u32),
+                ResidualCoder::Partitioned | ResidualCoder::Adaptive => None,
+            };
+            let predictor = read_predictor(input, float_coeffs, order)?;
+            let encoded_data = read_block_data(input, block_size)?;
+            let residuals = match (header.residual_coder, k) {
+                (ResidualCoder::Adaptive, _) => match model {
+                    Some(model) => arith::decode(&encoded_data, block_size, &mut model.contexts)?,
+                    None => arith::decode(&encoded_data, block_size, &mut arith::ContextModel::default())?,
+                },
+                (_, Some(k)) => coder::decode_rice(&encoded_data, block_size, k)?,
+                (_, None) => coder::decode_rice_partitioned(&encoded_data, block_size)?,
+            };
+            (predictor, residuals)
+        }
+        Mode::Events => unreachable!("events streams have no blocks"),
+    };
+    Ok(RawBlock { flags, reference, predictor, residuals })
+}
+
+/// Merge per-channel series back into interleaved frame order
+fn interleave(channels: &[Vec<i32>]) -> Vec<i32> {
+    let frames = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+    let mut out = Vec::with_capacity(frames * channels.len());
+    for i in 0..frames {
+        for channel in channels {
+            if let Some(&s) = channel.get(i) {
+                out.push(s);
+            }
+        }
+    }
+    out
+}
+
+impl<R: Read + Seek> Decoder<R> {
+    /// Position the decoder so the next `read_block` returns the frame holding
+    /// `sample` (a per-channel index), or an earlier one. Returns the first sample
+    /// of that frame. Streams without a seek table can only be skipped forward.
+    pub fn seek(&mut self, sample: u64) -> Result<u64> {
+        if self.header.flags & container::FLAG_SEEK_TABLE == 0 {
+            if sample < self.frames_read {
+                anyhow::bail!("Stream has no seek table; cannot seek backwards");
+            }
+            return Ok(self.frames_read);
+        }
+        let table = self.read_seek_table()?;
+        let Some(point) = table.lookup(sample) else {
+            anyhow::bail!("Seek table has no entry before sample {}", sample);
+        };
+        self.input.inner.seek(SeekFrom::Start(point.offset))?;
+        self.input.replay.clear();
+        self.lookahead = None;
+        self.frames_read = point.sample;
+        for history in &mut self.history {
+            history.clear();
+        }
+        self.desynced.fill(false);
+        Ok(point.sample)
+    }
+
+    /// Interleaved samples `start..end` (per-channel indices, clamped to the
+    /// stream), decoding only the frames that overlap the range.
+    pub fn read_range(&mut self, start: u64, end: u64) -> Result<Vec<i32>> {
+        // Legacy streams count positions in interleaved samples, since their blocks need not hold whole frames
+        let channels = coded_channels(&self.header);
+        let scale = (self.header.info.channel_count() / channels) as u64;
+        let (start, end) = (start * scale, end.saturating_mul(scale));
+        let end = end.min(self.total_frames());
+        let mut position = self.seek(start)?;
+        let mut samples = Vec::with_capacity(end.saturating_sub(start) as usize * channels);
+        while position < end {
+            let Some(block) = self.read_block()? else { break };
+            let frames = (block.len() / channels) as u64;
+            let from = start.saturating_sub(position).min(frames) as usize;
+            let to = (end - position).min(frames) as usize;
+            samples.extend_from_slice(&block[from * channels..to * channels]);
+            position += frames;
+        }
+        Ok(samples)
+    }
+
+    /// The trailer ends with the table's offset; the read position is restored.
+    fn read_seek_table(&mut self) -> Result<SeekTable> {
+        let input = &mut self.input.inner;
+        let resume = input.stream_position()?;
+        input.seek(SeekFrom::End(-8))?;
+        let offset = input.read_u64::<BigEndian>()?;
+        input.seek(SeekFrom::Start(offset))?;
+        let table = SeekTable::read(input)?;
+        input.seek(SeekFrom::Start(resume))?;
+        Ok(table)
+    }
+}
+
+/// Events payload or packet length: a `u32` under `parity` Reed–Solomon check bytes
+fn write_event_len<W: Write>(out: &mut W, len: usize, parity: u8) -> Result<()> {
+    out.write_all(&fec::encode(&(len as u32).to_be_bytes(), parity))?;
+    Ok(())
+}
+
+fn read_event_len<R: Read>(input: &mut R, parity: u8) -> Result<usize> {
+    let mut coded = vec![0u8; 4 + parity as usize];
+    input.read_exact(&mut coded)?;
+    let (len, _corrected) = fec::decode(&coded, parity)?;
+    Ok(u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+}
+
+/// Channels coded as separate block sequences. Legacy streams code their
+/// interleaved samples as a single sequence, whatever their channel count.
+fn coded_channels(header: &Header) -> usize {
+    if header.version == container::CURRENT_VERSION {
+        header.info.channel_count()
+    } else {
+        1
+    }
+}
+
+/// Block predictor as stored on disk: raw f64 (v1) or quantized integer (v7)
+enum Predictor {
+    Float(Vec<f64>),
+    Fixed(lpc::QuantizedCoeffs),
+}
+
+impl Predictor {
+    /// `history` is only ever non-empty for integer predictors: legacy float
+    /// streams predate warm-up.
+    fn restore(&self, history: &[i32], residuals: &[i32]) -> Vec<i32> {
+        match self {
+            Predictor::Float(coeffs) => lpc::restore_signal(residuals, coeffs),
+            Predictor::Fixed(qlp) => lpc::restore_signal_continued(history, residuals, qlp),
+        }
+    }
+}
+
+fn read_predictor<R: Read>(in_file: &mut R, float_coeffs: bool, order: usize) -> Result<Predictor> {
+    if float_coeffs {
+        let mut coeffs = Vec::with_capacity(order);
+        for _ in 0..order { coeffs.push(in_file.read_f64::<BigEndian>()?); }
+        Ok(Predictor::Float(coeffs))
+    } else {
+        Ok(Predictor::Fixed(lpc::QuantizedCoeffs::read(in_file, order)?))
+    }
+}
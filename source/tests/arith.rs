@@ -0,0 +1,94 @@
+use neuralink_compressor::arith::{decode, encode, ContextModel};
+use neuralink_compressor::{compress, decode_range, decompress, Config, Mode, ResidualCoder};
+
+mod common;
+use common::lcg;
+
+/// Quiet noise with a burst of large values every 300 samples
+fn bursty(len: usize, seed: u32) -> Vec<i16> {
+    let noise = lcg(len, seed, 6);
+    (0..len)
+        .map(|i| {
+            let burst = if i % 300 < 12 { [-6000, 4000, -2500][i % 3] } else { 0 };
+            (burst + noise[i]) as i16
+        })
+        .collect()
+}
+
+fn adaptive(mode: Mode) -> Config {
+    Config::default().with_mode(mode).with_residual_coder(ResidualCoder::Adaptive)
+}
+
+#[test]
+fn adaptive_roundtrip() {
+    let cases = vec![
+        Vec::new(),
+        vec![0; 50],
+        vec![1, -1, 2, -2],
+        vec![i32::MIN, i32::MAX, 0, i32::MIN + 1, -1, 1 << 30],
+        lcg(1000, 1, 5),
+        lcg(4099, 2, 70_000),
+        lcg(300, 3, i32::MAX),
+    ];
+    for residuals in &cases {
+        let mut model = ContextModel::default();
+        let encoded = encode(residuals, &mut model);
+        assert_eq!(decode(&encoded, residuals.len(), &mut ContextModel::default()).unwrap(), *residuals);
+    }
+
+    // Models carried across calls stay in step on both sides
+    let (mut encoder, mut decoder) = (ContextModel::default(), ContextModel::default());
+    for residuals in &cases {
+        let encoded = encode(residuals, &mut encoder);
+        assert_eq!(decode(&encoded, residuals.len(), &mut decoder).unwrap(), *residuals);
+    }
+    let tail = lcg(500, 4, 40);
+    assert_eq!(encode(&tail, &mut decoder), encode(&tail, &mut encoder));
+}
+
+#[test]
+fn truncated_adaptive_payload_fails() {
+    let residuals = lcg(2000, 8, 300);
+    let encoded = encode(&residuals, &mut ContextModel::default());
+    for len in [0, 1, encoded.len() / 2, encoded.len() - 1] {
+        assert!(decode(&encoded[..len], residuals.len(), &mut ContextModel::default()).is_err(), "{} bytes", len);
+    }
+    let mut padded = encoded.clone();
+    padded.push(0);
+    assert!(decode(&padded, residuals.len(), &mut ContextModel::default()).is_err());
+}
+
+#[test]
+fn adaptive_streams_roundtrip() {
+    let left = bursty(7000, 5);
+    let right = bursty(7000, 6);
+    let stereo: Vec<i16> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+    for seek_interval in [0, 1, 64] {
+        for block_crc in [false, true] {
+            let config = adaptive(Mode::Lossless)
+                .with_channels(2)
+                .with_seek_interval(seek_interval)
+                .with_block_crc(block_crc)
+                .with_order_search(true);
+            let encoded = compress(&stereo, config).expect("compress");
+            assert_eq!(decompress(&encoded).expect("decompress").samples, stereo);
+            let range = decode_range(&encoded, 2500, 4100).expect("range");
+            assert_eq!(range.samples, stereo[5000..8200]);
+        }
+    }
+
+    let near = compress(&left, adaptive(Mode::NearLossless).with_max_error(2)).expect("compress");
+    let decoded = decompress(&near).expect("decompress").samples;
+    assert!(left.iter().zip(&decoded).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 2));
+
+    let hybrid = compress(&left, adaptive(Mode::Hybrid).with_threshold(4.0)).expect("compress");
+    assert_eq!(decompress(&hybrid).expect("decompress").samples.len(), left.len());
+}
+
+#[test]
+fn adaptive_beats_partitioned_rice_on_bursts() {
+    let samples = bursty(20_000, 7);
+    let rice = compress(&samples, Config::default()).unwrap().len();
+    let adaptive = compress(&samples, adaptive(Mode::Lossless)).unwrap().len();
+    assert!(adaptive < rice, "{} vs {}", adaptive, rice);
+}
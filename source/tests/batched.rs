@@ -17,6 +17,20 @@ fn write_wav(path: &Path, samples: &[i16], sample_rate: u32) {
     writer.finalize().expect("finalize wav");
 }
 
+fn write_wav_interleaved(path: &Path, samples: &[i16], channels: u16, sample_rate: u32) {
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec).expect("create wav");
+    for &s in samples {
+        writer.write_sample(s).expect("write sample");
+    }
+    writer.finalize().expect("finalize wav");
+}
+
 fn read_wav_samples(path: &Path) -> Vec<i16> {
     let mut reader = hound::WavReader::open(path).expect("open wav");
     reader.samples::<i16>().map(|s| s.expect("read sample")).collect()
@@ -55,6 +69,27 @@ fn sparse_spikes(len: usize) -> Vec<i16> {
     out
 }
 
+/// Interleave one distinct signal per channel so a channel mix-up cannot go unnoticed
+fn multichannel_signal(frames: usize, channels: usize, sample_rate: u32) -> Vec<i16> {
+    let noise = lcg_noise(frames);
+    let per_channel: Vec<Vec<i16>> = (0..channels)
+        .map(|ch| {
+            let sine = sine_wave(frames, 5.0 + 3.0 * ch as f32, sample_rate);
+            sine.iter()
+                .zip(noise.iter())
+                .map(|(&s, &n)| (s / 2).saturating_add(n / 64).saturating_add(ch as i16 * 100))
+                .collect()
+        })
+        .collect();
+    let mut out = Vec::with_capacity(frames * channels);
+    for i in 0..frames {
+        for channel in &per_channel {
+            out.push(channel[i]);
+        }
+    }
+    out
+}
+
 fn temp_dir() -> PathBuf {
     let mut dir = std::env::temp_dir();
     let nanos = SystemTime::now()
@@ -91,6 +126,14 @@ fn run_cli(args: &[&str]) {
     assert!(status.success(), "cli failed for args: {:?}", args);
 }
 
+fn cli_succeeds(args: &[&str]) -> bool {
+    Command::new(find_exe())
+        .args(args)
+        .status()
+        .expect("run cli")
+        .success()
+}
+
 #[test]
 fn lossless_roundtrip_batched() {
     let dir = temp_dir();
@@ -167,3 +210,234 @@ fn events_roundtrip_batched() {
     }
     let _ = fs::remove_dir_all(&dir);
 }
+
+#[test]
+fn multichannel_roundtrip_batched() {
+    let dir = temp_dir();
+    let sample_rate = 1000;
+    for channels in [2u16, 5u16] {
+        // Odd frame count leaves a short trailing block in every channel.
+        let samples = multichannel_signal(1001, channels as usize, sample_rate);
+        let in_path = dir.join(format!("mc{}_in.wav", channels));
+        write_wav_interleaved(&in_path, &samples, channels, sample_rate);
+        for mode in ["lossless", "elite"] {
+            let out_path = dir.join(format!("mc{}_{}.neur", channels, mode));
+            let recon_path = dir.join(format!("mc{}_{}.wav", channels, mode));
+            run_cli(&[
+                "encode",
+                in_path.to_str().unwrap(),
+                out_path.to_str().unwrap(),
+                "--mode",
+                mode,
+                "--block-size",
+                "64",
+            ]);
+            run_cli(&[
+                "decode",
+                out_path.to_str().unwrap(),
+                recon_path.to_str().unwrap(),
+            ]);
+            let recon = read_wav_samples(&recon_path);
+            assert_eq!(samples, recon, "{} mismatch for {} channels", mode, channels);
+        }
+
+        let out_path = dir.join(format!("mc{}_events.neur", channels));
+        let recon_path = dir.join(format!("mc{}_events.wav", channels));
+        run_cli(&[
+            "encode",
+            in_path.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+            "--mode",
+            "events",
+            "--threshold",
+            "1.5",
+        ]);
+        run_cli(&[
+            "decode",
+            out_path.to_str().unwrap(),
+            recon_path.to_str().unwrap(),
+        ]);
+        let reader = hound::WavReader::open(&recon_path).expect("open wav");
+        assert_eq!(reader.spec().channels, channels);
+        assert_eq!(read_wav_samples(&recon_path).len(), samples.len());
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn square_wave(len: usize, period: usize) -> Vec<i16> {
+    (0..len)
+        .map(|i| if (i / period).is_multiple_of(2) { i16::MAX } else { i16::MIN })
+        .collect()
+}
+
+#[test]
+fn integer_lpc_full_scale_roundtrip() {
+    let dir = temp_dir();
+    let samples = square_wave(2048, 7);
+    let in_path = dir.join("square_in.wav");
+    write_wav(&in_path, &samples, 1000);
+    for (mode, mode_byte) in [("lossless", 0u8), ("elite", 2u8)] {
+        let out_path = dir.join(format!("square_{}.neur", mode));
+        let recon_path = dir.join(format!("square_{}.wav", mode));
+        run_cli(&[
+            "encode",
+            in_path.to_str().unwrap(),
+            out_path.to_str().unwrap(),
+            "--mode",
+            mode,
+            "--order",
+            "12",
+            "--block-size",
+            "256",
+        ]);
+        let encoded = fs::read(&out_path).expect("read encoded");
+        assert_eq!(&encoded[0..4], b"NEUR");
+        assert_eq!(u32::from_be_bytes(encoded[4..8].try_into().unwrap()), 7);
+        assert_eq!(encoded[10], mode_byte);
+        run_cli(&[
+            "decode",
+            out_path.to_str().unwrap(),
+            recon_path.to_str().unwrap(),
+        ]);
+        assert_eq!(samples, read_wav_samples(&recon_path), "{} mismatch", mode);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn legacy_v1_float_lpc_still_decodes() {
+    let dir = temp_dir();
+    let mut file = Vec::new();
+    file.extend_from_slice(b"NEUR");
+    file.extend_from_slice(&1u32.to_be_bytes()); // version
+    file.extend_from_slice(&1000u32.to_be_bytes()); // sample rate
+    file.extend_from_slice(&1u16.to_be_bytes()); // channels
+    file.extend_from_slice(&16u16.to_be_bytes()); // bits per sample
+    file.extend_from_slice(&8u64.to_be_bytes()); // total samples
+    // One block: 8 samples, order 1 with a = -1.0 (predict previous sample), k = 0
+    file.extend_from_slice(&8u32.to_be_bytes());
+    file.push(1);
+    file.push(0);
+    file.extend_from_slice(&(-1.0f64).to_be_bytes());
+    // Residuals [1, 0, 0, 0, 0, 0, 0, 0] -> zigzag unary "110" + seven "0" bits
+    file.extend_from_slice(&2u32.to_be_bytes());
+    file.extend_from_slice(&[0b1100_0000, 0b0000_0000]);
+
+    let in_path = dir.join("legacy_v1.neur");
+    let recon_path = dir.join("legacy_v1.wav");
+    fs::write(&in_path, &file).expect("write legacy file");
+    run_cli(&[
+        "decode",
+        in_path.to_str().unwrap(),
+        recon_path.to_str().unwrap(),
+    ]);
+    assert_eq!(read_wav_samples(&recon_path), vec![1i16; 8]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn trained_codebook_cli_roundtrip() {
+    let dir = temp_dir();
+    let sample_rate = 1000;
+    let samples = sparse_spikes(4096);
+    let in_path = dir.join("corpus.wav");
+    write_wav(&in_path, &samples, sample_rate);
+    let codebook_path = dir.join("spikes.ncb");
+    run_cli(&[
+        "train-codebook",
+        codebook_path.to_str().unwrap(),
+        in_path.to_str().unwrap(),
+        "--size",
+        "8",
+        "--threshold",
+        "4.0",
+        "--noise",
+        "windowed-mad",
+        "--noise-window",
+        "1024",
+    ]);
+    assert!(codebook_path.exists(), "codebook not written");
+
+    let out_path = dir.join("trained.neur");
+    let recon_path = dir.join("trained.wav");
+    run_cli(&[
+        "encode",
+        in_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "--mode",
+        "events",
+        "--threshold",
+        "4.0",
+        "--noise",
+        "windowed-mad",
+        "--noise-window",
+        "1024",
+        "--codebook",
+        codebook_path.to_str().unwrap(),
+    ]);
+    assert!(
+        !cli_succeeds(&["decode", out_path.to_str().unwrap(), recon_path.to_str().unwrap()]),
+        "decode without the codebook must fail"
+    );
+    run_cli(&[
+        "decode",
+        out_path.to_str().unwrap(),
+        recon_path.to_str().unwrap(),
+        "--codebook",
+        codebook_path.to_str().unwrap(),
+    ]);
+    assert_eq!(read_wav_samples(&recon_path).len(), samples.len());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn decode_time_window_cli() {
+    let dir = temp_dir();
+    let sample_rate = 1000;
+    let channels = 2;
+    let samples = multichannel_signal(5000, channels, sample_rate);
+    let in_path = dir.join("window_in.wav");
+    write_wav_interleaved(&in_path, &samples, channels as u16, sample_rate);
+    let out_path = dir.join("window.neur");
+    let recon_path = dir.join("window.wav");
+    run_cli(&[
+        "encode",
+        in_path.to_str().unwrap(),
+        out_path.to_str().unwrap(),
+        "--mode",
+        "lossless",
+        "--seek-interval",
+        "8",
+    ]);
+    run_cli(&[
+        "decode",
+        out_path.to_str().unwrap(),
+        recon_path.to_str().unwrap(),
+        "--start",
+        "1.25",
+        "--end",
+        "3.5",
+    ]);
+    let recon = read_wav_samples(&recon_path);
+    assert_eq!(recon, samples[1250 * channels..3500 * channels]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn truncated_wav_fails_to_encode() {
+    let dir = temp_dir();
+    let in_path = dir.join("truncated.wav");
+    write_wav(&in_path, &sine_wave(1000, 7.0, 1000), 1000);
+    // The header still declares 1000 samples, but the last 100 are gone
+    let wav = fs::read(&in_path).expect("read wav");
+    fs::write(&in_path, &wav[..wav.len() - 200]).expect("truncate wav");
+    for mode in ["lossless", "events"] {
+        let out_path = dir.join(format!("truncated.{}.neur", mode));
+        assert!(
+            !cli_succeeds(&["encode", in_path.to_str().unwrap(), out_path.to_str().unwrap(), "--mode", mode]),
+            "{} encode of a truncated WAV must fail",
+            mode
+        );
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,91 @@
+use neuralink_compressor::spike::SpikeCompressor;
+use neuralink_compressor::{encode_recording, Codebook, Decoder, Config, Mode, StreamInfo};
+
+/// Two spike shapes of varying amplitude on a quiet, slightly noisy baseline
+fn two_unit_recording(len: usize) -> Vec<i32> {
+    let mut out = vec![0i32; len];
+    let mut state: u32 = 42;
+    for v in out.iter_mut() {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        *v = ((state >> 24) as i32 - 128) / 8;
+    }
+    let unit_a = [0, -300, -1800, -4200, -2500, -600, 500, 900, 700, 300, 100, 0];
+    let unit_b = [0, 800, 2600, 3100, 1200, -400, -900, -500, -200, 0, 0, 0];
+    let mut i = 100;
+    let mut n = 0;
+    while i + 16 < len {
+        let (shape, gain) = if n % 2 == 0 { (&unit_a, 1.0) } else { (&unit_b, 0.8 + (n % 5) as f32 * 0.1) };
+        for (j, &s) in shape.iter().enumerate() {
+            out[i + j] += (s as f32 * gain) as i32;
+        }
+        i += 173 + (n % 7) * 11;
+        n += 1;
+    }
+    out
+}
+
+fn sq_error(a: &[i32], b: &[i32]) -> f64 {
+    a.iter().zip(b).map(|(&x, &y)| ((x - y) as f64).powi(2)).sum()
+}
+
+fn encode_events(samples: &[i32], codebook: Option<Codebook>) -> Vec<u8> {
+    let info = StreamInfo { sample_rate: 20_000, channels: 1, bits_per_sample: 16, total_samples: samples.len() as u64 };
+    let config = Config::default().with_mode(Mode::Events).with_threshold(4.0);
+    encode_recording(Vec::new(), info, config, codebook, samples).expect("encode")
+}
+
+fn decode_events(data: &[u8], codebook: Option<Codebook>) -> anyhow::Result<Vec<i32>> {
+    let mut decoder = Decoder::new(data)?;
+    if let Some(codebook) = codebook {
+        decoder = decoder.with_codebook(codebook);
+    }
+    let mut out = Vec::new();
+    while let Some(block) = decoder.read_block()? {
+        out.extend(block);
+    }
+    Ok(out)
+}
+
+#[test]
+fn codebook_file_roundtrip_preserves_id() {
+    let codebook = Codebook::synthetic(16);
+    let mut bytes = Vec::new();
+    codebook.write(&mut bytes).expect("write");
+    let read = Codebook::read(&mut bytes.as_slice()).expect("read");
+    assert_eq!(read, codebook);
+    assert_eq!(read.id(), codebook.id());
+    assert!(Codebook::read(&mut &b"NOPE"[..]).is_err());
+}
+
+#[test]
+fn trained_codebook_beats_synthetic() {
+    let samples = two_unit_recording(40_000);
+    let snippets = SpikeCompressor::new(4.0).snippets(&samples);
+    assert!(snippets.len() > 100, "expected plenty of training spikes");
+    let trained = Codebook::train(&snippets, 16, 20).expect("train");
+    assert_eq!(trained.templates.len(), 16);
+    assert_eq!(trained.snippet_len, 16);
+    assert_ne!(trained.id(), Codebook::synthetic(16).id());
+
+    let synthetic_recon = decode_events(&encode_events(&samples, None), None).expect("decode synthetic");
+    let encoded = encode_events(&samples, Some(trained.clone()));
+    let trained_recon = decode_events(&encoded, Some(trained)).expect("decode trained");
+    assert_eq!(trained_recon.len(), samples.len());
+    assert!(sq_error(&samples, &trained_recon) < sq_error(&samples, &synthetic_recon));
+}
+
+#[test]
+fn decoding_requires_matching_codebook() {
+    let samples = two_unit_recording(10_000);
+    let snippets = SpikeCompressor::new(4.0).snippets(&samples);
+    let trained = Codebook::train(&snippets, 8, 10).expect("train");
+    let other = Codebook::train(&snippets, 4, 10).expect("train");
+    let encoded = encode_events(&samples, Some(trained));
+    assert!(decode_events(&encoded, None).is_err());
+    assert!(decode_events(&encoded, Some(other)).is_err());
+}
+
+#[test]
+fn training_rejects_empty_corpus() {
+    assert!(Codebook::train(&[], 16, 10).is_err());
+}
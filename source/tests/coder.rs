@@ -0,0 +1,377 @@
+use neuralink_compressor::coder::{
+    decode_residuals_rans, decode_rice, decode_rice_partitioned, encode_residuals_rans, encode_rice,
+    encode_rice_partitioned, RansCoder, RansTable, RansTables, ResidualModel, MAX_PARTITION_ORDER, MAX_RANS_TABLES,
+    RAW_TABLE_ID, RANS_LANES,
+};
+use neuralink_compressor::container::{FLAG_ELITE_RANS, FLAG_WARM_UP};
+use neuralink_compressor::lpc::fixed_predictor;
+use neuralink_compressor::simd_ops::SimdOps;
+use neuralink_compressor::sparse::SparseEncoder;
+use neuralink_compressor::{compress, decompress, Config, FormatError, Header, Mode, ResidualCoder, StreamInfo};
+
+mod common;
+use common::lcg;
+
+#[test]
+fn partitioned_rice_roundtrip() {
+    let mut cases = vec![
+        Vec::new(),
+        vec![0; 37],
+        vec![-1],
+        lcg(32, 1, 50),
+        lcg(1000, 2, 3),
+        lcg(4099, 3, 70_000),
+        vec![i16::MIN as i32 * 2, i16::MAX as i32 * 2, 0, 1, -1],
+    ];
+    // Quiet start, loud burst: partitions should pick very different parameters.
+    let mut bursty = lcg(512, 4, 4);
+    bursty.extend(lcg(64, 5, 40_000));
+    bursty.extend(lcg(512, 6, 4));
+    cases.push(bursty);
+
+    for residuals in cases {
+        for max_order in [0, 3, MAX_PARTITION_ORDER] {
+            let encoded = encode_rice_partitioned(&residuals, max_order).expect("encode");
+            let decoded = decode_rice_partitioned(&encoded, residuals.len()).expect("decode");
+            assert_eq!(decoded, residuals, "max order {}", max_order);
+        }
+    }
+}
+
+#[test]
+fn escape_bounds_cost_of_outliers() {
+    let mut residuals = lcg(256, 7, 8);
+    residuals[100] = 60_000;
+    let single_k = encode_rice(&residuals, 2).expect("rice");
+    assert_eq!(decode_rice(&single_k, residuals.len(), 2).expect("rice decode"), residuals);
+    let partitioned = encode_rice_partitioned(&residuals, MAX_PARTITION_ORDER).expect("partitioned");
+    // A single outlier costs ~15000 unary bits with k = 2; escapes keep it to a raw word.
+    assert!(partitioned.len() * 10 < single_k.len(), "{} vs {}", partitioned.len(), single_k.len());
+}
+
+#[test]
+fn lossless_records_residual_coder() {
+    let samples: Vec<i16> = lcg(3000, 9, 2000).into_iter().map(|v| v as i16).collect();
+    for coder in [ResidualCoder::Rice, ResidualCoder::Partitioned] {
+        let config = Config::default().with_residual_coder(coder).with_block_size(256);
+        let encoded = compress(&samples, config).expect("compress");
+        let header = Header::read(&mut encoded.as_slice()).expect("header");
+        assert_eq!(header.residual_coder, coder);
+        assert_eq!(decompress(&encoded).expect("decompress").samples, samples);
+    }
+}
+
+/// Near-Gaussian noise on a slow wave, with a spike every 397 samples
+fn recording(len: usize, seed: u32) -> Vec<i16> {
+    let mut state = seed;
+    (0..len)
+        .map(|i| {
+            let mut noise = 0;
+            for _ in 0..2 {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                noise += ((state >> 16) % 61) as i32 - 30;
+            }
+            let lfp = ((i as f32) * 0.005).sin() * 3000.0;
+            let spike = if i % 397 < 5 { -4000 } else { 0 };
+            (lfp as i32 + noise + spike) as i16
+        })
+        .collect()
+}
+
+#[test]
+fn residual_rans_roundtrip() {
+    let mut model = ResidualModel::default();
+    let cases = vec![
+        Vec::new(),
+        vec![0; 37],
+        lcg(500, 11, 40),
+        lcg(300, 12, 70_000),
+        vec![i32::MIN, i32::MAX, -1, 0, 15, 16, -17, 1 << 20],
+    ];
+    for residuals in cases {
+        let table = model.table();
+        let mut encoded = encode_residuals_rans(&residuals, &table);
+        // The decoder stops at the end of its own bytes
+        encoded.extend_from_slice(b"tail");
+        let mut input = encoded.as_slice();
+        assert_eq!(decode_residuals_rans(&mut input, residuals.len(), &table).expect("decode"), residuals);
+        assert_eq!(input, b"tail");
+        model.update(&residuals);
+    }
+
+    // Statistics from earlier blocks make later ones cheaper
+    let block = lcg(256, 13, 20);
+    let fresh = encode_residuals_rans(&block, &ResidualModel::default().table()).len();
+    let mut trained = ResidualModel::default();
+    trained.update(&lcg(1024, 14, 20));
+    let shared = encode_residuals_rans(&block, &trained.table()).len();
+    assert!(shared * 10 < fresh * 8, "{} vs {}", shared, fresh);
+}
+
+#[test]
+fn elite_beats_lossless() {
+    let samples = recording(16_000, 5);
+    for block_size in [32, 512] {
+        for order_search in [false, true] {
+            let lossless = Config::default().with_block_size(block_size).with_order_search(order_search);
+            let elite = lossless.with_mode(Mode::Elite);
+            let lossless = compress(&samples, lossless).expect("compress").len();
+            let encoded = compress(&samples, elite).expect("compress");
+            assert!(encoded.len() < lossless, "block {} search {}: {} vs {}", block_size, order_search, encoded.len(), lossless);
+            let header = Header::read(&mut encoded.as_slice()).expect("header");
+            assert_ne!(header.flags & FLAG_ELITE_RANS, 0);
+            assert_eq!(decompress(&encoded).expect("decompress").samples, samples);
+        }
+    }
+}
+
+/// The byte rANS coder as first written, renormalising against `1 << l_bits`
+/// and optionally emitting its bytes in decoding order; with (24, true) it is
+/// `RansCoder::encode`
+fn byte_rans(data: &[u8], l_bits: u32, reverse: bool) -> Vec<u8> {
+    let freq = normalised_freq(data);
+    let cum: Vec<u32> = freq.iter().scan(0, |sum, &f| Some(std::mem::replace(sum, *sum + f))).collect();
+    let mut state = 1u32 << l_bits;
+    let mut out = Vec::new();
+    for &s in data.iter().rev() {
+        let (f, b) = (freq[s as usize], cum[s as usize]);
+        while state >= (f << 20) {
+            out.push(state as u8);
+            state >>= 8;
+        }
+        state = ((state / f) << 12) + (state % f) + b;
+    }
+    if reverse {
+        out.reverse();
+    }
+    let mut payload = state.to_le_bytes().to_vec();
+    payload.extend_from_slice(&out);
+    payload.extend(freq.iter().flat_map(|&f| (f as u16).to_le_bytes()));
+    payload
+}
+
+/// Decoder for `byte_rans` payloads renormalising against `1 << l_bits`
+fn byte_rans_decode(payload: &[u8], count: usize, l_bits: u32) -> Vec<u8> {
+    let table = payload.len() - 512;
+    let freq: Vec<u32> = payload[table..].chunks(2).map(|f| u16::from_le_bytes([f[0], f[1]]) as u32).collect();
+    let cum: Vec<u32> = freq.iter().scan(0, |sum, &f| Some(std::mem::replace(sum, *sum + f))).collect();
+    let mut state = u32::from_le_bytes(payload[..4].try_into().unwrap());
+    let mut pos = 4;
+    let mut out = Vec::new();
+    while out.len() < count {
+        let slot = state & 4095;
+        let s = (0..256).rfind(|&s| freq[s] > 0 && cum[s] <= slot).unwrap();
+        out.push(s as u8);
+        state = freq[s] * (state >> 12) + slot - cum[s];
+        while state < (1 << l_bits) && pos < table {
+            state = (state << 8) | payload[pos] as u32;
+            pos += 1;
+        }
+    }
+    out
+}
+
+/// Byte frequencies scaled to sum to 4096, as `RansTable::from_data`
+fn normalised_freq(data: &[u8]) -> [u32; 256] {
+    let mut counts = [0u64; 256];
+    data.iter().for_each(|&b| counts[b as usize] += 1);
+    let mut freq = counts.map(|c| if c > 0 { (c * 4096 / data.len() as u64).max(1) as u32 } else { 0 });
+    let mut sum: u32 = freq.iter().sum();
+    while sum > 4096 {
+        for f in freq.iter_mut().filter(|f| **f > 1) {
+            *f -= 1;
+            sum -= 1;
+            if sum == 4096 {
+                break;
+            }
+        }
+    }
+    while sum < 4096 {
+        for (f, _) in freq.iter_mut().zip(counts).filter(|(_, c)| *c > 0) {
+            *f += 1;
+            sum += 1;
+            if sum == 4096 {
+                break;
+            }
+        }
+    }
+    freq
+}
+
+#[test]
+fn byte_rans_fix_regression() {
+    let data: Vec<u8> = lcg(3000, 21, 40).into_iter().map(|x| (x * x / 64) as u8).collect();
+    // As first written the coder did not round-trip: its states sat below the
+    // range its renormalisation assumed, and its bytes came out in reverse order
+    assert_ne!(byte_rans_decode(&byte_rans(&data, 16, false), data.len(), 16), data);
+    assert_ne!(byte_rans_decode(&byte_rans(&data, 24, false), data.len(), 24), data);
+
+    let fixed = byte_rans(&data, 24, true);
+    assert_eq!(byte_rans_decode(&fixed, data.len(), 24), data);
+    assert_eq!(fixed, RansCoder::encode(&data).unwrap());
+    assert_eq!(RansCoder::decode(&fixed, 0).unwrap(), data);
+}
+
+#[test]
+fn baseline_elite_streams_are_refused() {
+    // Version 5 blocks hold the unfixed coder's output, so the header is refused outright
+    let mut stream = b"NEUR".to_vec();
+    stream.extend_from_slice(&5u32.to_be_bytes());
+    stream.extend_from_slice(&20_000u32.to_be_bytes());
+    stream.extend_from_slice(&1u16.to_be_bytes());
+    stream.extend_from_slice(&16u16.to_be_bytes());
+    stream.extend_from_slice(&3000u64.to_be_bytes());
+    assert!(matches!(Header::read(&mut stream.as_slice()), Err(FormatError::LegacyByteRans)));
+    let err = decompress(&stream).expect_err("version 5");
+    assert!(err.to_string().contains("re-encode"), "{}", err);
+}
+
+#[test]
+fn legacy_sparse_elite_still_decodes() {
+    // An Elite stream as written before `FLAG_ELITE_RANS`: sparse residuals, serialised, byte rANS
+    let samples: Vec<i32> = lcg(100, 15, 3000);
+    let info = StreamInfo { sample_rate: 20_000, channels: 1, bits_per_sample: 16, total_samples: 100 };
+    let mut stream = Vec::new();
+    Header::new(Mode::Elite, info, 100, 2).write(&mut stream).expect("header");
+    let qlp = fixed_predictor(2);
+    let residuals = neuralink_compressor::lpc::compute_residuals_fixed(&samples, &qlp);
+    let compressed = RansCoder::encode(&SimdOps::serialize(&SparseEncoder::encode(&residuals))).expect("rans");
+    stream.extend_from_slice(&100u32.to_be_bytes());
+    stream.push(2);
+    qlp.write(&mut stream).expect("qlp");
+    stream.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    stream.extend_from_slice(&compressed);
+
+    let header = Header::read(&mut stream.as_slice()).expect("header");
+    assert_eq!(header.flags & (FLAG_ELITE_RANS | FLAG_WARM_UP), 0);
+    let decoded = decompress(&stream).expect("decompress");
+    assert_eq!(decoded.samples, samples.iter().map(|&x| x as i16).collect::<Vec<_>>());
+}
+
+#[test]
+fn sparse_values_escape_past_16_bits() {
+    let data = SparseEncoder::encode(&[0, -32768, 70_000]);
+    let escaped = SimdOps::serialize(&data);
+    assert_eq!(SparseEncoder::decode(&SimdOps::deserialize(&escaped)), [0, -32768, 70_000]);
+    // Both nonzero values escape: -32768 zigzags to 0xFFFF itself
+    assert_eq!(escaped.len(), 8 + 2 * (2 + 4) + 2 * 4);
+}
+
+/// Serialised sparse residuals, the byte stream legacy Elite blocks rANS-code
+fn serialised_block(seed: u32) -> Vec<u8> {
+    SimdOps::serialize(&SparseEncoder::encode(&lcg(64, seed, 200)))
+}
+
+#[test]
+fn compact_rans_tables() {
+    let data = serialised_block(16);
+    let table = RansTable::from_data(&data);
+    let mut compact = Vec::new();
+    table.write_compact(&mut compact);
+    assert!(compact.len() < 256, "{} bytes", compact.len());
+    assert_eq!(RansTable::read_compact(&mut compact.as_slice()).expect("read"), table);
+
+    let payload = RansCoder::encode_with(&data, &table).expect("encode");
+    assert_eq!(RansCoder::decode_with(&payload, data.len(), &table).expect("decode"), data);
+    assert_eq!(RansCoder::decode_with(&payload, 0, &table).expect("decode to end"), data);
+    // The legacy layout is the same payload followed by the full 512-byte table
+    let legacy = RansCoder::encode(&data).expect("legacy");
+    assert_eq!(legacy.len(), payload.len() + 512);
+    assert_eq!(RansCoder::decode(&legacy, 0).expect("legacy decode"), data);
+
+    // Bytes the table has no slot for cannot be coded
+    let single = RansTable::from_data(&[7, 7, 7]);
+    assert!(RansCoder::encode_with(&[7, 8], &single).is_err());
+    let mut corrupt = compact.clone();
+    corrupt[2] ^= 0x01; // first frequency
+    assert!(RansTable::read_compact(&mut corrupt.as_slice()).is_err());
+    let mut corrupt = legacy.clone();
+    let first_freq = legacy.len() - 512 + 2 * data[0] as usize;
+    corrupt[first_freq] ^= 0x01;
+    assert!(RansCoder::decode(&corrupt, 0).is_err());
+}
+
+#[test]
+fn blocks_share_rans_tables_by_id() {
+    let blocks: Vec<Vec<u8>> = (0..40).map(|i| serialised_block(100 + i)).collect();
+    let mut tables = RansTables::default();
+    let shared: Vec<Vec<u8>> = blocks.iter().map(|b| RansCoder::encode_shared(b, &mut tables).expect("encode")).collect();
+    // Similar blocks reuse the tables of earlier ones
+    assert!(tables.len() < blocks.len() / 4, "{} tables", tables.len());
+
+    let mut file = Vec::new();
+    tables.write(&mut file).expect("tables");
+    let read = RansTables::read(&mut file.as_slice()).expect("read");
+    assert_eq!(read, tables);
+    for (block, payload) in blocks.iter().zip(&shared) {
+        assert_eq!(RansCoder::decode_shared(payload, block.len(), &read).expect("decode"), *block);
+    }
+
+    let total = file.len() + shared.iter().map(|p| p.len()).sum::<usize>();
+    let legacy: usize = blocks.iter().map(|b| RansCoder::encode(b).unwrap().len()).sum();
+    assert!(total * 3 < legacy, "{} vs {}", total, legacy);
+    assert!(RansCoder::decode_shared(&[0xFE, 0xFF, 0, 0, 0, 1], 1, &read).is_err());
+
+    // Data no table shrinks is stored as is
+    let uniform: Vec<u8> = (0..=255).collect();
+    let raw = RansCoder::encode_shared(&uniform, &mut tables).expect("encode");
+    assert_eq!(raw[..2], RAW_TABLE_ID.to_le_bytes());
+    assert_eq!(raw.len(), uniform.len() + 2);
+    assert_eq!(RansCoder::decode_shared(&raw, 0, &tables).expect("decode"), uniform);
+    assert!(RansCoder::decode_shared(&raw, uniform.len() - 1, &tables).is_err());
+}
+
+#[test]
+fn shared_table_count_fits_its_field() {
+    let mut one = Vec::new();
+    RansTable::from_data(&[7]).write_compact(&mut one);
+    let fill = |count: u16| {
+        let mut file = count.to_le_bytes().to_vec();
+        (0..count).for_each(|_| file.extend_from_slice(&one));
+        RansTables::read(&mut file.as_slice()).expect("read")
+    };
+
+    let mut tables = fill(u16::MAX - 1);
+    assert_eq!(tables.insert(RansTable::from_data(&[8])).expect("last table"), u16::MAX - 1);
+    assert_eq!(tables.len(), MAX_RANS_TABLES);
+    assert!(tables.insert(RansTable::from_data(&[9])).is_err());
+    // Tables already held are still found
+    assert_eq!(tables.insert(RansTable::from_data(&[8])).expect("existing"), u16::MAX - 1);
+
+    let mut file = Vec::new();
+    tables.write(&mut file).expect("write");
+    assert_eq!(file[..2], u16::MAX.to_le_bytes());
+    assert_eq!(RansTables::read(&mut file.as_slice()).expect("read").len(), MAX_RANS_TABLES);
+}
+
+#[test]
+fn interleaved_rans_roundtrip() {
+    let skewed: Vec<u8> = lcg(50_000, 9, 40).into_iter().map(|x| (x * x / 64) as u8).collect();
+    let table = RansTable::from_data(&skewed);
+    for len in [0, 1, RANS_LANES - 1, RANS_LANES, RANS_LANES + 1, 1000, skewed.len()] {
+        let data = &skewed[..len];
+        let payload = RansCoder::encode_interleaved(data, &table).expect("encode");
+        assert_eq!(RansCoder::decode_interleaved(&payload, len, &table).expect("decode"), data, "{} bytes", len);
+        assert_eq!(RansCoder::decode_interleaved_scalar(&payload, len, &table).expect("scalar"), data);
+    }
+
+    // Eight states cost a few bytes over one
+    let single = RansCoder::encode_with(&skewed, &table).unwrap().len();
+    let interleaved = RansCoder::encode_interleaved(&skewed, &table).unwrap();
+    assert!(interleaved.len() <= single + 8 * RANS_LANES, "{} vs {}", interleaved.len(), single);
+
+    let uniform = RansTable::covering(&[]);
+    let bytes: Vec<u8> = (0..=255).cycle().take(3000).collect();
+    let payload = RansCoder::encode_interleaved(&bytes, &uniform).expect("encode");
+    assert_eq!(RansCoder::decode_interleaved(&payload, bytes.len(), &uniform).expect("decode"), bytes);
+
+    assert!(RansCoder::encode_interleaved(&[7, 8], &RansTable::from_data(&[7, 7, 7])).is_err());
+    let len = interleaved.len();
+    for bad in [&interleaved[..len - 2], &interleaved[..len - 1], &interleaved[..20]] {
+        assert!(RansCoder::decode_interleaved(bad, skewed.len(), &table).is_err());
+        assert!(RansCoder::decode_interleaved_scalar(bad, skewed.len(), &table).is_err());
+    }
+    let mut corrupt = interleaved.clone();
+    corrupt[len / 2] ^= 0x10;
+    assert!(RansCoder::decode_interleaved(&corrupt, skewed.len(), &table).is_err());
+}
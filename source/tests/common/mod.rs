@@ -0,0 +1,35 @@
+//! Fixtures shared by the integration tests
+#![allow(dead_code)]
+
+/// `len` pseudo-random values in `0..spread`
+pub fn lcg(len: usize, seed: u32, spread: i32) -> Vec<i32> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as i32 % spread
+        })
+        .collect()
+}
+
+/// `len` samples of uniform noise in `-amplitude..=amplitude`
+pub fn lcg_noise(len: usize, seed: u32, amplitude: i32) -> Vec<i32> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            ((state >> 16) as i32 % (2 * amplitude + 1)) - amplitude
+        })
+        .collect()
+}
+
+/// `lcg_noise` as 16-bit samples
+pub fn lcg_noise_i16(len: usize, seed: u32, amplitude: i16) -> Vec<i16> {
+    lcg_noise(len, seed, amplitude as i32).into_iter().map(|x| x as i16).collect()
+}
+
+/// Interleave equal-length channels frame by frame
+pub fn interleave<T: Copy>(channels: &[Vec<T>]) -> Vec<T> {
+    let frames = channels[0].len();
+    (0..frames).flat_map(|i| channels.iter().map(move |c| c[i])).collect()
+}
@@ -0,0 +1,271 @@
+use neuralink_compressor::coder::{RansCoder, RansTables};
+use neuralink_compressor::container::FLAG_EVENT_TABLES;
+use neuralink_compressor::spike::{mad_noise, EventParams, NoiseEstimator, Polarity, SpikeCompressor};
+use neuralink_compressor::{compress, decompress, Codebook, Config, Header, Mode};
+
+mod common;
+use common::{interleave, lcg_noise};
+
+fn spiky(len: usize) -> Vec<i16> {
+    let mut state: u32 = 9;
+    (0..len)
+        .map(|i| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let noise = ((state >> 24) as i16 - 128) / 4;
+            let phase = i % 311;
+            let spike = if phase < 6 { [-800, -3000, -5200, -2600, 400, 900][phase] } else { 0 };
+            noise + spike
+        })
+        .collect()
+}
+
+/// Split an events stream into its container header, event params and the rest
+fn split_events(encoded: &[u8]) -> (Header, EventParams, &[u8]) {
+    let mut rest = encoded;
+    let header = Header::read(&mut rest).expect("header");
+    let params = EventParams::read(&mut rest).expect("event params");
+    (header, params, rest)
+}
+
+#[test]
+fn event_params_are_stored_in_stream() {
+    let config = Config::default().with_mode(Mode::Events).with_threshold(3.5).with_snippet_len(24);
+    let encoded = compress(&spiky(5000), config).expect("compress");
+    let (header, params, _) = split_events(&encoded);
+    assert_eq!(header.mode, Mode::Events);
+    assert_eq!(params.threshold_multiplier, 3.5);
+    assert_eq!(params.snippet_len, 24);
+    assert_eq!(params.align_offset, 12);
+    assert_eq!(params.codebook_id, Codebook::synthetic(24).id());
+
+    // Nothing but the file is needed to decode a non-default snippet length.
+    let decoded = decompress(&encoded).expect("decompress");
+    assert_eq!(decoded.samples.len(), 5000);
+}
+
+#[test]
+fn payloads_are_length_prefixed_for_every_channel_count() {
+    let mono = spiky(3000);
+    let stereo: Vec<i16> = mono.iter().flat_map(|&s| [s, s / 2]).collect();
+    for (samples, channels) in [(&mono, 1), (&stereo, 2)] {
+        let config = Config::default().with_mode(Mode::Events).with_channels(channels);
+        let encoded = compress(samples, config).expect("compress");
+        let (_, _, mut rest) = split_events(&encoded);
+        RansTables::read(&mut rest).expect("tables");
+        for _ in 0..channels {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            rest = &rest[4 + len..];
+        }
+        assert!(rest.is_empty(), "{} channels", channels);
+    }
+}
+
+#[test]
+fn payloads_are_coded_against_shared_tables() {
+    let mono = spiky(20_000);
+    let channels: Vec<Vec<i16>> = (1..=4).map(|c| mono.iter().map(|&s| s / c).collect()).collect();
+    let config = Config::default().with_mode(Mode::Events).with_channels(4);
+    let encoded = compress(&interleave(&channels), config).expect("compress");
+    let (header, _, mut rest) = split_events(&encoded);
+    assert_ne!(header.flags & FLAG_EVENT_TABLES, 0);
+    let section = rest.len();
+    let tables = RansTables::read(&mut rest).expect("tables");
+    assert!(tables.len() < channels.len(), "{} tables", tables.len());
+    let mut coded = section - rest.len();
+    let mut plain = 0;
+    for _ in 0..channels.len() {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        plain += RansCoder::decode_shared(&rest[4..4 + len], 0, &tables).expect("payload").len();
+        coded += len;
+        rest = &rest[4 + len..];
+    }
+    assert!(coded < plain, "{} vs {}", coded, plain);
+
+    // FEC parity protects the payloads as stored, so they stay uncoded
+    let protected = compress(&interleave(&channels), config.with_parity(4)).expect("compress");
+    assert_eq!(split_events(&protected).0.flags & FLAG_EVENT_TABLES, 0);
+}
+
+#[test]
+fn unusable_event_params_are_rejected() {
+    let config = Config::default().with_mode(Mode::Events).with_threshold(3.0);
+    let encoded = compress(&spiky(2000), config).expect("compress");
+    let mut after_header = encoded.as_slice();
+    Header::read(&mut after_header).expect("header");
+    let params_start = encoded.len() - after_header.len();
+    let params_end = encoded.len() - split_events(&encoded).2.len();
+
+    let rewrite = |params: EventParams| {
+        let mut out = encoded[..params_start].to_vec();
+        params.write(&mut out).expect("write params");
+        out.extend_from_slice(&encoded[params_end..]);
+        out
+    };
+    let (_, good, _) = split_events(&encoded);
+    assert!(decompress(&rewrite(good)).is_ok());
+    assert!(decompress(&rewrite(EventParams { align_offset: 16, ..good })).is_err());
+    assert!(decompress(&rewrite(EventParams { snippet_len: 0, ..good })).is_err());
+    assert!(decompress(&rewrite(EventParams { threshold_multiplier: f32::NAN, ..good })).is_err());
+    assert!(decompress(&rewrite(EventParams { codebook_id: 0xdead_beef, ..good })).is_err());
+}
+
+fn sq_error(a: &[i16], b: &[i16]) -> f64 {
+    a.iter().zip(b).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum()
+}
+
+#[test]
+fn higher_quality_reconstructs_spikes_more_closely() {
+    // Same shape at several amplitudes: only gain/residual can follow them.
+    let mut samples = spiky(12_000);
+    for (n, chunk) in samples.chunks_mut(311).enumerate() {
+        let gain = 0.5 + (n % 4) as f32 * 0.5;
+        for v in chunk.iter_mut().take(6) {
+            *v = (*v as f32 * gain) as i16;
+        }
+    }
+    let mut errors = Vec::new();
+    for quality in 0..=3u8 {
+        let config = Config::default().with_mode(Mode::Events).with_threshold(3.0).with_quality(quality);
+        let encoded = compress(&samples, config).expect("compress");
+        let (_, params, _) = split_events(&encoded);
+        assert_eq!(params.quality, quality);
+        let decoded = decompress(&encoded).expect("decompress");
+        errors.push(sq_error(&samples, &decoded.samples));
+    }
+    for pair in errors.windows(2) {
+        assert!(pair[1] < pair[0], "error did not drop with quality: {:?}", errors);
+    }
+}
+
+#[test]
+fn gain_preserves_spike_amplitude() {
+    let mut samples = vec![0i32; 4000];
+    let shape = [-400, -2000, -6000, -3000, 500, 1200, 800, 200];
+    for (n, start) in (200..3800).step_by(400).enumerate() {
+        let gain = 1.0 + n as f32 * 0.4;
+        for (j, &s) in shape.iter().enumerate() {
+            samples[start + j] = (s as f32 * gain) as i32;
+        }
+    }
+    // A single trained template: without gain every spike comes back the same size.
+    let snippets = SpikeCompressor::new(2.0).snippets(&samples);
+    let codebook = Codebook::train(&snippets, 1, 10).expect("train");
+    let peak = |v: &[i32], start: usize| v[start - 8..start + 16].iter().map(|x| x.abs()).max().unwrap() as f64;
+
+    for (quality, tolerance) in [(0u8, f64::MAX), (1u8, 0.1)] {
+        let compressor = SpikeCompressor::with_codebook(2.0, codebook.clone()).with_quality(quality);
+        let decoded = compressor.decode(&compressor.encode(&samples).expect("encode"), samples.len()).expect("decode");
+        let mut worst: f64 = 0.0;
+        for start in (200usize..3800).step_by(400) {
+            worst = worst.max((peak(&decoded, start) / peak(&samples, start) - 1.0).abs());
+        }
+        if quality == 0 {
+            assert!(worst > 0.3, "index-only events should not track amplitude");
+        } else {
+            assert!(worst < tolerance, "peak error {:.3} with gain", worst);
+        }
+    }
+}
+
+fn add_spikes(signal: &mut [i32], shape: &[i32], starts: impl Iterator<Item = usize>) {
+    for start in starts {
+        for (j, &v) in shape.iter().enumerate() {
+            signal[start + j] += v;
+        }
+    }
+}
+
+#[test]
+fn mad_threshold_ignores_spike_activity() {
+    // A busy channel: large spikes inflate the RMS past the small ones.
+    let mut samples = lcg_noise(8000, 3, 32);
+    add_spikes(&mut samples, &[-800, -3000, -5200, -2600, 400, 900], (0..8000).step_by(40));
+    add_spikes(&mut samples, &[-100, -250, -300, -150], (20..8000).step_by(40));
+
+    let quiet_sigma = mad_noise(&lcg_noise(8000, 3, 32));
+    assert!(mad_noise(&samples) < 2.0 * quiet_sigma);
+
+    let count = |noise| SpikeCompressor::new(4.0).with_noise(noise, 1024).snippets(&samples).len();
+    assert_eq!(count(NoiseEstimator::Rms), 200);
+    assert_eq!(count(NoiseEstimator::Mad), 400);
+}
+
+#[test]
+fn windowed_mad_follows_noise_drift() {
+    let mut samples = lcg_noise(8000, 4, 20);
+    samples.extend(lcg_noise(8000, 5, 400));
+    add_spikes(&mut samples, &[-400, -1200, -2000, -900, 100, 200], (100..16_000).step_by(200));
+
+    let count = |noise| SpikeCompressor::new(4.0).with_noise(noise, 2000).snippets(&samples).len();
+    // One threshold for both halves fires on the loud noise
+    assert!(count(NoiseEstimator::Mad) > 200);
+    assert_eq!(count(NoiseEstimator::WindowedMad), 80);
+}
+
+#[test]
+fn noise_estimator_is_stored_in_stream() {
+    let samples = spiky(20_000);
+    for noise in [NoiseEstimator::Rms, NoiseEstimator::Mad, NoiseEstimator::WindowedMad] {
+        let config = Config::default().with_mode(Mode::Events).with_threshold(5.0).with_noise(noise, 4096);
+        let encoded = compress(&samples, config).expect("compress");
+        let (_, params, _) = split_events(&encoded);
+        assert_eq!(params.noise, noise);
+        assert_eq!(params.noise_window, 4096);
+        let decoded = decompress(&encoded).expect("decompress");
+        assert_eq!(decoded.samples.len(), samples.len());
+    }
+}
+
+/// Place `shape` at each start in an otherwise silent channel
+fn silent_with(len: usize, spikes: &[(usize, &[i32])]) -> Vec<i32> {
+    let mut samples = vec![0; len];
+    for &(start, shape) in spikes {
+        add_spikes(&mut samples, shape, std::iter::once(start));
+    }
+    samples
+}
+
+fn argmin(snippet: &[i16]) -> usize {
+    (0..snippet.len()).min_by_key(|&i| snippet[i]).unwrap()
+}
+
+#[test]
+fn snippets_align_on_the_peak() {
+    // Slow and fast rises cross the threshold at different distances from the trough.
+    let slow: &[i32] = &[-600, -1200, -1800, -2400, -3000, -3600, -2000, 500];
+    let fast: &[i32] = &[-3000, -5000, -1000, 300];
+    let samples = silent_with(2000, &[(100, slow), (500, fast), (900, slow), (1300, fast)]);
+    let threshold = 3.5; // about 940 for this channel's RMS
+
+    let centred = SpikeCompressor::new(threshold).snippets(&samples);
+    assert_eq!(centred.len(), 4);
+    assert!(centred.iter().all(|s| argmin(s) == 8), "{:?}", centred);
+
+    let early = SpikeCompressor::new(threshold).with_pre_samples(4).expect("pre").snippets(&samples);
+    assert!(early.iter().all(|s| argmin(s) == 4));
+    assert_eq!(early[1][4], -5000);
+    assert!(SpikeCompressor::new(threshold).with_pre_samples(16).is_err());
+}
+
+#[test]
+fn polarity_selects_crossing_direction() {
+    let down: &[i32] = &[-1000, -4000, -1500];
+    let up: &[i32] = &[1000, 4000, 1500];
+    let samples = silent_with(3000, &[(100, down), (400, up), (700, down), (1000, up), (1300, up)]);
+    let count = |polarity| SpikeCompressor::new(2.0).with_polarity(polarity).snippets(&samples).len();
+    assert_eq!(count(Polarity::Negative), 2);
+    assert_eq!(count(Polarity::Positive), 3);
+    assert_eq!(count(Polarity::Bipolar), 5);
+}
+
+#[test]
+fn refractory_period_is_separate_from_snippet_length() {
+    let spike: &[i32] = &[-2000, -4000, -1000];
+    // Two spikes 10 samples apart, inside the default dead time of one snippet
+    let samples = silent_with(1000, &[(100, spike), (110, spike), (500, spike)]);
+    let detector = SpikeCompressor::new(2.0).with_polarity(Polarity::Negative);
+    assert_eq!(detector.clone().snippets(&samples).len(), 2);
+    let snippets = detector.with_refractory(5).snippets(&samples);
+    assert_eq!(snippets.len(), 3);
+    assert_eq!(argmin(&snippets[1]), 8);
+}
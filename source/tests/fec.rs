@@ -0,0 +1,145 @@
+use neuralink_compressor::fec;
+use neuralink_compressor::container::FLAG_EVENT_FEC;
+use neuralink_compressor::spike::{EventParams, PARAMS_PARITY};
+use neuralink_compressor::{compress, decompress, Config, Header, Mode};
+
+fn lcg_bytes(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+fn spiky(len: usize) -> Vec<i16> {
+    let noise = lcg_bytes(len, 17);
+    (0..len)
+        .map(|i| {
+            let phase = i % 173;
+            let spike = if phase < 6 { [-900, -3100, -5000, -2500, 300, 800][phase] } else { 0 };
+            (noise[i] as i16 - 128) / 4 + spike
+        })
+        .collect()
+}
+
+#[test]
+fn fec_roundtrip_without_errors() {
+    for len in [0, 1, 100, 239, 240, 1000] {
+        let data = lcg_bytes(len, len as u32 + 1);
+        for parity in [0, 2, 16, fec::MAX_PARITY] {
+            let encoded = fec::encode(&data, parity);
+            let packets = len.div_ceil(fec::PACKET_LEN - parity as usize);
+            assert_eq!(encoded.len(), len + packets * parity as usize);
+            let (decoded, corrected) = fec::decode(&encoded, parity).expect("decode");
+            assert_eq!(decoded, data, "len {} parity {}", len, parity);
+            assert_eq!(corrected, 0);
+        }
+    }
+}
+
+#[test]
+fn fec_corrects_up_to_half_the_parity() {
+    let data = lcg_bytes(700, 3);
+    for parity in [2u8, 8, 32] {
+        let mut encoded = fec::encode(&data, parity);
+        let t = parity as usize / 2;
+        // t corrupted bytes in every packet, including the shortened last one
+        let mut flipped = 0;
+        for (p, packet) in encoded.chunks_mut(fec::PACKET_LEN).enumerate() {
+            let positions = lcg_bytes(t, (p * 31 + parity as usize) as u32);
+            let mut used = Vec::new();
+            for &pos in &positions {
+                let mut idx = pos as usize % packet.len();
+                while used.contains(&idx) {
+                    idx = (idx + 1) % packet.len();
+                }
+                used.push(idx);
+                packet[idx] ^= pos | 1;
+                flipped += 1;
+            }
+        }
+        let (decoded, corrected) = fec::decode(&encoded, parity).expect("decode");
+        assert_eq!(decoded, data, "parity {}", parity);
+        assert_eq!(corrected, flipped);
+    }
+}
+
+#[test]
+fn fec_refuses_packets_beyond_repair() {
+    let data = lcg_bytes(200, 5);
+    let mut encoded = fec::encode(&data, 4);
+    for idx in [3, 50, 120] {
+        encoded[idx] ^= 0x5a;
+    }
+    match fec::decode(&encoded, 4) {
+        Ok((decoded, _)) => assert_ne!(decoded, data),
+        Err(e) => assert!(e.to_string().contains("corrupted bytes"), "{}", e),
+    }
+}
+
+#[test]
+fn events_parity_repairs_corrupted_payload() {
+    let samples = spiky(6000);
+    let config = Config::default().with_mode(Mode::Events).with_threshold(3.0).with_quality(2).with_parity(16);
+    let encoded = compress(&samples, config).expect("compress");
+    let expected = decompress(&encoded).expect("decompress");
+
+    let mut rest = encoded.as_slice();
+    Header::read(&mut rest).expect("header");
+    let params = EventParams::read_protected(&mut rest).expect("event params");
+    assert_eq!(params.parity, 16);
+    let payload_start = encoded.len() - rest.len() + 4 + 16; // past the coded channel length
+    assert!(encoded.len() - payload_start > 2 * fec::PACKET_LEN);
+
+    // 8 bad bytes in each of the first two packets: within what 16 parity bytes fix
+    let mut damaged = encoded.clone();
+    for i in 0..8 {
+        damaged[payload_start + 3 + i * 29] ^= 0xff;
+        damaged[payload_start + fec::PACKET_LEN + 7 + i * 23] ^= 0x81;
+    }
+    assert_eq!(decompress(&damaged).expect("repaired"), expected);
+
+    // Without parity one bad payload byte is only detected
+    let plain = compress(&samples, config.with_parity(0)).expect("compress");
+    let mut damaged = plain.clone();
+    let last = damaged.len() - 40;
+    damaged[last] ^= 0xff;
+    assert!(decompress(&damaged).is_err());
+}
+
+/// Offset of the events parameters, just past the container header
+fn params_start(encoded: &[u8]) -> usize {
+    let mut rest = encoded;
+    Header::read(&mut rest).expect("header");
+    encoded.len() - rest.len()
+}
+
+#[test]
+fn events_parity_covers_params_and_lengths() {
+    let samples: Vec<i16> = spiky(6000).iter().chain(&spiky(6000)).copied().collect();
+    let stereo = Config::default().with_mode(Mode::Events).with_channels(2).with_threshold(3.0);
+    let packets = Config::default().with_mode(Mode::Events).with_threshold(3.0).with_packet_len(500);
+    for config in [stereo, packets] {
+        let encoded = compress(&samples, config.with_parity(8)).expect("compress");
+        let expected = decompress(&encoded).expect("decompress");
+        assert_ne!(Header::read(&mut encoded.as_slice()).expect("header").flags & FLAG_EVENT_FEC, 0);
+        let params = params_start(&encoded);
+        let first_len = params + 2 + 25 + PARAMS_PARITY as usize;
+
+        // Four bad bytes across the params and four in the first length prefix
+        let mut damaged = encoded.clone();
+        for i in 0..4 {
+            damaged[params + 1 + i * 7] ^= 0x5a;
+            damaged[first_len + i] ^= 0xff;
+        }
+        assert_eq!(decompress(&damaged).expect("repaired"), expected, "{} samples per packet", config.packet_len);
+    }
+
+    // Without parity one bad length byte is fatal
+    let plain = compress(&samples, packets).expect("compress");
+    let mut damaged = plain.clone();
+    damaged[params_start(&plain) + 2 + 25] ^= 0xff;
+    assert!(decompress(&damaged).is_err());
+}
@@ -0,0 +1,84 @@
+use neuralink_compressor::filter::BandPass;
+use neuralink_compressor::spike::{EventParams, SpikeCompressor};
+use neuralink_compressor::{compress, decompress, Config, Header, Mode};
+
+const RATE: u32 = 20_000;
+
+fn sine(len: usize, freq: f64, amplitude: f64) -> Vec<i32> {
+    (0..len)
+        .map(|i| (amplitude * (2.0 * std::f64::consts::PI * freq * i as f64 / RATE as f64).sin()).round() as i32)
+        .collect()
+}
+
+/// Peak output over input amplitude once the filter has settled
+fn gain(freq: f64) -> f64 {
+    let mut filter = BandPass::new(300, 6000, RATE).expect("filter");
+    let out = filter.apply(&sine(8000, freq, 4000.0));
+    out[4000..].iter().map(|x| x.abs()).max().unwrap() as f64 / 4000.0
+}
+
+/// Spikes every 400 samples riding on a slow, large LFP wave
+fn drifting_spikes(len: usize) -> Vec<i32> {
+    let mut signal = sine(len, 3.0, 3000.0);
+    let mut state = 1u32;
+    for x in signal.iter_mut() {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        *x += ((state >> 16) % 61) as i32 - 30;
+    }
+    for start in (200..len - 8).step_by(400) {
+        for (j, v) in [-300, -1200, -1800, -900, 300, 500].into_iter().enumerate() {
+            signal[start + j] += v;
+        }
+    }
+    signal
+}
+
+#[test]
+fn band_pass_response() {
+    assert!((gain(1000.0) - 1.0).abs() < 0.1, "passband gain {}", gain(1000.0));
+    assert!(gain(30.0) < 0.05, "30 Hz gain {}", gain(30.0));
+    assert!(gain(9500.0) < 0.2, "9.5 kHz gain {}", gain(9500.0));
+
+    // Integer arithmetic: the same input always filters to the same output
+    let input = drifting_spikes(5000);
+    let mut a = BandPass::new(300, 0, RATE).unwrap();
+    let mut b = BandPass::new(300, 0, RATE).unwrap();
+    assert_eq!(a.apply(&input), b.apply(&input));
+}
+
+#[test]
+fn invalid_cutoffs_are_rejected() {
+    assert!(BandPass::new(0, 0, RATE).is_err());
+    assert!(BandPass::new(6000, 300, RATE).is_err());
+    assert!(BandPass::new(300, 10_000, RATE).is_err());
+    assert!(BandPass::new(0, 6000, RATE).is_ok());
+
+    let config = Config::default().with_mode(Mode::Events).with_band_pass(300, 12_000);
+    assert!(compress(&[0i16; 100], config).is_err());
+}
+
+#[test]
+fn prefilter_removes_drift_before_detection() {
+    let signal = drifting_spikes(20_000);
+    let raw = SpikeCompressor::new(5.0);
+    // The wave swamps the RMS, hiding every spike
+    assert_eq!(raw.snippets(&signal).len(), 0);
+    let filtered = raw.with_band_pass(300, 6000, RATE).expect("filter");
+    assert_eq!(filtered.snippets(&signal).len(), 50);
+}
+
+#[test]
+fn cutoffs_are_recorded_and_lossless_is_unfiltered() {
+    let samples: Vec<i16> = drifting_spikes(8000).iter().map(|&x| x as i16).collect();
+    let config = Config::default().with_mode(Mode::Events).with_threshold(5.0).with_band_pass(300, 6000);
+    let encoded = compress(&samples, config).expect("compress");
+    let mut rest = encoded.as_slice();
+    Header::read(&mut rest).expect("header");
+    let params = EventParams::read(&mut rest).expect("params");
+    assert_eq!((params.low_cut, params.high_cut), (300, 6000));
+    assert_eq!(decompress(&encoded).expect("decompress").samples.len(), samples.len());
+
+    let lossless = config.with_mode(Mode::Lossless);
+    let decoded = decompress(&compress(&samples, lossless).expect("compress")).expect("decompress");
+    assert_eq!(decoded.samples, samples);
+}
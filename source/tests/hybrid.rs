@@ -0,0 +1,103 @@
+use neuralink_compressor::hybrid::{Background, HybridParams};
+use neuralink_compressor::spike::SpikeCompressor;
+use neuralink_compressor::{compress, decompress, Config, Header, Mode};
+
+/// Slow LFP wave plus noise plus spikes every 397 samples
+fn recording(len: usize, seed: u32) -> Vec<i16> {
+    let mut state = seed;
+    let mut signal: Vec<i32> = (0..len)
+        .map(|i| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let lfp = 600.0 * (2.0 * std::f64::consts::PI * 4.0 * i as f64 / 20_000.0).sin();
+            lfp.round() as i32 + ((state >> 16) % 41) as i32 - 20
+        })
+        .collect();
+    for start in (150..len - 8).step_by(397) {
+        for (j, v) in [-700, -2600, -4300, -2100, 500, 900, 300].into_iter().enumerate() {
+            signal[start + j] += v;
+        }
+    }
+    signal.into_iter().map(|x| x as i16).collect()
+}
+
+fn hybrid(background: Background) -> Config {
+    Config::default().with_mode(Mode::Hybrid).with_threshold(4.0).with_background(background)
+}
+
+fn windows(samples: &[i16]) -> Vec<std::ops::Range<usize>> {
+    let channel: Vec<i32> = samples.iter().map(|&x| x as i32).collect();
+    SpikeCompressor::new(4.0).spike_windows(&channel)
+}
+
+#[test]
+fn spike_windows_are_lossless() {
+    let samples = recording(20_000, 1);
+    let windows = windows(&samples);
+    assert_eq!(windows.len(), 50);
+    for background in [Background::None, Background::Decimated, Background::Noise] {
+        let decoded = decompress(&compress(&samples, hybrid(background)).expect("compress")).expect("decompress");
+        assert_eq!(decoded.samples.len(), samples.len());
+        for window in &windows {
+            assert_eq!(decoded.samples[window.clone()], samples[window.clone()], "{:?}", background);
+        }
+    }
+}
+
+#[test]
+fn background_choices() {
+    let samples = recording(20_000, 2);
+    let windows = windows(&samples);
+    let outside = |decoded: &[i16]| -> Vec<(i16, i16)> {
+        let mut inside = vec![false; samples.len()];
+        for window in &windows {
+            inside[window.clone()].fill(true);
+        }
+        (0..samples.len()).filter(|&i| !inside[i]).map(|i| (samples[i], decoded[i])).collect()
+    };
+    let lossless = compress(&samples, Config::default().with_mode(Mode::Lossless)).unwrap().len();
+
+    let none = compress(&samples, hybrid(Background::None)).unwrap();
+    assert!(none.len() * 10 < lossless, "{} vs {}", none.len(), lossless);
+    let decoded = decompress(&none).unwrap().samples;
+    assert!(outside(&decoded).iter().all(|&(_, d)| d == 0));
+
+    // The LFP survives decimation to within about the noise
+    let decimated = compress(&samples, hybrid(Background::Decimated)).unwrap();
+    assert!(decimated.len() * 4 < lossless, "{} vs {}", decimated.len(), lossless);
+    let decoded = decompress(&decimated).unwrap().samples;
+    let worst = outside(&decoded).iter().map(|&(s, d)| (s as i32 - d as i32).abs()).max().unwrap();
+    assert!(worst <= 40, "worst LFP error {}", worst);
+
+    // The noise model keeps the background's spread, not its samples
+    let noise = compress(&samples, hybrid(Background::Noise).with_decimation(256)).unwrap();
+    assert!(noise.len() * 10 < lossless);
+    let decoded = decompress(&noise).unwrap().samples;
+    let synthetic: Vec<f64> = outside(&decoded).iter().map(|&(_, d)| d as f64).collect();
+    let rms = (synthetic.iter().map(|x| x * x).sum::<f64>() / synthetic.len() as f64).sqrt();
+    // The recording's noise is uniform on [-20, 20]
+    assert!((rms / 11.8 - 1.0).abs() < 0.2, "synthetic noise rms {}", rms);
+}
+
+#[test]
+fn hybrid_stream_layout_and_channels() {
+    let left = recording(6000, 3);
+    let right = recording(6000, 4);
+    let samples: Vec<i16> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+    let config = hybrid(Background::Decimated).with_channels(2).with_decimation(16);
+    let encoded = compress(&samples, config).expect("compress");
+
+    let mut rest = encoded.as_slice();
+    let header = Header::read(&mut rest).expect("header");
+    assert_eq!(header.mode, Mode::Hybrid);
+    assert_eq!(HybridParams::read(&mut rest).expect("params"), HybridParams { background: Background::Decimated, factor: 16 });
+
+    let decoded = decompress(&encoded).expect("decompress").samples;
+    for (c, original) in [&left, &right].into_iter().enumerate() {
+        let channel: Vec<i16> = decoded.iter().skip(c).step_by(2).copied().collect();
+        for window in windows(original) {
+            assert_eq!(channel[window.clone()], original[window]);
+        }
+    }
+
+    assert!(compress(&samples, config.with_decimation(0)).is_err());
+}
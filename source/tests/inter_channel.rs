@@ -0,0 +1,126 @@
+use neuralink_compressor::container::FLAG_INTER_CHANNEL;
+use neuralink_compressor::{compress, decode_range, decompress, Config, Damage, Header, Mode, ResidualCoder};
+
+mod common;
+use common::{interleave, lcg_noise_i16};
+
+/// Neighbouring electrodes: a shared LFP wave and spikes, each channel scaled a
+/// little differently, plus independent noise of a few LSB
+fn array(channels: usize, frames: usize, seed: u32) -> Vec<i16> {
+    let mut state = seed;
+    let mut noise = || {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        ((state >> 16) % 9) as i32 - 4
+    };
+    (0..frames)
+        .flat_map(|i| {
+            let lfp = (i as f32 * 0.013).sin() * 3000.0 + (i as f32 * 0.21).sin() * 400.0;
+            let spike = if i % 311 < 5 { [-800.0, -4000.0, -2500.0, 900.0, 400.0][i % 311] } else { 0.0 };
+            (0..channels).map(|c| ((lfp + spike) * (1.0 - c as f32 * 0.02)) as i32).collect::<Vec<_>>()
+        })
+        .map(|common| (common + noise()) as i16)
+        .collect()
+}
+
+fn inter_channel(channels: u16) -> Config {
+    Config::default().with_channels(channels).with_inter_channel(true)
+}
+
+#[test]
+fn inter_channel_roundtrip() {
+    let samples = array(4, 1500, 1);
+    let configs = [
+        (Mode::Lossless, ResidualCoder::Rice),
+        (Mode::Lossless, ResidualCoder::Partitioned),
+        (Mode::Lossless, ResidualCoder::Adaptive),
+        (Mode::Elite, ResidualCoder::Partitioned),
+    ];
+    for (mode, residual_coder) in configs {
+        for seek_interval in [0, 1, 16] {
+            let config = inter_channel(4).with_mode(mode).with_residual_coder(residual_coder).with_seek_interval(seek_interval);
+            let encoded = compress(&samples, config).expect("compress");
+            assert_eq!(decompress(&encoded).expect("decompress").samples, samples, "{:?} {:?}", mode, residual_coder);
+            let range = decode_range(&encoded, 500, 1100).expect("range");
+            assert_eq!(range.samples, samples[2000..4400]);
+        }
+    }
+}
+
+#[test]
+fn neighbouring_channels_compress_better_together() {
+    let samples = array(6, 3000, 2);
+    for mode in [Mode::Lossless, Mode::Elite] {
+        let config = inter_channel(6).with_mode(mode).with_block_size(256);
+        let separate = compress(&samples, config.with_inter_channel(false)).unwrap().len();
+        let together = compress(&samples, config).unwrap().len();
+        assert!(together * 10 < separate * 9, "{:?}: {} vs {}", mode, together, separate);
+    }
+}
+
+#[test]
+fn references_are_taken_only_where_they_pay() {
+    // Two copies of one recording, apart from a little noise: the second channel
+    // costs little more than that noise
+    let base = array(1, 4000, 6);
+    let copies: Vec<i16> = base.iter().zip(lcg_noise_i16(4000, 7, 1)).flat_map(|(&s, n)| [s, s + n]).collect();
+    let config = inter_channel(2).with_block_size(256);
+    let separate = compress(&copies, config.with_inter_channel(false)).unwrap().len();
+    let together = compress(&copies, config).unwrap().len();
+    assert!(together * 4 < separate * 3, "{} vs {}", together, separate);
+
+    // Unrelated channels gain nothing, and lose no more than the reference bytes
+    let unrelated = interleave(&[lcg_noise_i16(4000, 8, 300), lcg_noise_i16(4000, 9, 300)]);
+    let separate = compress(&unrelated, config.with_inter_channel(false)).unwrap().len();
+    let together = compress(&unrelated, config).unwrap().len();
+    assert!(together <= separate + 2 * 4000 / 256 + 2, "{} vs {}", together, separate);
+}
+
+#[test]
+fn flag_is_set_only_for_multi_channel_blocks() {
+    let flags = |samples: &[i16], config| Header::read(&mut compress(samples, config).unwrap().as_slice()).unwrap().flags;
+    let stereo = array(2, 500, 3);
+    assert_ne!(flags(&stereo, inter_channel(2)) & FLAG_INTER_CHANNEL, 0);
+    assert_eq!(flags(&stereo, Config::default()) & FLAG_INTER_CHANNEL, 0);
+
+    // Mono streams have nothing to reference and are written as before
+    let mono = array(1, 500, 3);
+    assert_eq!(flags(&mono, inter_channel(1)) & FLAG_INTER_CHANNEL, 0);
+    assert_eq!(compress(&mono, inter_channel(1)).unwrap(), compress(&mono, Config::default()).unwrap());
+}
+
+#[test]
+fn near_lossless_references_keep_the_bound() {
+    let mut samples = array(3, 4000, 4);
+    // Full-scale stretch, where differences and their reconstruction overshoot
+    for (i, s) in samples[6000..6600].iter_mut().enumerate() {
+        *s = if (i / 9) % 2 == 0 { i16::MAX - (i % 3) as i16 } else { i16::MIN + (i % 3) as i16 };
+    }
+    for seek_interval in [1, 64] {
+        let config = inter_channel(3).with_mode(Mode::NearLossless).with_max_error(3).with_seek_interval(seek_interval);
+        let decoded = decompress(&compress(&samples, config).expect("compress")).expect("decompress").samples;
+        let worst = samples.iter().zip(&decoded).map(|(&a, &b)| (a as i32 - b as i32).abs()).max().unwrap();
+        assert!(worst <= 3, "error {}", worst);
+    }
+}
+
+#[test]
+fn blocks_referencing_a_corrupt_block_are_lost_with_it() {
+    let samples = array(2, 640, 5);
+    let config = inter_channel(2).with_block_size(64).with_seek_interval(1).with_block_crc(true);
+    let clean = compress(&samples, config).expect("compress");
+    let header_len = 10 + u16::from_be_bytes([clean[8], clean[9]]) as usize + 4;
+    // Reference byte of the first block of channel 1
+    let first_len = u32::from_be_bytes(clean[header_len + 8..header_len + 12].try_into().unwrap()) as usize;
+    let second = header_len + 12 + first_len + 4;
+    assert_ne!(clean[second + 12 + 4], 0, "channel 1 should reference channel 0");
+
+    // Corrupt frame 0 of channel 0
+    let mut encoded = clean.clone();
+    encoded[header_len + 12 + first_len / 2] ^= 0x40;
+    let decoded = decompress(&encoded).expect("decompress");
+    assert_eq!(
+        decoded.damaged,
+        vec![Damage { channel: 0, start: 0, end: 64 }, Damage { channel: 1, start: 0, end: 64 }]
+    );
+    assert_eq!(decoded.samples[128..], samples[128..]);
+}
@@ -0,0 +1,424 @@
+use neuralink_compressor::coder::RansTables;
+use neuralink_compressor::{
+    compress, decode_range, decompress, encode_recording, Config, Decoder, Encoder, FormatError, Header, Mode,
+    SeekTable, StreamInfo,
+};
+
+mod common;
+use common::{interleave, lcg_noise_i16};
+
+fn neural_like(len: usize, seed: u32) -> Vec<i16> {
+    let noise = lcg_noise_i16(len, seed, 30000);
+    (0..len)
+        .map(|i| {
+            let lfp = (i as f32 * 0.01).sin() * 4000.0;
+            let spike = if i % 257 < 4 { 12000.0 } else { 0.0 };
+            (lfp + spike) as i16 + noise[i] / 128
+        })
+        .collect()
+}
+
+#[test]
+fn lossless_and_elite_roundtrip() {
+    let samples = neural_like(3000, 7);
+    for mode in [Mode::Lossless, Mode::Elite] {
+        for block_size in [32, 100, 4096] {
+            let config = Config::default().with_mode(mode).with_block_size(block_size);
+            let encoded = compress(&samples, config).expect("compress");
+            let decoded = decompress(&encoded).expect("decompress");
+            assert_eq!(decoded.samples, samples, "{:?} block {}", mode, block_size);
+            assert_eq!(decoded.sample_rate, config.sample_rate);
+            assert_eq!(decoded.channels, 1);
+            assert_eq!(decoded.bits_per_sample, 16);
+        }
+    }
+}
+
+#[test]
+fn multichannel_roundtrip() {
+    let channels: Vec<Vec<i16>> = (0..4).map(|ch| neural_like(777, ch + 1)).collect();
+    let samples = interleave(&channels);
+    let config = Config::default().with_channels(4).with_block_size(64);
+    let decoded = decompress(&compress(&samples, config).expect("compress")).expect("decompress");
+    assert_eq!(decoded.channels, 4);
+    assert_eq!(decoded.samples, samples);
+}
+
+#[test]
+fn events_preserve_length() {
+    let samples = neural_like(5000, 3);
+    let config = Config::default().with_mode(Mode::Events).with_threshold(2.0);
+    let encoded = compress(&samples, config).expect("compress");
+    assert!(encoded.len() < samples.len() * 2);
+    let decoded = decompress(&encoded).expect("decompress");
+    assert_eq!(decoded.samples.len(), samples.len());
+}
+
+#[test]
+fn streaming_encoder_matches_compress() {
+    let channels: Vec<Vec<i16>> = (0..3).map(|ch| neural_like(1000, ch + 11)).collect();
+    let samples = interleave(&channels);
+    let config = Config::default().with_channels(3).with_block_size(50);
+    let reference = compress(&samples, config).expect("compress");
+
+    let info = StreamInfo {
+        sample_rate: config.sample_rate,
+        channels: 3,
+        bits_per_sample: 16,
+        total_samples: samples.len() as u64,
+    };
+    let mut encoder = Encoder::new(Vec::new(), info, config).expect("encoder");
+    // Chunks deliberately split frames mid-way.
+    let widened: Vec<i32> = samples.iter().map(|&s| s as i32).collect();
+    for chunk in widened.chunks(37) {
+        encoder.write_samples(chunk).expect("write");
+    }
+    let streamed = encoder.finish().expect("finish");
+    assert_eq!(streamed, reference);
+
+    let mut decoder = Decoder::new(streamed.as_slice()).expect("decoder");
+    let mut frames = 0;
+    while let Some(block) = decoder.read_block().expect("block") {
+        assert!(block.len() <= 50 * 3, "decoder returned more than one frame");
+        frames += 1;
+    }
+    assert_eq!(frames, 20);
+}
+
+fn stream_info(config: &Config, total_samples: usize) -> StreamInfo {
+    StreamInfo {
+        sample_rate: config.sample_rate,
+        channels: config.channels,
+        bits_per_sample: 16,
+        total_samples: total_samples as u64,
+    }
+}
+
+#[test]
+fn streaming_handles_partial_frames() {
+    // 1013 frames of 3 channels in blocks of 50: the last frame holds 13
+    let channels: Vec<Vec<i16>> = (0..3).map(|ch| neural_like(1013, ch + 31)).collect();
+    let samples = interleave(&channels);
+    let widened: Vec<i32> = samples.iter().map(|&s| s as i32).collect();
+    for mode in [Mode::Lossless, Mode::Elite] {
+        let config = Config::default().with_mode(mode).with_channels(3).with_block_size(50);
+        let reference = compress(&samples, config).expect("compress");
+
+        // Chunks of varying length, straddling frame and block boundaries
+        let mut encoder = Encoder::new(Vec::new(), stream_info(&config, samples.len()), config).expect("encoder");
+        let mut rest = widened.as_slice();
+        for len in [1, 7, 149, 150, 151, 2].into_iter().cycle() {
+            let (chunk, tail) = rest.split_at(len.min(rest.len()));
+            encoder.write_samples(chunk).expect("write");
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        let streamed = encoder.finish().expect("finish");
+        assert_eq!(streamed, reference, "{:?}", mode);
+
+        let mut decoder = Decoder::new(streamed.as_slice()).expect("decoder");
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_block().expect("frame") {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 21);
+        assert!(frames[..20].iter().all(|f| f.len() == 150));
+        assert_eq!(frames[20].len(), 39);
+        assert_eq!(frames.concat(), widened);
+    }
+}
+
+/// Reader handing out a few bytes per call, splitting samples
+struct Trickle<'a>(&'a [u8]);
+
+impl std::io::Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.0.len()).min(5);
+        buf[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn streaming_from_a_reader() {
+    let samples = interleave(&[neural_like(777, 41), neural_like(777, 42)]);
+    let config = Config::default().with_channels(2).with_block_size(64);
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let mut encoder = Encoder::new(Vec::new(), stream_info(&config, samples.len()), config).expect("encoder");
+    assert_eq!(encoder.write_from(Trickle(&bytes)).expect("read"), samples.len() as u64);
+    assert_eq!(encoder.finish().expect("finish"), compress(&samples, config).expect("compress"));
+
+    // Input ending mid-sample
+    let mut encoder = Encoder::new(Vec::new(), stream_info(&config, samples.len()), config).expect("encoder");
+    assert!(encoder.write_from(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn streaming_checks_the_declared_length() {
+    let samples: Vec<i32> = neural_like(500, 43).into_iter().map(i32::from).collect();
+    let config = Config::default();
+    let encoder = |total| Encoder::new(Vec::new(), stream_info(&config, total), config).expect("encoder");
+
+    let mut short = encoder(501);
+    short.write_samples(&samples).expect("write");
+    assert!(short.finish().is_err());
+    let mut long = encoder(499);
+    assert!(long.write_samples(&samples).is_err());
+    // The rejected write leaves the count as it was
+    long.write_samples(&samples[..499]).expect("write");
+    assert!(long.finish().is_ok());
+}
+
+#[test]
+fn partial_frames_are_rejected() {
+    let samples = neural_like(4097, 47);
+    for inter_channel in [false, true] {
+        let config = Config::default().with_channels(2).with_inter_channel(inter_channel);
+        let err = compress(&samples, config).expect_err("odd sample count");
+        assert!(err.to_string().contains("whole frames"), "{}", err);
+        assert!(compress(&samples[..4096], config).is_ok());
+        assert!(Encoder::new(Vec::new(), stream_info(&config, samples.len()), config).is_err());
+    }
+}
+
+#[test]
+fn whole_recording_modes_do_not_stream() {
+    let samples = neural_like(3000, 44);
+    let info = stream_info(&Config::default(), samples.len());
+    for mode in [Mode::Events, Mode::Hybrid] {
+        let config = Config::default().with_mode(mode);
+        assert!(!config.streams());
+        assert!(Encoder::new(Vec::new(), info, config).is_err(), "{:?}", mode);
+    }
+
+    // Packetised events do stream
+    let config = Config::default().with_mode(Mode::Events).with_packet_len(200);
+    let widened: Vec<i32> = samples.iter().map(|&s| s as i32).collect();
+    let mut encoder = Encoder::new(Vec::new(), info, config).expect("encoder");
+    for chunk in widened.chunks(333) {
+        encoder.write_samples(chunk).expect("write");
+    }
+    assert_eq!(encoder.finish().expect("finish"), compress(&samples, config).expect("compress"));
+    let recorded = encode_recording(Vec::new(), info, Config::default().with_mode(Mode::Events), None, &widened);
+    assert_eq!(decompress(&recorded.expect("encode")).expect("decompress").samples.len(), samples.len());
+}
+
+fn header_error(data: &[u8]) -> FormatError {
+    let err = decompress(data).expect_err("decode should fail");
+    match err.downcast::<FormatError>() {
+        Ok(e) => e,
+        Err(other) => panic!("expected FormatError, got {:?}", other),
+    }
+}
+
+#[test]
+fn container_header_errors_are_typed() {
+    let encoded = compress(&neural_like(100, 5), Config::default()).expect("compress");
+
+    let mut bad_magic = encoded.clone();
+    bad_magic[0..4].copy_from_slice(b"RIFF");
+    assert!(matches!(header_error(&bad_magic), FormatError::BadMagic(m) if &m == b"RIFF"));
+
+    let mut future = encoded.clone();
+    future[4..8].copy_from_slice(&99u32.to_be_bytes());
+    assert!(matches!(header_error(&future), FormatError::UnsupportedVersion(99)));
+
+    assert!(matches!(header_error(&encoded[..20]), FormatError::TruncatedHeader));
+    assert!(matches!(header_error(&encoded[..3]), FormatError::TruncatedHeader));
+
+    let mut flipped = encoded.clone();
+    flipped[14] ^= 0x01; // sample rate byte
+    assert!(matches!(header_error(&flipped), FormatError::HeaderChecksum { .. }));
+}
+
+#[test]
+fn corrupt_lengths_are_rejected_before_allocating() {
+    let samples = neural_like(400, 6);
+    let header_len = |encoded: &[u8]| 10 + u16::from_be_bytes([encoded[8], encoded[9]]) as usize + 4;
+
+    // The only block's residual data runs up to the seek table
+    let encoded = compress(&samples, Config::default().with_block_size(400)).expect("compress");
+    let seek_table = encoded.windows(4).rposition(|w| w == b"NSEK").expect("seek table");
+    let data_len = (header_len(&encoded)..seek_table - 4)
+        .find(|&p| u32::from_be_bytes(encoded[p..p + 4].try_into().unwrap()) as usize == seek_table - p - 4)
+        .expect("data length field");
+    for len in [u32::MAX, 400 * 16 + 1025] {
+        let mut corrupt = encoded.clone();
+        corrupt[data_len..data_len + 4].copy_from_slice(&len.to_be_bytes());
+        let err = decompress(&corrupt).expect_err("oversized block data");
+        assert!(err.to_string().contains("Block data"), "{}", err);
+    }
+
+    // An events payload length beyond the end of the stream
+    let events = compress(&samples, Config::default().with_mode(Mode::Events)).expect("compress");
+    let params_len = u16::from_be_bytes([events[header_len(&events)], events[header_len(&events) + 1]]) as usize;
+    let mut after_tables = &events[header_len(&events) + 2 + params_len..];
+    RansTables::read(&mut after_tables).expect("tables");
+    let payload_len = events.len() - after_tables.len();
+    let mut corrupt = events.clone();
+    corrupt[payload_len..payload_len + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    let err = decompress(&corrupt).expect_err("oversized payload");
+    assert!(err.to_string().contains("Stream ends"), "{}", err);
+}
+
+#[test]
+fn container_header_describes_stream() {
+    let config = Config::default().with_mode(Mode::Elite).with_channels(2).with_block_size(128).with_order(6);
+    let samples = interleave(&[neural_like(300, 1), neural_like(300, 2)]);
+    let encoded = compress(&samples, config).expect("compress");
+    let header = Header::read(&mut encoded.as_slice()).expect("header");
+    assert_eq!(header.version, neuralink_compressor::container::CURRENT_VERSION);
+    assert_eq!(header.mode, Mode::Elite);
+    assert_eq!(header.info.channels, 2);
+    assert_eq!(header.info.total_samples, 600);
+    assert_eq!(header.block_size, 128);
+    assert_eq!(header.order, 6);
+}
+
+#[test]
+fn legacy_versions_still_decode() {
+    // v4 mono events stream: rms, zero events, CRC of the empty payload
+    let mut legacy = Vec::new();
+    legacy.extend_from_slice(b"NEUR");
+    legacy.extend_from_slice(&4u32.to_be_bytes());
+    legacy.extend_from_slice(&20_000u32.to_be_bytes());
+    legacy.extend_from_slice(&1u16.to_be_bytes());
+    legacy.extend_from_slice(&16u16.to_be_bytes());
+    legacy.extend_from_slice(&10u64.to_be_bytes());
+    legacy.extend_from_slice(&1.0f32.to_be_bytes());
+    legacy.extend_from_slice(&0u32.to_be_bytes());
+    legacy.extend_from_slice(&crc32fast::hash(&[]).to_be_bytes());
+    let decoded = decompress(&legacy).expect("decode v4");
+    assert_eq!(decoded.samples, vec![0i16; 10]);
+    // Multi-channel legacy streams code the interleaved samples as one sequence
+    legacy[12..14].copy_from_slice(&2u16.to_be_bytes());
+    assert_eq!(decompress(&legacy).expect("decode stereo v4").samples, vec![0i16; 10]);
+
+    // v1 stereo: blocks of 3 and 1 interleaved samples, order 1 (a = -1.0), k = 0
+    let mut v1 = b"NEUR".to_vec();
+    v1.extend_from_slice(&1u32.to_be_bytes());
+    v1.extend_from_slice(&20_000u32.to_be_bytes());
+    v1.extend_from_slice(&2u16.to_be_bytes());
+    v1.extend_from_slice(&16u16.to_be_bytes());
+    v1.extend_from_slice(&4u64.to_be_bytes());
+    for (len, rice) in [(3u32, 0b1100_0000u8), (1, 0b1111_0000)] {
+        v1.extend_from_slice(&len.to_be_bytes());
+        v1.extend_from_slice(&[1, 0]);
+        v1.extend_from_slice(&(-1.0f64).to_be_bytes());
+        v1.extend_from_slice(&1u32.to_be_bytes());
+        v1.push(rice);
+    }
+    assert_eq!(decompress(&v1).expect("decode stereo v1").samples, [1, 1, 1, 2]);
+    assert_eq!(decode_range(&v1, 1, 2).expect("range").samples, [1, 2]);
+}
+
+#[test]
+fn order_search_roundtrips_and_never_grows() {
+    // Silent and ramping stretches between noisy bursts
+    let mut samples = vec![0i16; 400];
+    samples.extend((0..400).map(|i| (i * 7 - 1400) as i16));
+    samples.extend(neural_like(800, 9));
+    samples.extend(vec![-3i16; 300]);
+    for mode in [Mode::Lossless, Mode::Elite] {
+        let plain = Config::default().with_mode(mode).with_order(12).with_block_size(100);
+        let searched = plain.with_order_search(true);
+        let fixed_order = compress(&samples, plain).expect("compress");
+        let encoded = compress(&samples, searched).expect("compress");
+        assert!(encoded.len() < fixed_order.len(), "{:?}: {} vs {}", mode, encoded.len(), fixed_order.len());
+        assert_eq!(decompress(&encoded).expect("decompress").samples, samples, "{:?}", mode);
+    }
+}
+
+#[test]
+fn out_of_range_lpc_parameters_are_rejected() {
+    use neuralink_compressor::lpc::QuantizedCoeffs;
+    let samples = neural_like(600, 4);
+    assert!(compress(&samples, Config::default().with_order(255)).is_ok());
+    assert!(compress(&samples, Config::default().with_order(256)).is_err());
+    // Precision byte, shift byte, then `order` packed coefficients
+    for precision in [0u8, 1, 14, 32] {
+        assert!(QuantizedCoeffs::read(&mut &[precision, 0, 0, 0, 0, 0][..], 2).is_err(), "{}", precision);
+    }
+    let qlp = QuantizedCoeffs::read(&mut &[13u8, 0, 0xff, 0xf8, 0x00, 0x40][..], 2).expect("read");
+    assert_eq!(qlp.coeffs, vec![-1, 1]);
+}
+
+#[test]
+fn fixed_predictors_cancel_polynomials() {
+    use neuralink_compressor::lpc::{compute_residuals_fixed, fixed_predictor, restore_signal_fixed};
+    let cubic: Vec<i32> = (0..50).map(|i: i32| i * i * i - 20 * i * i + 3 * i - 7).collect();
+    for order in 0..=4 {
+        let qlp = fixed_predictor(order);
+        assert_eq!(qlp.coeffs.len(), order);
+        let residuals = compute_residuals_fixed(&cubic, &qlp);
+        if order == 4 {
+            assert!(residuals[order..].iter().all(|&r| r == 0));
+        }
+        assert_eq!(restore_signal_fixed(&residuals, &qlp), cubic);
+    }
+    assert_eq!(fixed_predictor(4).coeffs, vec![4, -6, 4, -1]);
+}
+
+#[test]
+fn warm_up_predicts_across_blocks() {
+    // Smooth LFP-like channels, where the unpredicted start of each block dominates
+    let channels: Vec<Vec<i16>> = (0..2)
+        .map(|ch| {
+            let noise = lcg_noise_i16(2000, ch + 21, 30000);
+            (0..2000).map(|i| ((i as f32 * 0.02).sin() * 8000.0) as i16 + noise[i] / 2048).collect()
+        })
+        .collect();
+    let samples = interleave(&channels);
+    for mode in [Mode::Lossless, Mode::Elite] {
+        let independent = Config::default().with_mode(mode).with_channels(2).with_block_size(16).with_seek_interval(1);
+        let baseline = compress(&samples, independent).expect("compress");
+        let header = Header::read(&mut baseline.as_slice()).expect("header");
+        assert_eq!(header.flags & neuralink_compressor::container::FLAG_WARM_UP, 0);
+        for seek_interval in [0, 16, 64] {
+            let config = independent.with_seek_interval(seek_interval);
+            let encoded = compress(&samples, config).expect("compress");
+            assert!(encoded.len() < baseline.len(), "{:?} interval {}: {} vs {}", mode, seek_interval, encoded.len(), baseline.len());
+            let decoded = decompress(&encoded).expect("decompress");
+            assert_eq!(decoded.samples, samples, "{:?} interval {}", mode, seek_interval);
+        }
+    }
+}
+
+#[test]
+fn decode_range_matches_full_decode() {
+    let channels: Vec<Vec<i16>> = (0..3).map(|ch| neural_like(1500, ch + 31)).collect();
+    let samples = interleave(&channels);
+    for mode in [Mode::Lossless, Mode::Elite] {
+        for seek_interval in [0, 1, 4] {
+            let config = Config::default().with_mode(mode).with_channels(3).with_block_size(40).with_seek_interval(seek_interval);
+            let encoded = compress(&samples, config).expect("compress");
+            for (start, end) in [(0, 1500), (0, 1), (555, 1001), (1480, 9999), (700, 700), (2000, 3000)] {
+                let decoded = decode_range(&encoded, start, end).expect("decode range");
+                let (start, end) = (start.min(1500) as usize, end.min(1500) as usize);
+                let expected = &samples[start * 3..end.max(start) * 3];
+                assert_eq!(decoded.samples, expected, "{:?} interval {} range {}..{}", mode, seek_interval, start, end);
+            }
+        }
+    }
+}
+
+#[test]
+fn decode_range_skips_earlier_blocks() {
+    let samples = neural_like(4000, 41);
+    let config = Config::default().with_block_size(50).with_seek_interval(10);
+    let mut encoded = compress(&samples, config).expect("compress");
+    let table_offset = u64::from_be_bytes(encoded[encoded.len() - 8..].try_into().unwrap()) as usize;
+    let table = SeekTable::read(&mut &encoded[table_offset..]).expect("seek table");
+    assert_eq!(table.points.len(), 8);
+    let point = table.points[5];
+    assert_eq!(point.sample, 2500);
+
+    // Everything before the seek point is garbage now; the window must not notice.
+    let first = table.points[0].offset as usize;
+    encoded[first..point.offset as usize].fill(0xA5);
+    let decoded = decode_range(&encoded, 2600, 3100).expect("decode range");
+    assert_eq!(decoded.samples, samples[2600..3100]);
+}
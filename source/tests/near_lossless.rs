@@ -0,0 +1,97 @@
+use neuralink_compressor::lpc::{fixed_predictor, NearLossless};
+use neuralink_compressor::{compress, decode_range, decompress, Config, Header, Mode};
+
+/// LFP wave, spikes and wideband noise of about 12 LSB RMS
+fn recording(len: usize, seed: u32) -> Vec<i16> {
+    let mut state = seed;
+    (0..len)
+        .map(|i| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let lfp = (i as f32 * 0.01).sin() * 4000.0;
+            let spike = if i % 257 < 4 { -9000.0 } else { 0.0 };
+            (lfp + spike) as i16 + ((state >> 16) % 41) as i16 - 20
+        })
+        .collect()
+}
+
+fn near_lossless(max_error: u16) -> Config {
+    Config::default().with_mode(Mode::NearLossless).with_max_error(max_error)
+}
+
+fn worst_error(original: &[i16], decoded: &[i16]) -> i32 {
+    assert_eq!(original.len(), decoded.len());
+    original.iter().zip(decoded).map(|(&a, &b)| (a as i32 - b as i32).abs()).max().unwrap_or(0)
+}
+
+#[test]
+fn error_never_exceeds_the_bound() {
+    let samples = recording(20_000, 1);
+    for max_error in [1, 2, 4, 16] {
+        for seek_interval in [0, 1, 8] {
+            let config = near_lossless(max_error).with_seek_interval(seek_interval);
+            let decoded = decompress(&compress(&samples, config).expect("compress")).expect("decompress");
+            let worst = worst_error(&samples, &decoded.samples);
+            assert!(worst <= max_error as i32, "delta {} interval {}: error {}", max_error, seek_interval, worst);
+        }
+    }
+}
+
+#[test]
+fn zero_bound_is_lossless() {
+    let samples = recording(5000, 2);
+    let near = compress(&samples, near_lossless(0)).expect("compress");
+    assert_eq!(decompress(&near).expect("decompress").samples, samples);
+    let lossless = compress(&samples, Config::default()).expect("compress");
+    assert_eq!(near.len(), lossless.len());
+}
+
+#[test]
+fn ratio_grows_with_the_bound() {
+    let samples = recording(20_000, 3);
+    // Long blocks, so residuals rather than per-block overhead dominate
+    let config = |max_error| near_lossless(max_error).with_block_size(1024).with_order_search(true);
+    let lossless = compress(&samples, config(0)).unwrap().len();
+    let mut previous = lossless;
+    for max_error in [1, 2, 4] {
+        let size = compress(&samples, config(max_error)).unwrap().len();
+        assert!(size < previous, "delta {}: {} vs {}", max_error, size, previous);
+        previous = size;
+    }
+    // A step of 2 * 4 + 1 saves about log2(9) of the ~8 bits per sample
+    assert!(previous * 3 < lossless * 2, "{} vs {}", previous, lossless);
+}
+
+#[test]
+fn bound_is_stored_and_holds_everywhere() {
+    let left = recording(4000, 4);
+    let right = recording(4000, 5);
+    let samples: Vec<i16> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+    let config = near_lossless(3).with_channels(2).with_block_crc(true).with_order_search(true);
+    let encoded = compress(&samples, config).expect("compress");
+
+    let header = Header::read(&mut encoded.as_slice()).expect("header");
+    assert_eq!((header.mode, header.max_error), (Mode::NearLossless, 3));
+    assert!(worst_error(&samples, &decompress(&encoded).expect("decompress").samples) <= 3);
+
+    let range = decode_range(&encoded, 1500, 2500).expect("range");
+    assert!(worst_error(&samples[3000..5000], &range.samples) <= 3);
+
+    // Lossless streams record no error bound
+    let lossless = compress(&samples, config.with_mode(Mode::Lossless)).unwrap();
+    assert_eq!(Header::read(&mut lossless.as_slice()).unwrap().max_error, 0);
+}
+
+#[test]
+fn reconstruction_is_clamped_to_the_sample_range() {
+    // Full-scale square wave: predictions overshoot both rails
+    let samples: Vec<i16> = (0..2000).map(|i| if (i / 3) % 2 == 0 { i16::MAX } else { i16::MIN }).collect();
+    let decoded = decompress(&compress(&samples, near_lossless(50)).expect("compress")).expect("decompress");
+    assert!(worst_error(&samples, &decoded.samples) <= 50);
+
+    let quantizer = NearLossless { max_error: 2, bits_per_sample: 16 };
+    let signal: Vec<i32> = vec![0, 7, -7, 32767, -32768, 100];
+    let predictor = fixed_predictor(1);
+    let (indices, decoded) = quantizer.compute_residuals(&[], &signal, &predictor);
+    assert_eq!(quantizer.restore_signal(&[], &indices, &predictor), decoded);
+    assert!(signal.iter().zip(&decoded).all(|(&x, &y)| (x - y).abs() <= 2 && (-32768..=32767).contains(&y)));
+}
@@ -0,0 +1,151 @@
+use neuralink_compressor::realtime::{worst_case_latency, PacketDecoder, PacketEncoder};
+use neuralink_compressor::spike::{EventParams, NoiseEstimator, SpikeCompressor};
+use neuralink_compressor::{compress, decompress, Config, Header, Mode};
+
+mod common;
+use common::lcg_noise;
+
+/// Noise with a spike every `period` samples
+fn spiky(len: usize, period: usize) -> Vec<i32> {
+    let shape = [-900, -3100, -5000, -2500, 300, 800];
+    let mut signal = lcg_noise(len, 23, 40);
+    for start in (period / 2..len).step_by(period) {
+        for (j, &v) in shape.iter().enumerate() {
+            if let Some(x) = signal.get_mut(start + j) {
+                *x += v;
+            }
+        }
+    }
+    signal
+}
+
+fn encode_packets(signal: &[i32], packet_len: usize) -> (SpikeCompressor, Vec<Vec<u8>>) {
+    let compressor = SpikeCompressor::new(4.0).with_quality(2);
+    let mut encoder = PacketEncoder::new(SpikeCompressor::new(4.0).with_quality(2), 3, packet_len);
+    let mut packets: Vec<_> = signal.chunks(packet_len).map(|frame| encoder.push(frame).expect("push")).collect();
+    packets.extend(encoder.flush().expect("flush"));
+    (compressor, packets)
+}
+
+#[test]
+fn packets_decode_independently() {
+    let signal = spiky(2000, 150);
+    let (compressor, packets) = encode_packets(&signal, 20);
+    let decoder = PacketDecoder::new(compressor);
+
+    // Any order gives the same picture
+    let mut in_order = vec![0; signal.len()];
+    for packet in &packets {
+        decoder.decode(packet).expect("decode").render(&mut in_order);
+    }
+    let mut reversed = vec![0; signal.len()];
+    for packet in packets.iter().rev() {
+        decoder.decode(packet).expect("decode").render(&mut reversed);
+    }
+    assert_eq!(in_order, reversed);
+    assert!(in_order.iter().any(|&x| x < -2000), "spikes rendered");
+
+    for (i, packet) in packets.iter().take(100).enumerate() {
+        let decoded = decoder.decode(packet).expect("decode");
+        assert_eq!(decoded.sequence, i as u32);
+        assert_eq!(decoded.channel, 3);
+        assert_eq!(decoded.base, i as u64 * 20);
+        assert_eq!(decoded.frame_len, 20);
+    }
+    let events: usize = packets.iter().map(|p| decoder.decode(p).unwrap().events.len()).sum();
+    assert_eq!(events, (2000usize - 75).div_ceil(150));
+
+    let mut corrupted = packets[10].clone();
+    corrupted[12] ^= 0x01;
+    assert!(decoder.decode(&corrupted).is_err());
+}
+
+#[test]
+fn events_arrive_within_the_latency_bound() {
+    let packet_len = 20;
+    let signal = spiky(3000, 97);
+    let (compressor, packets) = encode_packets(&signal, packet_len);
+    let decoder = PacketDecoder::new(compressor);
+    let latency = worst_case_latency(packet_len, 16, 8) as u64;
+    assert_eq!(latency, 34);
+
+    for packet in &packets {
+        let packet = decoder.decode(packet).expect("decode");
+        let sent_at = packet.base + packet.frame_len as u64;
+        for event in &packet.events {
+            // The whole snippet has been seen by the end of the frame...
+            assert!(event.start + 16 <= sent_at || packet.frame_len == 0);
+            // ...and the spike crossed the threshold no more than `latency` samples earlier.
+            assert!(sent_at - event.timestamp <= latency, "event at {} sent at {}", event.timestamp, sent_at);
+        }
+    }
+}
+
+#[test]
+fn running_threshold_follows_the_noise_level() {
+    let mut signal = lcg_noise(20_000, 5, 20);
+    signal.extend(lcg_noise(40_000, 6, 400));
+    // Uniform noise in [-a, a] has RMS a / sqrt(3) and median |x| of a / 2
+    for (noise, scale) in [(NoiseEstimator::Rms, 0.577), (NoiseEstimator::Mad, 0.5 / 0.6745)] {
+        let compressor = SpikeCompressor::new(4.0).with_noise(noise, 2000);
+        let mut encoder = PacketEncoder::new(compressor.clone(), 0, 100);
+        let decoder = PacketDecoder::new(compressor);
+        let levels: Vec<f32> = signal
+            .chunks(100)
+            .map(|frame| decoder.decode(&encoder.push(frame).unwrap()).unwrap().noise)
+            .collect();
+        let (quiet, loud) = (20.0 * scale, 400.0 * scale);
+        assert!((levels[199] / quiet - 1.0).abs() < 0.2, "{:?} quiet level {}", noise, levels[199]);
+        assert!((levels[599] / loud - 1.0).abs() < 0.2, "{:?} loud level {}", noise, levels[599]);
+    }
+}
+
+#[test]
+fn packetised_container_roundtrip() {
+    let signal = spiky(6000, 211);
+    let samples: Vec<i16> = signal.iter().map(|&x| x as i16).collect();
+    let config = Config::default().with_mode(Mode::Events).with_threshold(4.0).with_quality(2).with_packet_len(20);
+    let encoded = compress(&samples, config).expect("compress");
+    let decoded = decompress(&encoded).expect("decompress");
+    assert_eq!(decoded.samples.len(), samples.len());
+    // Every spike trough survives
+    for start in (105..6000).step_by(211) {
+        assert!(decoded.samples[start + 2] < -3000, "spike at {}", start);
+    }
+
+    let mut rest = encoded.as_slice();
+    Header::read(&mut rest).expect("header");
+    assert_eq!(EventParams::read(&mut rest).expect("params").packet_len, 20);
+
+    // With parity, a corrupted byte in a packet is repaired
+    let protected = compress(&samples, config.with_parity(8)).expect("compress");
+    let mut damaged = protected.clone();
+    // Parity-coded params and length prefixes: 16 check bytes, then 8 per length
+    let first_packet = encoded.len() - rest.len() + 16 + 4 + 8;
+    damaged[first_packet + 5] ^= 0xff;
+    assert_eq!(decompress(&damaged).expect("repaired"), decompress(&protected).unwrap());
+    assert_eq!(protected.len(), encoded.len() + 16 + 300 * 8 + 301 * 8);
+}
+
+#[test]
+fn realtime_detection_matches_batch() {
+    // Large spikes over faint noise: both thresholds separate them cleanly, so
+    // the same peaks and snippets must come out.
+    let signal = spiky(4000, 133);
+    let compressor = SpikeCompressor::new(4.0).with_pre_samples(5).expect("pre").with_refractory(20);
+    let batch = compressor.decode(&compressor.encode(&signal).expect("encode"), signal.len()).expect("decode");
+
+    let mut encoder = PacketEncoder::new(compressor.clone(), 0, 32);
+    let decoder = PacketDecoder::new(compressor);
+    let mut streamed = vec![0; signal.len()];
+    let mut packets: Vec<_> = signal.chunks(32).map(|frame| encoder.push(frame).unwrap()).collect();
+    packets.extend(encoder.flush().unwrap());
+    for packet in &packets {
+        let packet = decoder.decode(packet).expect("decode");
+        for event in &packet.events {
+            assert_eq!(event.start + 5, event.timestamp);
+        }
+        packet.render(&mut streamed);
+    }
+    assert_eq!(streamed, batch);
+}
@@ -0,0 +1,128 @@
+use neuralink_compressor::{compress, decompress, Config, Damage, Mode};
+
+mod common;
+use common::{interleave, lcg_noise_i16};
+
+/// (offset, body length) of every framed block, walking from the end of the header
+fn block_offsets(encoded: &[u8], blocks: usize) -> Vec<(usize, usize)> {
+    let header_len = 10 + u16::from_be_bytes([encoded[8], encoded[9]]) as usize + 4;
+    let mut offsets = Vec::new();
+    let mut pos = header_len;
+    for _ in 0..blocks {
+        assert_eq!(encoded[pos..pos + 2], [0xFF, 0xB5], "sync word at {}", pos);
+        let len = u32::from_be_bytes(encoded[pos + 8..pos + 12].try_into().unwrap()) as usize;
+        offsets.push((pos, len));
+        pos += 12 + len + 4;
+    }
+    offsets
+}
+
+/// Samples of `channel` in per-channel range `start..end` of an interleaved signal
+fn channel_range(samples: &[i16], channels: usize, channel: usize, start: usize, end: usize) -> Vec<i16> {
+    (start..end).map(|i| samples[i * channels + channel]).collect()
+}
+
+#[test]
+fn block_crc_roundtrip() {
+    let samples = interleave(&[lcg_noise_i16(1000, 1, 2048), lcg_noise_i16(1000, 2, 2048)]);
+    for mode in [Mode::Lossless, Mode::Elite] {
+        for seek_interval in [1, 4] {
+            let config = Config::default()
+                .with_mode(mode)
+                .with_channels(2)
+                .with_block_size(64)
+                .with_seek_interval(seek_interval)
+                .with_block_crc(true);
+            let decoded = decompress(&compress(&samples, config).expect("compress")).expect("decompress");
+            assert_eq!(decoded.samples, samples, "{:?} interval {}", mode, seek_interval);
+            assert!(decoded.damaged.is_empty());
+        }
+    }
+}
+
+#[test]
+fn corrupt_block_is_zero_filled_and_reported() {
+    let samples = interleave(&[lcg_noise_i16(640, 3, 2048), lcg_noise_i16(640, 4, 2048)]);
+    let config = Config::default().with_channels(2).with_block_size(64).with_seek_interval(1).with_block_crc(true);
+    let mut encoded = compress(&samples, config).expect("compress");
+    // Frame 3, channel 1
+    let (offset, len) = block_offsets(&encoded, 20)[7];
+    encoded[offset + 12 + len / 2] ^= 0x40;
+
+    let decoded = decompress(&encoded).expect("decompress");
+    assert_eq!(decoded.damaged, vec![Damage { channel: 1, start: 192, end: 256 }]);
+    for (i, (&got, &want)) in decoded.samples.iter().zip(samples.iter()).enumerate() {
+        let (frame, channel) = (i / 2, i % 2);
+        if channel == 1 && (192..256).contains(&frame) {
+            assert_eq!(got, 0);
+        } else {
+            assert_eq!(got, want, "sample {} of channel {}", frame, channel);
+        }
+    }
+}
+
+#[test]
+fn damage_extends_to_next_independent_block() {
+    let samples = lcg_noise_i16(1024, 5, 2048);
+    let config = Config::default().with_block_size(32).with_seek_interval(8).with_block_crc(true);
+    let mut encoded = compress(&samples, config).expect("compress");
+    let (offset, _) = block_offsets(&encoded, 32)[10];
+    encoded[offset + 5] ^= 0x01; // frame index
+
+    let decoded = decompress(&encoded).expect("decompress");
+    // Blocks 10..16 predict from block 10; block 16 is the next seek point.
+    assert_eq!(decoded.damaged, vec![Damage { channel: 0, start: 320, end: 512 }]);
+    assert_eq!(decoded.samples[..320], samples[..320]);
+    assert!(decoded.samples[320..512].iter().all(|&s| s == 0));
+    assert_eq!(decoded.samples[512..], samples[512..]);
+}
+
+#[test]
+fn decoder_resyncs_after_lost_bytes() {
+    let channels = [lcg_noise_i16(800, 6, 2048), lcg_noise_i16(800, 7, 2048), lcg_noise_i16(800, 8, 2048)];
+    let samples = interleave(&channels);
+    for mode in [Mode::Lossless, Mode::Elite] {
+        let config = Config::default()
+            .with_mode(mode)
+            .with_channels(3)
+            .with_block_size(100)
+            .with_seek_interval(1)
+            .with_block_crc(true);
+        let encoded = compress(&samples, config).expect("compress");
+        let offsets = block_offsets(&encoded, 24);
+        // Cut from the middle of frame 2 channel 0 into the middle of frame 3 channel 1.
+        let cut_start = offsets[6].0 + 20;
+        let cut_end = offsets[10].0 + 30;
+        let mut damaged = encoded[..cut_start].to_vec();
+        damaged.extend_from_slice(&encoded[cut_end..]);
+
+        let decoded = decompress(&damaged).expect("decompress");
+        assert_eq!(decoded.samples.len(), samples.len());
+        assert_eq!(
+            decoded.damaged,
+            vec![
+                Damage { channel: 0, start: 200, end: 400 },
+                Damage { channel: 1, start: 200, end: 400 },
+                Damage { channel: 2, start: 200, end: 300 },
+            ],
+            "{:?}",
+            mode
+        );
+        let decoded_channel = |c| channel_range(&decoded.samples, 3, c, 400, 800);
+        for (c, original) in channels.iter().enumerate() {
+            assert_eq!(decoded_channel(c), original[400..], "{:?} channel {}", mode, c);
+        }
+        assert_eq!(channel_range(&decoded.samples, 3, 2, 300, 400), channels[2][300..400]);
+    }
+}
+
+#[test]
+fn truncated_stream_zero_fills_the_tail() {
+    let samples = lcg_noise_i16(500, 9, 2048);
+    let config = Config::default().with_block_size(100).with_seek_interval(1).with_block_crc(true);
+    let encoded = compress(&samples, config).expect("compress");
+    let (offset, _) = block_offsets(&encoded, 5)[3];
+    let decoded = decompress(&encoded[..offset + 40]).expect("decompress");
+    assert_eq!(decoded.damaged, vec![Damage { channel: 0, start: 300, end: 500 }]);
+    assert_eq!(decoded.samples[..300], samples[..300]);
+}